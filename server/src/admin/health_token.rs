@@ -0,0 +1,98 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use pkarr::{Keypair, PublicKey};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Length of an ed25519 secret key in bytes.
+const SECRET_KEY_LENGTH: usize = 32;
+
+/// Keypair of a zbase32 encoded secret key like the ones `pkdns-cli generate` prints.
+pub fn parse_secret_key(secret_key: &str) -> Result<Keypair, String> {
+    let bytes =
+        zbase32::decode_full_bytes_str(secret_key).map_err(|_| "Secret key is not valid zbase32.".to_string())?;
+    let bytes: [u8; SECRET_KEY_LENGTH] = bytes
+        .try_into()
+        .map_err(|_| format!("Secret key must be {SECRET_KEY_LENGTH} bytes."))?;
+    Ok(Keypair::from_secret_key(&bytes))
+}
+
+/**
+ * Signs health reports with the key of this pkdns instance.
+ * A monitor that knows the public key can verify a report came from this instance. The timestamp
+ * and the nonce of the monitor make replayed or cached reports detectable.
+ */
+#[derive(Clone, Debug)]
+pub struct HealthToken {
+    keypair: Keypair,
+}
+
+impl HealthToken {
+    pub fn new(keypair: Keypair) -> Self {
+        Self { keypair }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.keypair.public_key()
+    }
+
+    /// Signed payload. One `key=value` pair per line.
+    pub fn payload(ready: bool, nonce: Option<&str>) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let mut payload = format!("timestamp={timestamp}\nready={ready}\n");
+        if let Some(nonce) = nonce {
+            payload.push_str(&format!("nonce={nonce}\n"));
+        }
+        payload
+    }
+
+    /// Base64 encoded ed25519 signature of the payload.
+    pub fn sign(&self, payload: &str) -> String {
+        STANDARD.encode(self.keypair.sign(payload.as_bytes()).to_bytes())
+    }
+}
+
+/// Checks the base64 encoded signature of a payload.
+#[cfg(test)]
+pub fn verify(public_key: &PublicKey, payload: &str, signature: &str) -> bool {
+    let Ok(signature) = STANDARD.decode(signature) else {
+        return false;
+    };
+    let Ok(signature) = signature.as_slice().try_into() else {
+        return false;
+    };
+    public_key.verify(payload.as_bytes(), &signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_verifies() {
+        let keypair = Keypair::random();
+        let secret = zbase32::encode_full_bytes(&keypair.secret_key());
+        let token = HealthToken::new(parse_secret_key(&secret).unwrap());
+        assert_eq!(token.public_key(), keypair.public_key());
+
+        let payload = HealthToken::payload(true, Some("abc"));
+        let signature = token.sign(&payload);
+        assert!(verify(&token.public_key(), &payload, &signature));
+
+        let tampered = payload.replace("ready=true", "ready=false");
+        assert!(!verify(&token.public_key(), &tampered, &signature));
+        assert!(!verify(&Keypair::random().public_key(), &payload, &signature));
+    }
+
+    #[test]
+    fn secret_key_length_checked() {
+        let keypair = Keypair::random();
+        assert!(parse_secret_key(&zbase32::encode_full_bytes(&keypair.secret_key())).is_ok());
+
+        let mut longer = keypair.secret_key().to_vec();
+        longer.extend_from_slice(&[1, 2, 3]);
+        assert!(parse_secret_key(&zbase32::encode_full_bytes(&longer)).is_err());
+        assert!(parse_secret_key(&zbase32::encode_full_bytes(&longer[..31])).is_err());
+    }
+}
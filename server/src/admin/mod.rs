@@ -0,0 +1,8 @@
+mod health_token;
+mod server;
+
+pub use health_token::{parse_secret_key, HealthToken};
+
+#[cfg(test)]
+pub use health_token::verify;
+pub use server::run_admin_server;
@@ -0,0 +1,286 @@
+use super::HealthToken;
+use crate::{metrics::METRICS, resolution::DnsSocket};
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use std::{collections::HashMap, net::SocketAddr};
+
+/// Maximum length of the nonce a monitor may pass to /health/signed.
+const MAX_NONCE_LENGTH: usize = 64;
+
+// HTTP server for operators. Exposes metrics and other administrative endpoints.
+// Should never be reachable from the public internet.
+
+async fn metrics_get(State(dns_socket): State<DnsSocket>) -> impl IntoResponse {
+    dns_socket.update_cache_gauges().await;
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+}
+
+/// 200 while the node serves queries and reaches the DHT, 503 while it is in standby or not connected yet.
+async fn readyz_get(State(dns_socket): State<DnsSocket>) -> impl IntoResponse {
+    if dns_socket.is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+/// Most queried public keys, one `<public key> <query count>` line each, most queried first.
+async fn top_keys_get(State(dns_socket): State<DnsSocket>) -> impl IntoResponse {
+    let body: String = dns_socket
+        .top_keys()
+        .into_iter()
+        .map(|(pubkey, count)| format!("{} {count}\n", pubkey.to_z32()))
+        .collect();
+    (StatusCode::OK, [(header::CONTENT_TYPE, "text/plain")], body)
+}
+
+/// Promotes a standby node so it starts to serve queries from its warm cache.
+async fn promote_post(State(dns_socket): State<DnsSocket>) -> impl IntoResponse {
+    if !dns_socket.is_standby() {
+        return (StatusCode::OK, "already active");
+    }
+    dns_socket.set_standby(false);
+    tracing::info!("Promoted from standby. Serving queries.");
+    (StatusCode::OK, "promoted")
+}
+
+/// Health report signed with the health token. The optional `nonce` query parameter is included in the
+/// signed payload so a monitor can tell a fresh report from a replayed one.
+async fn signed_health_get(
+    State((dns_socket, token)): State<(DnsSocket, HealthToken)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let nonce = params.get("nonce").map(String::as_str);
+    let valid_nonce = nonce.is_none_or(|nonce| {
+        nonce.len() <= MAX_NONCE_LENGTH && nonce.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    if !valid_nonce {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Nonce must be up to 64 alphanumeric characters.",
+        )
+            .into_response();
+    }
+
+    let payload = HealthToken::payload(dns_socket.is_ready(), nonce);
+    (
+        StatusCode::OK,
+        [
+            ("x-pkdns-signature", token.sign(&payload)),
+            ("x-pkdns-public-key", token.public_key().to_z32()),
+        ],
+        payload,
+    )
+        .into_response()
+}
+
+fn create_app(dns_socket: DnsSocket, health_token: Option<HealthToken>) -> Router {
+    let mut app = Router::new()
+        .route("/metrics", get(metrics_get))
+        .route("/readyz", get(readyz_get))
+        .route("/promote", post(promote_post))
+        .route("/stats/top-keys", get(top_keys_get))
+        .with_state(dns_socket.clone());
+    if let Some(token) = health_token {
+        let health = Router::new()
+            .route("/health/signed", get(signed_health_get))
+            .with_state((dns_socket, token));
+        app = app.merge(health);
+    }
+    app
+}
+
+pub async fn run_admin_server(addr: SocketAddr, dns_socket: DnsSocket, health_token: Option<HealthToken>) {
+    let app = create_app(dns_socket, health_token);
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::create_app;
+    use crate::admin::{verify, HealthToken};
+    use crate::resolution::{
+        test_helpers::{a_query, apex_a_packet, offline_socket, offline_socket_with_settings},
+        MockDht, ResolverSettings,
+    };
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+    use pkarr::{
+        dns::{Packet, RCODE},
+        Keypair,
+    };
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    async fn metrics() {
+        let socket = offline_socket(MockDht::new()).await;
+        let app = create_app(socket, None);
+        let server = TestServer::new(app.into_make_service_with_connect_info::<SocketAddr>()).unwrap();
+
+        let response = server.get("/metrics").await;
+        response.assert_status_ok();
+        let body = response.text();
+        assert!(body.contains("# TYPE pkdns_pkarr_lock_wait_seconds histogram"));
+        assert!(body.contains("pkdns_pkarr_lock_timeouts_total"));
+        assert!(body.contains("# TYPE pkdns_pkarr_cache_size_bytes gauge"));
+        assert!(body.contains("pkdns_pkarr_cache_budget_bytes"));
+        assert!(body.contains("# TYPE pkdns_response_cache_size_bytes gauge"));
+    }
+
+    #[tokio::test]
+    async fn readyz_flips_when_promoted() {
+        let socket = offline_socket(MockDht::new()).await;
+        socket.set_standby(true);
+        let app = create_app(socket.clone(), None);
+        let server = TestServer::new(app.into_make_service_with_connect_info::<SocketAddr>()).unwrap();
+
+        let response = server.get("/readyz").await;
+        response.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+
+        server.post("/promote").await.assert_status_ok();
+        let response = server.get("/readyz").await;
+        response.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn readyz_waits_for_dht_responses() {
+        let mut settings = ResolverSettings::default();
+        settings.min_dht_responses_for_ready = 2;
+        let dht = MockDht::new();
+        let keypairs = [Keypair::random(), Keypair::random()];
+        for keypair in keypairs.iter() {
+            dht.add_packet(apex_a_packet(keypair));
+        }
+        let mut socket = offline_socket_with_settings(dht, settings).await;
+        let app = create_app(socket.clone(), None);
+        let server = TestServer::new(app.into_make_service_with_connect_info::<SocketAddr>()).unwrap();
+
+        // Misses and cache hits don't prove the DHT is reachable.
+        let _ = socket
+            .resolve_signed_packet(&Keypair::random().public_key(), None)
+            .await;
+        socket
+            .resolve_signed_packet(&keypairs[0].public_key(), None)
+            .await
+            .unwrap();
+        socket
+            .resolve_signed_packet(&keypairs[0].public_key(), None)
+            .await
+            .unwrap();
+        server
+            .get("/readyz")
+            .await
+            .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+
+        socket
+            .resolve_signed_packet(&keypairs[1].public_key(), None)
+            .await
+            .unwrap();
+        server.get("/readyz").await.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn signed_health_verifies() {
+        let socket = offline_socket(MockDht::new()).await;
+        let keypair = Keypair::random();
+        let app = create_app(socket, Some(HealthToken::new(keypair.clone())));
+        let server = TestServer::new(app.into_make_service_with_connect_info::<SocketAddr>()).unwrap();
+
+        let response = server.get("/health/signed").add_query_param("nonce", "abc123").await;
+        response.assert_status_ok();
+        let signature = response.header("x-pkdns-signature").to_str().unwrap().to_string();
+        let payload = response.text();
+        assert!(payload.contains("ready=true\n"));
+        assert!(payload.contains("nonce=abc123\n"));
+        assert!(verify(&keypair.public_key(), &payload, &signature));
+
+        let tampered = payload.replace("nonce=abc123", "nonce=abc124");
+        assert!(!verify(&keypair.public_key(), &tampered, &signature));
+
+        let response = server
+            .get("/health/signed")
+            .add_query_param("nonce", "a\nready=false")
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn signed_health_disabled_by_default() {
+        let socket = offline_socket(MockDht::new()).await;
+        let app = create_app(socket, None);
+        let server = TestServer::new(app.into_make_service_with_connect_info::<SocketAddr>()).unwrap();
+
+        let response = server.get("/health/signed").await;
+        response.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn heavily_queried_key_in_top_keys() {
+        let mut settings = ResolverSettings::default();
+        settings.top_keys_tracked = 5;
+        let dht = MockDht::new();
+        let heavy = Keypair::random();
+        dht.add_packet(apex_a_packet(&heavy));
+        let mut socket = offline_socket_with_settings(dht, settings).await;
+
+        let query = |pubkey: String| a_query(&pubkey).build_bytes_vec().unwrap();
+        for _ in 0..20 {
+            socket.query_me_recursively_raw(query(heavy.to_z32()), None).await;
+            socket
+                .query_me_recursively_raw(query(Keypair::random().to_z32()), None)
+                .await;
+        }
+
+        let app = create_app(socket, None);
+        let server = TestServer::new(app.into_make_service_with_connect_info::<SocketAddr>()).unwrap();
+        let response = server.get("/stats/top-keys").await;
+        response.assert_status_ok();
+        let body = response.text();
+        assert_eq!(body.lines().count(), 5);
+        assert_eq!(body.lines().next().unwrap(), format!("{} 20", heavy.to_z32()));
+    }
+
+    #[tokio::test]
+    async fn standby_refuses_until_promoted() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut socket = offline_socket(dht.clone()).await;
+        socket.set_standby(true);
+
+        // Warm the cache while in standby.
+        socket.resolve_signed_packet(&keypair.public_key(), None).await.unwrap();
+        let warm_lookups = dht.lookup_count();
+
+        let pubkey = keypair.to_z32();
+        let query = a_query(&pubkey).build_bytes_vec().unwrap();
+        let reply = socket.query_me_recursively_raw(query.clone(), None).await;
+        assert_eq!(Packet::parse(&reply).unwrap().rcode(), RCODE::Refused);
+
+        let app = create_app(socket.clone(), None);
+        let server = TestServer::new(app.into_make_service_with_connect_info::<SocketAddr>()).unwrap();
+        let response = server.post("/promote").await;
+        response.assert_status_ok();
+        response.assert_text("promoted");
+
+        let reply = socket.query_me_recursively_raw(query, None).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(dht.lookup_count(), warm_lookups);
+    }
+}
@@ -1,9 +1,14 @@
+use crate::{
+    admin::parse_secret_key,
+    resolution::{parse_record_type, SoaTemplate, DEFAULT_RESERVED_TLDS, MAX_TOP_KEYS_TRACKED},
+};
 use anyhow::anyhow;
 use dirs::home_dir;
-use pkarr::dns::Name;
+use pkarr::{dns::Name, PublicKey};
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::{
+    collections::HashMap,
     fs,
     net::SocketAddr,
     num::NonZeroU64,
@@ -35,9 +40,45 @@ pub struct General {
     #[serde(default = "default_forward")]
     pub forward: SocketAddr,
 
+    #[serde(default, deserialize_with = "deserialize_forward_hostname")]
+    pub forward_hostname: Option<String>,
+
+    #[serde(default = "default_forward_re_resolve_interval_s")]
+    pub forward_re_resolve_interval_s: u64,
+
+    #[serde(default = "default_false")]
+    pub socket_trusted: bool,
+
     #[serde(default = "default_none")]
     pub dns_over_http_socket: Option<SocketAddr>,
 
+    #[serde(default = "default_false")]
+    pub dns_over_http_trusted: bool,
+
+    #[serde(default = "default_none")]
+    pub admin_http_socket: Option<SocketAddr>,
+
+    #[serde(default, deserialize_with = "deserialize_secret_key")]
+    pub health_token_secret_key: Option<String>,
+
+    #[serde(default = "default_none")]
+    pub relay_http_socket: Option<SocketAddr>,
+
+    #[serde(default)]
+    pub max_connections: usize,
+
+    #[serde(default)]
+    pub max_connections_per_listener: usize,
+
+    #[serde(default = "default_true")]
+    pub randomize_source_ports: bool,
+
+    #[serde(default = "default_false")]
+    pub standby: bool,
+
+    #[serde(default = "default_standby_prefetch_interval_s")]
+    pub standby_prefetch_interval_s: u64,
+
     #[serde(default = "default_false")]
     pub verbose: bool,
 }
@@ -47,8 +88,20 @@ impl Default for General {
         Self {
             socket: default_socket(),
             forward: default_forward(),
+            forward_hostname: None,
+            forward_re_resolve_interval_s: default_forward_re_resolve_interval_s(),
             verbose: default_false(),
+            socket_trusted: default_false(),
             dns_over_http_socket: default_none(),
+            dns_over_http_trusted: default_false(),
+            admin_http_socket: default_none(),
+            health_token_secret_key: None,
+            relay_http_socket: default_none(),
+            max_connections: 0,
+            max_connections_per_listener: 0,
+            randomize_source_ports: default_true(),
+            standby: default_false(),
+            standby_prefetch_interval_s: default_standby_prefetch_interval_s(),
         }
     }
 }
@@ -61,6 +114,10 @@ fn default_forward() -> SocketAddr {
     "8.8.8.8:53".parse().unwrap()
 }
 
+fn default_forward_re_resolve_interval_s() -> u64 {
+    300
+}
+
 fn default_false() -> bool {
     false
 }
@@ -69,9 +126,9 @@ fn default_none() -> Option<SocketAddr> {
     None
 }
 
-// fn default_true() -> bool {
-//     false
-// }
+fn default_true() -> bool {
+    true
+}
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Dns {
@@ -87,14 +144,56 @@ pub struct Dns {
     #[serde(default = "default_query_rate_limit_burst")]
     pub query_rate_limit_burst: u32,
 
+    #[serde(default)]
+    pub query_rate_limit_subnet: u32,
+
+    #[serde(default)]
+    pub query_rate_limit_subnet_burst: u32,
+
+    #[serde(default = "default_subnet_ipv4_prefix_len")]
+    pub subnet_ipv4_prefix_len: u8,
+
+    #[serde(default = "default_subnet_ipv6_prefix_len")]
+    pub subnet_ipv6_prefix_len: u8,
+
     #[serde(default = "default_false")]
     pub disable_any_queries: bool,
 
+    #[serde(default)]
+    pub any_policy: AnyPolicy,
+
     #[serde(default = "default_icann_cache_mb")]
     pub icann_cache_mb: u64,
 
     #[serde(default = "default_max_recursion_depth")]
     pub max_recursion_depth: u8,
+
+    #[serde(default = "default_max_resolution_depth")]
+    pub max_resolution_depth: u8,
+
+    #[serde(default = "default_max_records_per_query")]
+    pub max_records_per_query: usize,
+
+    #[serde(default = "default_false")]
+    pub require_edns: bool,
+
+    #[serde(default = "default_false")]
+    pub minimal_responses: bool,
+
+    #[serde(default = "default_query_timeout_ms")]
+    pub query_timeout_ms: u64,
+
+    #[serde(default = "default_false")]
+    pub resolve_all_questions: bool,
+
+    #[serde(default = "default_reserved_tlds", deserialize_with = "deserialize_reserved_tlds")]
+    pub reserved_tlds: Vec<String>,
+
+    #[serde(default)]
+    pub reserved_tld_policy: ReservedTldPolicy,
+
+    #[serde(default)]
+    pub unsupported_key_policy: UnsupportedKeyPolicy,
 }
 
 impl Default for Dns {
@@ -104,13 +203,86 @@ impl Default for Dns {
             max_ttl: default_max_ttl(),
             query_rate_limit: default_query_rate_limit(),
             query_rate_limit_burst: default_query_rate_limit_burst(),
+            query_rate_limit_subnet: 0,
+            query_rate_limit_subnet_burst: 0,
+            subnet_ipv4_prefix_len: default_subnet_ipv4_prefix_len(),
+            subnet_ipv6_prefix_len: default_subnet_ipv6_prefix_len(),
             disable_any_queries: default_false(),
+            any_policy: AnyPolicy::default(),
             icann_cache_mb: default_icann_cache_mb(),
             max_recursion_depth: default_max_recursion_depth(),
+            max_resolution_depth: default_max_resolution_depth(),
+            max_records_per_query: default_max_records_per_query(),
+            require_edns: default_false(),
+            minimal_responses: default_false(),
+            query_timeout_ms: default_query_timeout_ms(),
+            resolve_all_questions: default_false(),
+            reserved_tlds: default_reserved_tlds(),
+            reserved_tld_policy: ReservedTldPolicy::default(),
+            unsupported_key_policy: UnsupportedKeyPolicy::default(),
         }
     }
 }
 
+/// How queries for names under a reserved tld are answered. They are never resolved as public key domains.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReservedTldPolicy {
+    /// Forward to the ICANN forward server.
+    #[default]
+    Forward,
+    /// Reply with REFUSED.
+    Refuse,
+}
+
+/// How queries for public key domains with a key type pkdns doesn't support are answered.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsupportedKeyPolicy {
+    /// Reply with NOTIMP and an Extended DNS Error.
+    #[default]
+    NotImp,
+    /// Forward to the ICANN forward server.
+    Forward,
+}
+
+fn default_reserved_tlds() -> Vec<String> {
+    DEFAULT_RESERVED_TLDS.map(String::from).to_vec()
+}
+
+fn deserialize_reserved_tlds<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Vec::<String>::deserialize(deserializer)?;
+    for label in value.iter() {
+        validate_tld_label(label).map_err(D::Error::custom)?;
+    }
+    Ok(value)
+}
+
+/// How ANY queries for public key domains are answered.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AnyPolicy {
+    /// All records of the name.
+    #[default]
+    Expand,
+    /// Only one RRset of the name (RFC 8482).
+    Minimal,
+}
+
+/// How pkarr packets with more records than allowed are handled.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizedPacketPolicy {
+    /// Serve the first records up to the limit.
+    #[default]
+    Truncate,
+    /// Treat the packet as not found.
+    Reject,
+}
+
 fn default_min_ttl() -> u64 {
     60
 }
@@ -119,6 +291,10 @@ fn default_max_ttl() -> u64 {
     86400
 }
 
+fn default_query_timeout_ms() -> u64 {
+    10_000
+}
+
 fn default_query_rate_limit() -> u32 {
     100
 }
@@ -127,6 +303,14 @@ fn default_query_rate_limit_burst() -> u32 {
     200
 }
 
+fn default_subnet_ipv4_prefix_len() -> u8 {
+    24
+}
+
+fn default_subnet_ipv6_prefix_len() -> u8 {
+    48
+}
+
 fn default_icann_cache_mb() -> u64 {
     100
 }
@@ -135,6 +319,18 @@ fn default_max_recursion_depth() -> u8 {
     15
 }
 
+fn default_standby_prefetch_interval_s() -> u64 {
+    60
+}
+
+fn default_max_resolution_depth() -> u8 {
+    20
+}
+
+fn default_max_records_per_query() -> usize {
+    1000
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Dht {
     #[serde(default = "default_cache_mb")]
@@ -148,6 +344,129 @@ pub struct Dht {
         deserialize_with = "deserialize_top_level_domain"
     )]
     pub top_level_domain: Option<String>,
+    #[serde(default = "default_dht_lock_timeout_ms")]
+    pub dht_lock_timeout_ms: u64,
+    /// Number of DHT answers before `/readyz` reports ready. 0 = disabled.
+    #[serde(default)]
+    pub min_dht_responses_for_ready: usize,
+    /// Public keys whose queries are traced in detail independent of the log level.
+    #[serde(default, deserialize_with = "deserialize_debug_keys")]
+    pub debug_keys: Vec<String>,
+    /// Answers served from a cache entry older than this are counted as stale. 0 = disabled.
+    #[serde(default)]
+    pub staleness_warn_s: u64,
+    /// Log a warning for every stale answer in addition to counting it.
+    #[serde(default = "default_false")]
+    pub log_stale_answers: bool,
+    /// Add the apex SOA to the authority section of NODATA replies for public key domains.
+    #[serde(default = "default_false")]
+    pub nodata_soa: bool,
+    /// Remove A and AAAA records with private, loopback or link-local addresses from pkarr answers.
+    #[serde(default = "default_false")]
+    pub block_private_ips: bool,
+    /// Append the tld to the owner names of authority and additional records too.
+    #[serde(default = "default_false")]
+    pub fully_qualify_owner_names: bool,
+    /// Honor the fresh lookup EDNS option that makes pkdns bypass its caches for a query.
+    #[serde(default = "default_false")]
+    pub allow_fresh_lookups: bool,
+    /// Serve the recoverable records of pkarr packets that don't survive a serialization round trip.
+    #[serde(default = "default_false")]
+    pub lenient_parsing: bool,
+    /// Maximum number of records served from a pkarr packet. 0 = unlimited.
+    #[serde(default)]
+    pub max_records_per_packet: usize,
+    /// What happens to packets with more than `max_records_per_packet` records.
+    #[serde(default)]
+    pub oversized_packet_policy: OversizedPacketPolicy,
+    /// File the pkarr cache is saved to on shutdown and loaded from on startup.
+    #[serde(default)]
+    pub cache_state_file: Option<PathBuf>,
+    /// Abort loading the cache state on the first corrupt entry instead of skipping it.
+    #[serde(default = "default_false")]
+    pub cache_state_strict: bool,
+    /// Directory with pre-signed pkarr packets that are served without DHT lookups.
+    #[serde(default)]
+    pub local_packets_dir: Option<PathBuf>,
+    /// Directory of a pkarr cache tier shared with other pkdns instances.
+    #[serde(default)]
+    pub shared_cache_dir: Option<PathBuf>,
+    /// ICANN domains that serve public key domains via a synthesized DNAME to the top level domain.
+    #[serde(default, deserialize_with = "deserialize_dname_parents")]
+    pub dname_parents: Vec<String>,
+    /// Settings overrides per top level domain. Public key domains under these tlds are resolved too.
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        deserialize_with = "deserialize_tld_overrides"
+    )]
+    pub tld_overrides: HashMap<String, TldOverride>,
+    /// SOA answered for SOA queries at the apex of public key zones that don't publish one.
+    #[serde(default, deserialize_with = "deserialize_soa")]
+    pub soa: SoaTemplate,
+    /// Record types whose replies are cached. Either `{ allow = [..] }` or `{ deny = [..] }`. Default: All.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_cacheable_types"
+    )]
+    pub cacheable_types: Option<CacheableTypeList>,
+    /// Pkarr relay sets asked when the DHT has no packet, in order of preference like the nearest region first.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "deserialize_relay_sets"
+    )]
+    pub relay_sets: Vec<RelaySetConfig>,
+    /// Timeout of a single fallback relay request in milliseconds.
+    #[serde(default = "default_relay_timeout_ms")]
+    pub relay_timeout_ms: u64,
+    /// Seconds a relay set that failed completely is only used if all other sets fail too.
+    #[serde(default = "default_relay_set_cooldown_s")]
+    pub relay_set_cooldown_s: u64,
+    /// Relay listener of a parent pkdns that is asked on cache misses before the DHT.
+    #[serde(default = "default_none")]
+    pub parent_resolver: Option<SocketAddr>,
+    /// Seconds after which packets published through pkdns are republished on the DHT. 0 = disabled.
+    #[serde(default)]
+    pub republish_interval_s: u64,
+    /// Maximum random delay in seconds added to the republish interval of every packet.
+    #[serde(default = "default_republish_jitter_s")]
+    pub republish_jitter_s: u64,
+    /// Maximum number of republishes per second. 0 = unlimited.
+    #[serde(default = "default_max_republishes_per_second")]
+    pub max_republishes_per_second: u32,
+    /// Maximum number of republished packets. When full, the packet published the longest time ago is dropped.
+    #[serde(default = "default_max_republished_packets")]
+    pub max_republished_packets: usize,
+    /// Seconds after which a packet that was not published again through pkdns is no longer republished.
+    #[serde(default = "default_republish_max_age_s")]
+    pub republish_max_age_s: u64,
+    /// Number of most queried public keys listed on the admin /stats/top-keys endpoint. 0 = disabled.
+    #[serde(default, deserialize_with = "deserialize_top_keys_tracked")]
+    pub top_keys_tracked: usize,
+}
+
+/// Pkarr relays of one region. Each relay is a host:port that speaks plain HTTP.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RelaySetConfig {
+    pub name: String,
+    pub relays: Vec<String>,
+}
+
+/// Allowlist or denylist of record types like `TXT`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheableTypeList {
+    Allow(Vec<String>),
+    Deny(Vec<String>),
+}
+
+/// Overrides the [dns] ttl settings for public key domains under one top level domain.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct TldOverride {
+    pub min_ttl: Option<u64>,
+    pub max_ttl: Option<u64>,
 }
 
 fn default_cache_mb() -> NonZeroU64 {
@@ -162,6 +481,10 @@ fn default_dht_rate_limit_burst() -> u32 {
     25
 }
 
+fn default_dht_lock_timeout_ms() -> u64 {
+    5000
+}
+
 fn default_top_level_domain() -> Option<String> {
     Some("key".to_string())
 }
@@ -184,19 +507,167 @@ where
     };
 
     if let Some(label) = &value {
-        let parsed_name = Name::new(&label);
-        if let Err(e) = parsed_name {
-            return Err(e).map_err(D::Error::custom);
+        validate_tld_label(label).map_err(D::Error::custom)?;
+    }
+
+    Ok(value)
+}
+
+fn deserialize_tld_overrides<'de, D>(deserializer: D) -> Result<HashMap<String, TldOverride>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = HashMap::<String, TldOverride>::deserialize(deserializer)?;
+    for label in value.keys() {
+        validate_tld_label(label).map_err(D::Error::custom)?;
+    }
+    Ok(value)
+}
+
+fn deserialize_dname_parents<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Vec::<String>::deserialize(deserializer)?;
+    for parent in value.iter() {
+        Name::new(parent).map_err(D::Error::custom)?;
+    }
+    Ok(value)
+}
+
+fn default_relay_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_relay_set_cooldown_s() -> u64 {
+    60
+}
+
+fn default_republish_jitter_s() -> u64 {
+    600
+}
+
+fn default_max_republishes_per_second() -> u32 {
+    10
+}
+
+fn default_max_republished_packets() -> usize {
+    10_000
+}
+
+fn default_republish_max_age_s() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn deserialize_relay_sets<'de, D>(deserializer: D) -> Result<Vec<RelaySetConfig>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Vec::<RelaySetConfig>::deserialize(deserializer)?;
+    for set in value.iter() {
+        if set.relays.is_empty() {
+            return Err(D::Error::custom(format!("Relay set {} has no relays.", set.name)));
+        }
+        for relay in set.relays.iter() {
+            let has_port = relay
+                .rsplit_once(':')
+                .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+            if !has_port {
+                return Err(D::Error::custom(format!("Relay {relay} must be host:port.")));
+            }
         }
-        let name = parsed_name.unwrap();
-        if name.get_labels().len() != 1 {
-            return Err(anyhow!("TLD can only be one label")).map_err(D::Error::custom);
-        };
     }
+    Ok(value)
+}
 
+fn deserialize_forward_hostname<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    if let Some(hostname) = &value {
+        let has_port = hostname
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+        if !has_port {
+            return Err(D::Error::custom(format!(
+                "Forward hostname {hostname} must be host:port."
+            )));
+        }
+    }
     Ok(value)
 }
 
+fn deserialize_secret_key<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    if let Some(secret_key) = &value {
+        parse_secret_key(secret_key).map_err(D::Error::custom)?;
+    }
+    Ok(value)
+}
+
+fn deserialize_debug_keys<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Vec::<String>::deserialize(deserializer)?;
+    for key in value.iter() {
+        PublicKey::try_from(key.as_str()).map_err(D::Error::custom)?;
+    }
+    Ok(value)
+}
+
+fn deserialize_top_keys_tracked<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = usize::deserialize(deserializer)?;
+    if value > MAX_TOP_KEYS_TRACKED {
+        return Err(D::Error::custom(format!(
+            "top_keys_tracked {value} is more than the maximum of {MAX_TOP_KEYS_TRACKED}."
+        )));
+    }
+    Ok(value)
+}
+
+fn deserialize_soa<'de, D>(deserializer: D) -> Result<SoaTemplate, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = SoaTemplate::deserialize(deserializer)?;
+    for name in [&value.mname, &value.rname] {
+        if name != "@" {
+            Name::new(name.trim_end_matches('.')).map_err(D::Error::custom)?;
+        }
+    }
+    Ok(value)
+}
+
+fn deserialize_cacheable_types<'de, D>(deserializer: D) -> Result<Option<CacheableTypeList>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = CacheableTypeList::deserialize(deserializer)?;
+    let (CacheableTypeList::Allow(types) | CacheableTypeList::Deny(types)) = &value;
+    for record_type in types.iter() {
+        if parse_record_type(record_type).is_none() {
+            return Err(D::Error::custom(format!("Unknown record type {record_type}")));
+        }
+    }
+    Ok(Some(value))
+}
+
+fn validate_tld_label(label: &str) -> Result<(), anyhow::Error> {
+    let name = Name::new(label)?;
+    if name.get_labels().len() != 1 {
+        return Err(anyhow!("TLD can only be one label"));
+    };
+    Ok(())
+}
+
 impl Default for Dht {
     fn default() -> Self {
         Self {
@@ -204,6 +675,36 @@ impl Default for Dht {
             dht_query_rate_limit: default_dht_rate_limit(),
             dht_query_rate_limit_burst: default_dht_rate_limit_burst(),
             top_level_domain: default_top_level_domain(),
+            dht_lock_timeout_ms: default_dht_lock_timeout_ms(),
+            min_dht_responses_for_ready: 0,
+            debug_keys: vec![],
+            staleness_warn_s: 0,
+            log_stale_answers: default_false(),
+            nodata_soa: default_false(),
+            block_private_ips: default_false(),
+            fully_qualify_owner_names: default_false(),
+            allow_fresh_lookups: default_false(),
+            lenient_parsing: default_false(),
+            max_records_per_packet: 0,
+            oversized_packet_policy: OversizedPacketPolicy::default(),
+            cache_state_file: None,
+            cache_state_strict: default_false(),
+            local_packets_dir: None,
+            shared_cache_dir: None,
+            tld_overrides: HashMap::new(),
+            dname_parents: vec![],
+            soa: SoaTemplate::default(),
+            cacheable_types: None,
+            relay_sets: vec![],
+            relay_timeout_ms: default_relay_timeout_ms(),
+            relay_set_cooldown_s: default_relay_set_cooldown_s(),
+            parent_resolver: default_none(),
+            republish_interval_s: 0,
+            republish_jitter_s: default_republish_jitter_s(),
+            max_republishes_per_second: default_max_republishes_per_second(),
+            max_republished_packets: default_max_republished_packets(),
+            republish_max_age_s: default_republish_max_age_s(),
+            top_keys_tracked: 0,
         }
     }
 }
@@ -212,10 +713,55 @@ impl Default for Dht {
 pub fn read_config(path: &Path) -> Result<PkdnsConfig, anyhow::Error> {
     let config_str = fs::read_to_string(path)?;
     let config: PkdnsConfig = toml::from_str(&config_str)?;
-
+    validate(&config)?;
     Ok(config)
 }
 
+/// Checks settings of different sections that contradict each other.
+fn validate(config: &PkdnsConfig) -> Result<(), anyhow::Error> {
+    let tlds = config
+        .dht
+        .top_level_domain
+        .iter()
+        .chain(config.dht.tld_overrides.keys());
+    for tld in tlds {
+        if config
+            .dns
+            .reserved_tlds
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(tld))
+        {
+            return Err(anyhow!(
+                "Top level domain .{tld} is a reserved tld and would never be resolved. Remove it from dns.reserved_tlds."
+            ));
+        }
+    }
+    if config.dns.query_rate_limit_subnet > 0 {
+        if config.dns.subnet_ipv4_prefix_len > 32 {
+            return Err(anyhow!(
+                "dns.subnet_ipv4_prefix_len {} is longer than 32 bits.",
+                config.dns.subnet_ipv4_prefix_len
+            ));
+        }
+        if config.dns.subnet_ipv6_prefix_len > 64 {
+            return Err(anyhow!(
+                "dns.subnet_ipv6_prefix_len {} is longer than 64 bits.",
+                config.dns.subnet_ipv6_prefix_len
+            ));
+        }
+    }
+    if let (Some(parent), Some(relay)) = (config.dht.parent_resolver, config.general.relay_http_socket) {
+        let same_port_on_this_host =
+            relay.ip().is_unspecified() && relay.port() == parent.port() && parent.ip().is_loopback();
+        if parent == relay || same_port_on_this_host {
+            return Err(anyhow!(
+                "dht.parent_resolver {parent} is the relay listener of this node. Lookups would ask themselves."
+            ));
+        }
+    }
+    Ok(())
+}
+
 /// Read or create a config file at a given path.
 pub fn read_or_create_config(path: &PathBuf) -> Result<PkdnsConfig, anyhow::Error> {
     let path = expand_tilde(path);
@@ -236,6 +782,8 @@ pub fn read_or_create_config(path: &PathBuf) -> Result<PkdnsConfig, anyhow::Erro
     let mut config = PkdnsConfig::default();
     // Add default values for Options. They don't appear otherwise in the commented out config.
     config.general.dns_over_http_socket = Some("127.0.0.1:3000".parse().unwrap());
+    config.general.admin_http_socket = Some("127.0.0.1:3001".parse().unwrap());
+    config.general.relay_http_socket = Some("127.0.0.1:3002".parse().unwrap());
     let full_config = toml::to_string(&config).expect("Valid toml config.");
     let commented_out: Vec<String> = full_config
         .split("\n")
@@ -287,3 +835,69 @@ pub fn expand_tilde(path: &PathBuf) -> PathBuf {
     }
     PathBuf::from(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_top_level_domain_rejected() {
+        let mut config = PkdnsConfig::default();
+        assert!(validate(&config).is_ok());
+
+        config.dht.top_level_domain = Some("test".to_string());
+        assert!(validate(&config).is_err());
+
+        config.dht.top_level_domain = Some("key".to_string());
+        config
+            .dht
+            .tld_overrides
+            .insert("Local".to_string(), TldOverride::default());
+        assert!(validate(&config).is_err());
+
+        config.dns.reserved_tlds.retain(|tld| tld != "local");
+        assert!(validate(&config).is_ok());
+    }
+
+    #[test]
+    fn parent_resolver_self_reference_rejected() {
+        let mut config = PkdnsConfig::default();
+        config.general.relay_http_socket = Some("0.0.0.0:3002".parse().unwrap());
+        config.dht.parent_resolver = Some("10.0.0.1:3002".parse().unwrap());
+        assert!(validate(&config).is_ok());
+
+        config.dht.parent_resolver = Some("127.0.0.1:3002".parse().unwrap());
+        assert!(validate(&config).is_err());
+
+        config.general.relay_http_socket = Some("10.0.0.1:3002".parse().unwrap());
+        config.dht.parent_resolver = Some("10.0.0.1:3002".parse().unwrap());
+        assert!(validate(&config).is_err());
+    }
+
+    #[test]
+    fn subnet_prefix_len_checked_if_subnet_limit_enabled() {
+        let mut config = PkdnsConfig::default();
+        config.dns.subnet_ipv4_prefix_len = 33;
+        config.dns.subnet_ipv6_prefix_len = 65;
+        assert!(validate(&config).is_ok());
+
+        config.dns.query_rate_limit_subnet = 100;
+        assert!(validate(&config).is_err());
+        config.dns.subnet_ipv4_prefix_len = 32;
+        assert!(validate(&config).is_err());
+        config.dns.subnet_ipv6_prefix_len = 64;
+        assert!(validate(&config).is_ok());
+    }
+
+    #[test]
+    fn top_keys_tracked_bounded() {
+        let config: PkdnsConfig = toml::from_str("[general]\n[dns]\n[dht]\ntop_keys_tracked = 100\n").unwrap();
+        assert_eq!(config.dht.top_keys_tracked, 100);
+
+        let too_many = format!(
+            "[general]\n[dns]\n[dht]\ntop_keys_tracked = {}\n",
+            MAX_TOP_KEYS_TRACKED + 1
+        );
+        assert!(toml::from_str::<PkdnsConfig>(&too_many).is_err());
+    }
+}
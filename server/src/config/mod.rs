@@ -1,5 +1,8 @@
 mod config_file;
 mod global;
 
-pub use config_file::{read_or_create_config, read_or_create_from_dir};
+pub use config_file::{
+    expand_tilde, read_or_create_config, read_or_create_from_dir, AnyPolicy, CacheableTypeList, OversizedPacketPolicy,
+    ReservedTldPolicy, UnsupportedKeyPolicy,
+};
 pub use global::{get_global_config, update_global_config};
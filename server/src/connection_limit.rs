@@ -0,0 +1,161 @@
+use axum::{
+    extract::connect_info::ConnectInfo,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    serve::IncomingStream,
+    Extension, Router,
+};
+use std::{
+    convert::Infallible,
+    future::{ready, Ready},
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tower::Service;
+
+/**
+ * Limits the number of concurrent connections of the HTTP listeners.
+ * Combines a limit shared by all listeners with a limit per listener.
+ * Connections above a limit are answered with 503 and closed.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionLimit {
+    global: Option<Arc<Semaphore>>,
+    listener: Option<Arc<Semaphore>>,
+}
+
+fn semaphore(max_connections: usize) -> Option<Arc<Semaphore>> {
+    (max_connections > 0).then(|| Arc::new(Semaphore::new(max_connections)))
+}
+
+impl ConnectionLimit {
+    /// Limit shared by all listeners created with `for_listener`. 0 = unlimited.
+    pub fn global(max_connections: usize) -> Self {
+        Self {
+            global: semaphore(max_connections),
+            listener: None,
+        }
+    }
+
+    /// Limit for a single listener. Also counts towards the global limit. 0 = unlimited.
+    pub fn for_listener(&self, max_connections: usize) -> Self {
+        Self {
+            global: self.global.clone(),
+            listener: semaphore(max_connections),
+        }
+    }
+
+    /// Permits for a new connection. None if a limit is reached.
+    fn try_acquire(&self) -> Option<Vec<OwnedSemaphorePermit>> {
+        let mut permits = vec![];
+        for semaphore in self.global.iter().chain(self.listener.iter()) {
+            permits.push(semaphore.clone().try_acquire_owned().ok()?);
+        }
+        Some(permits)
+    }
+
+    /// Make service for `axum::serve` that applies the limit. Adds `ConnectInfo<SocketAddr>` like
+    /// `Router::into_make_service_with_connect_info`.
+    pub fn into_make_service(self, router: Router) -> LimitedMakeService {
+        LimitedMakeService { router, limit: self }
+    }
+}
+
+async fn too_many_connections() -> impl IntoResponse {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::CONNECTION, "close")],
+        "Too many connections.",
+    )
+}
+
+#[derive(Clone, Debug)]
+pub struct LimitedMakeService {
+    router: Router,
+    limit: ConnectionLimit,
+}
+
+impl Service<IncomingStream<'_>> for LimitedMakeService {
+    type Response = Router;
+    type Error = Infallible;
+    type Future = Ready<Result<Router, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, stream: IncomingStream<'_>) -> Self::Future {
+        let remote_addr = stream.remote_addr();
+        let router = match self.limit.try_acquire() {
+            // The permits live as long as the router of this connection.
+            Some(permits) => self
+                .router
+                .clone()
+                .layer(Extension(ConnectInfo::<SocketAddr>(remote_addr)))
+                .layer(Extension(Arc::new(permits))),
+            None => {
+                tracing::debug!("Rejected connection from {remote_addr}. Too many connections.");
+                Router::new().fallback(too_many_connections)
+            }
+        };
+        ready(Ok(router))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectionLimit;
+    use axum::{routing::get, Router};
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    async fn get_status(addr: std::net::SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn excess_connections_rejected() {
+        let limit = ConnectionLimit::global(3).for_listener(2);
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, limit.into_make_service(router)).await.unwrap();
+        });
+
+        // Idle connections hold their permit until they are closed.
+        let first = TcpStream::connect(addr).await.unwrap();
+        let _second = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(get_status(addr).await, "HTTP/1.1 503 Service Unavailable");
+
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(get_status(addr).await, "HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn global_limit_shared_by_listeners() {
+        let global = ConnectionLimit::global(1);
+        let first = global.for_listener(0);
+        let second = global.for_listener(0);
+
+        let permits = first.try_acquire();
+        assert!(permits.is_some());
+        assert!(second.try_acquire().is_none());
+        drop(permits);
+        assert!(second.try_acquire().is_some());
+    }
+}
@@ -1,4 +1,7 @@
-use crate::resolution::DnsSocket;
+use crate::{
+    connection_limit::ConnectionLimit,
+    resolution::{DnsSocket, ParsedPacket},
+};
 use axum::{
     body::Body,
     extract::{ConnectInfo, Query, State},
@@ -8,7 +11,6 @@ use axum::{
     Router,
 };
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
-use pkarr::dns::Packet;
 use std::{
     collections::HashMap,
     net::{IpAddr, SocketAddr},
@@ -68,7 +70,7 @@ fn decode_dns_base64_packet(param: &String) -> Result<Vec<u8>, (StatusCode, Stri
         ));
     };
     let vec = val.unwrap();
-    if let Err(e) = Packet::parse(&vec) {
+    if let Err(e) = ParsedPacket::new(vec.clone()) {
         tracing::info!("{e}");
         return Err((
             StatusCode::BAD_REQUEST,
@@ -81,12 +83,13 @@ fn decode_dns_base64_packet(param: &String) -> Result<Vec<u8>, (StatusCode, Stri
 /// Extract lowest ttl of answer to set caching parameter
 fn get_lowest_ttl(reply: &Vec<u8>) -> u32 {
     const DEFAULT_VALUE: u32 = 300;
-    let parsed = Packet::parse(reply);
+    let parsed = ParsedPacket::new(reply.clone());
     if let Err(_) = parsed {
         return DEFAULT_VALUE;
     };
     let parsed = parsed.unwrap();
     let val = parsed
+        .parsed()
         .answers
         .iter()
         .map(|answer| answer.ttl)
@@ -115,8 +118,8 @@ fn extract_client_ip(request_addr: &SocketAddr, headers: &HeaderMap) -> IpAddr {
     }
 }
 
-async fn query_to_response(query: Vec<u8>, dns_socket: &mut DnsSocket, client_ip: IpAddr) -> Response<Body> {
-    let reply = dns_socket.query_me_recursively_raw(query, Some(client_ip)).await;
+async fn query_to_response(query: Vec<u8>, dns_socket: &mut DnsSocket, client_ip: Option<IpAddr>) -> Response<Body> {
+    let reply = dns_socket.query_me_recursively_raw(query, client_ip).await;
     let lowest_ttl = get_lowest_ttl(&reply);
 
     let response = Response::builder()
@@ -136,7 +139,7 @@ async fn dns_query_get(
     State(state): State<Arc<AppState>>,
     ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
 ) -> Result<impl IntoResponse, impl IntoResponse> {
-    let client_ip = extract_client_ip(&client_addr, &headers);
+    let client_ip = state.rate_limited_ip(&client_addr, &headers);
     if let Err(response) = validate_accept_header(&headers) {
         return Err(response);
     }
@@ -159,7 +162,7 @@ async fn dns_query_post(
     ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
     request: axum::http::Request<axum::body::Body>,
 ) -> Result<impl IntoResponse, impl IntoResponse> {
-    let client_ip = extract_client_ip(&client_addr, &headers);
+    let client_ip = state.rate_limited_ip(&client_addr, &headers);
     if let Err(response) = validate_accept_header(&headers) {
         return Err(response);
     }
@@ -176,9 +179,18 @@ async fn dns_query_post(
 
 pub struct AppState {
     pub socket: DnsSocket,
+    /// Queries of a trusted listener bypass the rate limits.
+    pub trusted: bool,
+}
+
+impl AppState {
+    /// Client ip the rate limits apply to. None on a trusted listener.
+    fn rate_limited_ip(&self, client_addr: &SocketAddr, headers: &HeaderMap) -> Option<IpAddr> {
+        (!self.trusted).then(|| extract_client_ip(client_addr, headers))
+    }
 }
 
-fn create_app(dns_socket: DnsSocket) -> Router {
+fn create_app(dns_socket: DnsSocket, trusted: bool) -> Router {
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods([Method::GET, Method::POST])
@@ -188,15 +200,18 @@ fn create_app(dns_socket: DnsSocket) -> Router {
         .route("/dns-query", get(dns_query_get))
         .route("/dns-query", post(dns_query_post))
         .layer(cors)
-        .with_state(Arc::new(AppState { socket: dns_socket }));
+        .with_state(Arc::new(AppState {
+            socket: dns_socket,
+            trusted,
+        }));
     app
 }
 
-pub async fn run_doh_server(addr: SocketAddr, dns_socket: DnsSocket) {
-    let app = create_app(dns_socket);
+pub async fn run_doh_server(addr: SocketAddr, dns_socket: DnsSocket, connection_limit: ConnectionLimit, trusted: bool) {
+    let app = create_app(dns_socket, trusted);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     tokio::spawn(async move {
-        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        axum::serve(listener, connection_limit.into_make_service(app))
             .await
             .unwrap();
     });
@@ -217,7 +232,7 @@ mod tests {
         // RFC8484 example https://datatracker.ietf.org/doc/html/rfc8484#section-4.1
         let socket = DnsSocket::default_random_socket().await.unwrap();
         let join_handle = socket.start_receive_loop();
-        let app = create_app(socket);
+        let app = create_app(socket, false);
         let server = TestServer::new(app.into_make_service_with_connect_info::<SocketAddr>()).unwrap();
         let base64 = "AAABAAABAAAAAAAAAWE-NjJjaGFyYWN0ZXJsYWJlbC1tYWtlcy1iYXNlNjR1cmwtZGlzdGluY3QtZnJvbS1zdGFuZGFyZC1iYXNlNjQHZXhhbXBsZQNjb20AAAEAAQ";
         let response = server
@@ -253,7 +268,7 @@ mod tests {
     async fn query_doh_wireformat_post() {
         let socket = DnsSocket::default_random_socket().await.unwrap();
         let join_handle = socket.start_receive_loop();
-        let app = create_app(socket);
+        let app = create_app(socket, false);
         let server = TestServer::new(app.into_make_service_with_connect_info::<SocketAddr>()).unwrap();
 
         let mut query = Packet::new_query(50);
@@ -292,7 +307,7 @@ mod tests {
         // RFC8484 example https://datatracker.ietf.org/doc/html/rfc8484#section-4.1
         let socket = DnsSocket::default_random_socket().await.unwrap();
         socket.start_receive_loop();
-        let app = create_app(socket);
+        let app = create_app(socket, false);
         let server = TestServer::new(app.into_make_service_with_connect_info::<SocketAddr>()).unwrap();
         let base64 = "AAABAAABAAAAAAAAAWE-NjJjaGFyYWN0ZXJsYWJlbC1tYWtlcy1iYXNlNjR1cmwtZGlzdGluY3QtZnJvbS1zdGFuZGFyZC1iYXNlNjQHZXhhbXBsZQNjb20AAAEAAQ";
         let response = server
@@ -35,10 +35,12 @@ pub(crate) fn enable_logging(verbose: bool) {
 
     let regular_filter = tracing_subscriber::filter::Targets::new()
         .with_target("pkdns", Level::INFO)
+        .with_target("pkdns::debug_keys", Level::TRACE)
         .with_target("mainline", Level::WARN);
 
     let verbose_filter = tracing_subscriber::filter::Targets::new()
         .with_target("pkdns", Level::DEBUG)
+        .with_target("pkdns::debug_keys", Level::TRACE)
         .with_target("mainline", Level::WARN);
 
     let mut filter: Targets = regular_filter;
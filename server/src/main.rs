@@ -1,14 +1,21 @@
+use admin::{parse_secret_key, run_admin_server, HealthToken};
 use clap::Parser;
-use config::{read_or_create_config, read_or_create_from_dir, update_global_config};
+use config::{expand_tilde, read_or_create_config, read_or_create_from_dir, update_global_config};
+use connection_limit::ConnectionLimit;
 use dns_over_https::run_doh_server;
 use helpers::{enable_logging, set_full_stacktrace_as_default, wait_on_ctrl_c};
-use resolution::DnsSocketBuilder;
+use relay::run_relay_server;
+use resolution::{DnsSocketBuilder, ForwardServer};
 
-use std::{error::Error, net::SocketAddr, path::PathBuf};
+use std::{error::Error, net::SocketAddr, path::PathBuf, time::Duration};
 
+mod admin;
 mod config;
+mod connection_limit;
 mod dns_over_https;
 mod helpers;
+mod metrics;
+mod relay;
 mod resolution;
 
 #[derive(Parser, Debug)]
@@ -55,12 +62,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     };
 
-    update_global_config(config.clone());
-
     enable_logging(config.general.verbose);
     const VERSION: &str = env!("CARGO_PKG_VERSION");
 
     tracing::info!("Starting pkdns v{VERSION}");
+
+    if let Some(hostname) = &config.general.forward_hostname {
+        match ForwardServer::resolve_hostname(hostname).await {
+            Ok(addr) => {
+                tracing::info!("Resolved the forward server {hostname} to {addr}.");
+                config.general.forward = addr;
+            }
+            Err(e) => tracing::warn!(
+                "Failed to resolve the forward server {hostname}. Use {} until it resolves. {e}",
+                config.general.forward
+            ),
+        };
+    };
+
+    update_global_config(config.clone());
     tracing::debug!("Configuration:\n{}", toml::to_string(&config).unwrap());
     tracing::info!("Forward ICANN queries to {}", config.general.forward);
 
@@ -73,7 +93,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         std::process::exit(1);
     }));
 
-    let dns_socket = DnsSocketBuilder::new()
+    let mut dns_socket = DnsSocketBuilder::new()
         .listen(config.general.socket)
         .icann_resolver(config.general.forward)
         .icann_cache_mb(config.dns.icann_cache_mb)
@@ -89,19 +109,96 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .build()
         .await?;
 
+    let cache_state_file = config.dht.cache_state_file.as_ref().map(expand_tilde);
+    if let Some(path) = &cache_state_file {
+        if path.exists() {
+            match dns_socket.load_pkarr_cache(path, config.dht.cache_state_strict).await {
+                Ok(import) if import.skipped > 0 || import.rejected > 0 => tracing::warn!(
+                    "Loaded {} cached pkarr packets from {}. Skipped {} corrupt entries. Rejected {} packets with too many records.",
+                    import.loaded,
+                    path.display(),
+                    import.skipped,
+                    import.rejected
+                ),
+                Ok(import) => tracing::info!("Loaded {} cached pkarr packets from {}.", import.loaded, path.display()),
+                Err(e) => tracing::warn!("Failed to load the pkarr cache from {}. {e}", path.display()),
+            };
+        }
+    };
+
+    if let Some(dir) = config.dht.local_packets_dir.as_ref().map(expand_tilde) {
+        match dns_socket.load_local_packets(&dir) {
+            Ok(count) => tracing::info!("Loaded {count} local pkarr packets from {}.", dir.display()),
+            Err(e) => tracing::error!("Failed to load the local pkarr packets from {}. {e}", dir.display()),
+        };
+    };
+
     let join_handle = dns_socket.start_receive_loop();
 
+    if let Some(hostname) = config.general.forward_hostname.clone() {
+        if config.general.forward_re_resolve_interval_s > 0 {
+            let interval = Duration::from_secs(config.general.forward_re_resolve_interval_s);
+            dns_socket.start_forward_re_resolution(hostname, interval);
+        }
+    }
+
+    if config.dht.republish_interval_s > 0 {
+        dns_socket.start_republisher();
+    }
+
+    if dns_socket.is_standby() {
+        dns_socket.start_standby_prefetch(Duration::from_secs(config.general.standby_prefetch_interval_s));
+        tracing::info!("Standby mode. Queries are refused until promoted with POST /promote on the admin server.");
+    }
+
     tracing::info!("Listening on {}. Waiting for Ctrl-C...", config.general.socket);
 
+    let connection_limit = ConnectionLimit::global(config.general.max_connections);
+    let max_connections_per_listener = config.general.max_connections_per_listener;
+
     if let Some(http_socket) = config.general.dns_over_http_socket {
-        run_doh_server(http_socket, dns_socket).await;
+        let limit = connection_limit.for_listener(max_connections_per_listener);
+        run_doh_server(
+            http_socket,
+            dns_socket.clone(),
+            limit,
+            config.general.dns_over_http_trusted,
+        )
+        .await;
         tracing::info!("[EXPERIMENTAL] DNS-over-HTTP listening on http://{http_socket}/dns-query.");
     };
 
+    if let Some(admin_socket) = config.general.admin_http_socket {
+        let health_token = config.general.health_token_secret_key.as_ref().map(|secret_key| {
+            HealthToken::new(parse_secret_key(secret_key).expect("Secret key is validated when reading the config."))
+        });
+        if let Some(token) = &health_token {
+            tracing::info!(
+                "Signed health reports on /health/signed. Public key {}.",
+                token.public_key()
+            );
+        }
+        run_admin_server(admin_socket, dns_socket.clone(), health_token).await;
+        tracing::info!("Admin server listening on http://{admin_socket}. Metrics on /metrics.");
+    };
+
+    if let Some(relay_socket) = config.general.relay_http_socket {
+        let limit = connection_limit.for_listener(max_connections_per_listener);
+        run_relay_server(relay_socket, dns_socket.clone(), limit).await;
+        tracing::info!("Pkarr relay listening on http://{relay_socket}.");
+    };
+
     wait_on_ctrl_c().await;
     println!();
     tracing::info!("Got it! Exiting...");
     join_handle.send(()).unwrap();
 
+    if let Some(path) = &cache_state_file {
+        match dns_socket.save_pkarr_cache(path) {
+            Ok(()) => tracing::info!("Saved the pkarr cache to {}.", path.display()),
+            Err(e) => tracing::error!("Failed to save the pkarr cache to {}. {e}", path.display()),
+        };
+    };
+
     Ok(())
 }
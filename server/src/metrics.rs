@@ -0,0 +1,315 @@
+use once_cell::sync::Lazy;
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Application wide metrics. Rendered in the Prometheus text format by the admin server.
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+/// Default histogram buckets in seconds.
+const DURATION_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+trait Metric {
+    /// Writes this metric in the Prometheus text format.
+    fn render(&self, out: &mut String);
+}
+
+/// Monotonically increasing counter.
+#[derive(Debug)]
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, value: u64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Counter {
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+        let _ = writeln!(out, "{} {}", self.name, self.get());
+    }
+}
+
+/// Value that can go up and down.
+#[derive(Debug)]
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Gauge {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set(&self, value: u64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Gauge {
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} gauge", self.name);
+        let _ = writeln!(out, "{} {}", self.name, self.get());
+    }
+}
+
+/// Histogram of durations in seconds.
+#[derive(Debug)]
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    buckets: &'static [f64],
+    /// Number of observations per bucket. Not cumulative.
+    bucket_counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str, buckets: &'static [f64]) -> Self {
+        Self {
+            name,
+            help,
+            buckets,
+            bucket_counts: buckets.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(index) = self.buckets.iter().position(|bound| seconds <= *bound) {
+            self.bucket_counts[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Total number of observations.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Histogram {
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+        let mut cumulative = 0;
+        for (bound, count) in self.buckets.iter().zip(self.bucket_counts.iter()) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{bound}\"}} {cumulative}", self.name);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", self.name, self.count());
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {sum}", self.name);
+        let _ = writeln!(out, "{}_count {}", self.name, self.count());
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    /// Time queries wait on the per public key lock before they may refresh a pkarr packet.
+    pub pkarr_lock_wait_seconds: Histogram,
+    /// Number of times the per public key lock could not be acquired in time.
+    pub pkarr_lock_timeouts: Counter,
+    /// Number of queries that exceeded the query timeout.
+    pub query_timeouts: Counter,
+    /// Number of answers served from a cache entry older than the staleness threshold.
+    pub stale_answers_served: Counter,
+    /// Number of corrupt cache state entries skipped while loading the pkarr cache from disk.
+    pub cache_state_skipped_entries: Counter,
+    /// Number of EDNS options in queries that pkdns doesn't understand and ignored.
+    pub unsupported_edns_options: Counter,
+    /// Number of pkarr replies whose answers all got filtered out. Answered with NODATA.
+    pub all_answers_filtered: Counter,
+    /// Number of pkarr packets with more records than `max_records_per_packet`.
+    pub oversized_packets: Counter,
+    /// Number of queries aborted because they collected more records than `max_records_per_query`.
+    pub query_budget_exceeded: Counter,
+    /// Accounted memory of the pkarr packet cache in bytes.
+    pub pkarr_cache_size_bytes: Gauge,
+    /// Configured memory budget of the pkarr packet cache in bytes.
+    pub pkarr_cache_budget_bytes: Gauge,
+    /// Number of items in the pkarr packet cache.
+    pub pkarr_cache_entries: Gauge,
+    /// Estimated memory of the pkarr reply cache in bytes.
+    pub response_cache_size_bytes: Gauge,
+    /// Number of replies in the pkarr reply cache.
+    pub response_cache_entries: Gauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            pkarr_lock_wait_seconds: Histogram::new(
+                "pkdns_pkarr_lock_wait_seconds",
+                "Time queries wait on the per public key lock before refreshing a pkarr packet.",
+                &DURATION_BUCKETS,
+            ),
+            pkarr_lock_timeouts: Counter::new(
+                "pkdns_pkarr_lock_timeouts_total",
+                "Number of times the per public key lock could not be acquired within the lock timeout.",
+            ),
+            query_timeouts: Counter::new(
+                "pkdns_query_timeouts_total",
+                "Number of queries answered with SERVFAIL because they exceeded the query timeout.",
+            ),
+            stale_answers_served: Counter::new(
+                "pkdns_stale_answers_served_total",
+                "Number of answers served from a pkarr cache entry older than the staleness threshold.",
+            ),
+            cache_state_skipped_entries: Counter::new(
+                "pkdns_cache_state_skipped_entries_total",
+                "Number of corrupt entries skipped while loading the pkarr cache state from disk.",
+            ),
+            unsupported_edns_options: Counter::new(
+                "pkdns_unsupported_edns_options_total",
+                "Number of EDNS options in queries that pkdns doesn't understand. They are ignored.",
+            ),
+            all_answers_filtered: Counter::new(
+                "pkdns_all_answers_filtered_total",
+                "Number of pkarr replies answered with NODATA because all their answers got filtered out.",
+            ),
+            oversized_packets: Counter::new(
+                "pkdns_oversized_packets_total",
+                "Number of pkarr packets from the DHT with more records than the configured maximum.",
+            ),
+            query_budget_exceeded: Counter::new(
+                "pkdns_query_budget_exceeded_total",
+                "Number of queries answered with SERVFAIL because they collected more records than allowed.",
+            ),
+            pkarr_cache_size_bytes: Gauge::new(
+                "pkdns_pkarr_cache_size_bytes",
+                "Estimated memory of the pkarr packet cache. Packet bytes and records plus an estimated per entry overhead.",
+            ),
+            pkarr_cache_budget_bytes: Gauge::new(
+                "pkdns_pkarr_cache_budget_bytes",
+                "Configured memory budget of the pkarr packet cache.",
+            ),
+            pkarr_cache_entries: Gauge::new(
+                "pkdns_pkarr_cache_entries",
+                "Number of items in the pkarr packet cache.",
+            ),
+            response_cache_size_bytes: Gauge::new(
+                "pkdns_response_cache_size_bytes",
+                "Estimated memory of the cached pkarr replies. Key and reply bytes plus an estimated per entry overhead.",
+            ),
+            response_cache_entries: Gauge::new(
+                "pkdns_response_cache_entries",
+                "Number of cached pkarr replies.",
+            ),
+        }
+    }
+
+    fn all(&self) -> Vec<&dyn Metric> {
+        vec![
+            &self.pkarr_lock_wait_seconds,
+            &self.pkarr_lock_timeouts,
+            &self.query_timeouts,
+            &self.stale_answers_served,
+            &self.cache_state_skipped_entries,
+            &self.unsupported_edns_options,
+            &self.all_answers_filtered,
+            &self.oversized_packets,
+            &self.query_budget_exceeded,
+            &self.pkarr_cache_size_bytes,
+            &self.pkarr_cache_budget_bytes,
+            &self.pkarr_cache_entries,
+            &self.response_cache_size_bytes,
+            &self.response_cache_entries,
+        ]
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for metric in self.all() {
+            metric.render(&mut out);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_histogram() {
+        let histogram = Histogram::new("test_seconds", "Test histogram.", &DURATION_BUCKETS);
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(20));
+
+        let mut out = String::new();
+        histogram.render(&mut out);
+        assert!(out.contains("# TYPE test_seconds histogram"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.001\"} 0"));
+        assert!(out.contains("test_seconds_bucket{le=\"0.005\"} 1"));
+        assert!(out.contains("test_seconds_bucket{le=\"10\"} 1"));
+        assert!(out.contains("test_seconds_bucket{le=\"+Inf\"} 2"));
+        assert!(out.contains("test_seconds_sum 20.003"));
+        assert!(out.contains("test_seconds_count 2"));
+    }
+
+    #[test]
+    fn render_counter() {
+        let counter = Counter::new("test_total", "Test counter.");
+        counter.inc();
+        counter.inc();
+
+        let mut out = String::new();
+        counter.render(&mut out);
+        assert!(out.contains("# TYPE test_total counter"));
+        assert!(out.contains("test_total 2"));
+    }
+
+    #[test]
+    fn render_gauge() {
+        let gauge = Gauge::new("test_bytes", "Test gauge.");
+        gauge.set(5);
+        gauge.set(3);
+
+        let mut out = String::new();
+        gauge.render(&mut out);
+        assert!(out.contains("# TYPE test_bytes gauge"));
+        assert!(out.contains("test_bytes 3"));
+    }
+}
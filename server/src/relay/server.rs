@@ -0,0 +1,220 @@
+use crate::{
+    connection_limit::ConnectionLimit,
+    resolution::{CustomHandlerError, DnsSocket, PkarrResolverError},
+};
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use pkarr::{PublicKey, SignedPacket};
+use std::net::SocketAddr;
+
+// Pkarr relay HTTP protocol
+// https://github.com/pubky/pkarr/blob/main/design/relays.md
+// Lets pkarr clients use pkdns as their relay. Packets are served from the pkarr cache and the DHT.
+
+const PAYLOAD_CONTENT_TYPE: &str = "application/pkarr.org/relays#payload";
+
+fn parse_public_key(key: &str) -> Result<PublicKey, (StatusCode, String)> {
+    PublicKey::try_from(key).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid public key. {e}")))
+}
+
+/// Standby nodes don't serve relay requests until they are promoted.
+fn reject_standby(dns_socket: &DnsSocket) -> Result<(), (StatusCode, String)> {
+    if dns_socket.is_standby() {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "Node is in standby.".to_string()));
+    }
+    Ok(())
+}
+
+async fn relay_get(
+    Path(key): Path<String>,
+    State(mut dns_socket): State<DnsSocket>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+) -> Result<Response, (StatusCode, String)> {
+    reject_standby(&dns_socket)?;
+    let pubkey = parse_public_key(&key)?;
+    match dns_socket.resolve_signed_packet(&pubkey, Some(client_addr.ip())).await {
+        Ok(Some(packet)) => Ok((
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, PAYLOAD_CONTENT_TYPE)],
+            packet.to_relay_payload(),
+        )
+            .into_response()),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("No packet found for {pubkey}."))),
+        Err(CustomHandlerError::RateLimited(ip)) => {
+            Err((StatusCode::TOO_MANY_REQUESTS, format!("{ip} is rate limited.")))
+        }
+        Err(e) => {
+            tracing::debug!("Relay lookup of [{pubkey}] failed. {e}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Lookup failed. {e}")))
+        }
+    }
+}
+
+async fn relay_put(
+    Path(key): Path<String>,
+    State(mut dns_socket): State<DnsSocket>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    body: Bytes,
+) -> Result<StatusCode, (StatusCode, String)> {
+    reject_standby(&dns_socket)?;
+    let pubkey = parse_public_key(&key)?;
+    let packet = SignedPacket::from_relay_payload(&pubkey, &body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid signed packet. {e}")))?;
+    match dns_socket.publish_signed_packet(packet, Some(client_addr.ip())).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(e @ PkarrResolverError::OutdatedPacket(_)) => Err((StatusCode::CONFLICT, e.to_string())),
+        Err(e @ PkarrResolverError::TooManyRecords(_)) => Err((StatusCode::PAYLOAD_TOO_LARGE, e.to_string())),
+        Err(PkarrResolverError::RateLimited(ip)) => {
+            Err((StatusCode::TOO_MANY_REQUESTS, format!("{ip} is rate limited.")))
+        }
+        Err(e) => {
+            tracing::debug!("Relay publish of [{pubkey}] failed. {e}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Publish failed. {e}")))
+        }
+    }
+}
+
+fn create_app(dns_socket: DnsSocket) -> Router {
+    Router::new()
+        .route("/:key", get(relay_get).put(relay_put))
+        .with_state(dns_socket)
+}
+
+pub async fn run_relay_server(addr: SocketAddr, dns_socket: DnsSocket, connection_limit: ConnectionLimit) {
+    let app = create_app(dns_socket);
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, connection_limit.into_make_service(app))
+            .await
+            .unwrap();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_app, PAYLOAD_CONTENT_TYPE};
+    use crate::resolution::{
+        test_helpers::{a_packet, offline_socket, offline_socket_with_settings},
+        DnsSocket, MockDht, ResolverSettings,
+    };
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+    use pkarr::Keypair;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    async fn test_server(dht: &MockDht) -> TestServer {
+        server_for_socket(offline_socket(dht.clone()).await)
+    }
+
+    async fn test_server_with_settings(dht: &MockDht, settings: ResolverSettings) -> TestServer {
+        server_for_socket(offline_socket_with_settings(dht.clone(), settings).await)
+    }
+
+    fn server_for_socket(socket: DnsSocket) -> TestServer {
+        TestServer::new(create_app(socket).into_make_service_with_connect_info::<SocketAddr>()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn relay_get_served_from_cache() {
+        let keypair = Keypair::random();
+        let packet = a_packet(&keypair, Ipv4Addr::new(1, 1, 1, 1));
+        let dht = MockDht::new();
+        dht.add_packet(packet.clone());
+        let server = test_server(&dht).await;
+
+        for _ in 0..2 {
+            let response = server.get(&format!("/{}", keypair.to_z32())).await;
+            response.assert_status_ok();
+            assert_eq!(response.header("content-type"), PAYLOAD_CONTENT_TYPE);
+            assert_eq!(response.into_bytes(), packet.to_relay_payload());
+        }
+        assert_eq!(dht.lookup_count(), 1);
+
+        let response = server.get(&format!("/{}", Keypair::random().to_z32())).await;
+        response.assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn relay_put_published_to_dht() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        let server = test_server(&dht).await;
+        let old_packet = a_packet(&keypair, Ipv4Addr::new(1, 1, 1, 1));
+        let new_packet = a_packet(&keypair, Ipv4Addr::new(2, 2, 2, 2));
+
+        let response = server
+            .put(&format!("/{}", keypair.to_z32()))
+            .bytes(new_packet.to_relay_payload())
+            .await;
+        response.assert_status(StatusCode::NO_CONTENT);
+        assert!(dht.contains(&keypair.public_key()));
+
+        // Served from the cache without a DHT lookup.
+        let response = server.get(&format!("/{}", keypair.to_z32())).await;
+        assert_eq!(response.into_bytes(), new_packet.to_relay_payload());
+        assert_eq!(dht.lookup_count(), 0);
+
+        let response = server
+            .put(&format!("/{}", keypair.to_z32()))
+            .bytes(old_packet.to_relay_payload())
+            .await;
+        response.assert_status(StatusCode::CONFLICT);
+
+        let response = server
+            .put(&format!("/{}", keypair.to_z32()))
+            .bytes(vec![0u8; 10].into())
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn relay_put_burst_rate_limited() {
+        let mut settings = ResolverSettings::default();
+        settings.max_dht_queries_per_ip_per_second = 2;
+        let dht = MockDht::new();
+        let server = test_server_with_settings(&dht, settings).await;
+
+        let mut statuses = vec![];
+        for _ in 0..5 {
+            let keypair = Keypair::random();
+            let packet = a_packet(&keypair, Ipv4Addr::new(1, 1, 1, 1));
+            let response = server
+                .put(&format!("/{}", keypair.to_z32()))
+                .bytes(packet.to_relay_payload())
+                .await;
+            statuses.push(response.status_code());
+        }
+        assert_eq!(statuses[0], StatusCode::NO_CONTENT);
+        assert_eq!(statuses.last(), Some(&StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    #[tokio::test]
+    async fn standby_node_refuses_relay_requests() {
+        let keypair = Keypair::random();
+        let packet = a_packet(&keypair, Ipv4Addr::new(1, 1, 1, 1));
+        let dht = MockDht::new();
+        dht.add_packet(packet.clone());
+        let socket = offline_socket(dht.clone()).await;
+        socket.set_standby(true);
+        let server = server_for_socket(socket.clone());
+
+        let response = server.get(&format!("/{}", keypair.to_z32())).await;
+        response.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        let response = server
+            .put(&format!("/{}", keypair.to_z32()))
+            .bytes(packet.to_relay_payload())
+            .await;
+        response.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(dht.lookup_count(), 0);
+
+        socket.set_standby(false);
+        let response = server.get(&format!("/{}", keypair.to_z32())).await;
+        response.assert_status_ok();
+    }
+}
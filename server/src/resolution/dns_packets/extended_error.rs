@@ -0,0 +1,99 @@
+use pkarr::dns::{
+    rdata::{OPTCode, OPT},
+    Packet,
+};
+use std::borrow::Cow;
+
+/// EDNS option code of Extended DNS Errors.
+pub(super) const EDE_OPTION_CODE: u16 = 15;
+
+/// UDP payload size pkdns advertises in the OPT record of replies.
+const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// Extended DNS Error info codes (RFC 8914) pkdns returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtendedDnsError {
+    Other = 0,
+}
+
+/// Adds an Extended DNS Error to the reply.
+/// Only added if the query supports EDNS. Clients without EDNS don't understand OPT records.
+pub fn add_extended_error(reply: &mut Packet<'_>, query: &Packet<'_>, code: ExtendedDnsError, extra_text: &str) {
+    if query.opt().is_none() {
+        return;
+    }
+    let mut data = (code as u16).to_be_bytes().to_vec();
+    data.extend_from_slice(extra_text.as_bytes());
+    let option = OPTCode {
+        code: EDE_OPTION_CODE,
+        data: Cow::Owned(data),
+    };
+
+    let opt = reply.opt_mut().get_or_insert_with(|| OPT {
+        opt_codes: vec![],
+        udp_packet_size: EDNS_UDP_PAYLOAD_SIZE,
+        version: 0,
+    });
+    opt.opt_codes.push(option);
+}
+
+/// Copies the Extended DNS Errors of a downstream reply to the reply for the client.
+pub fn copy_extended_errors(reply: &mut Packet<'_>, downstream: &Packet<'_>) {
+    let Some(downstream_opt) = downstream.opt() else {
+        return;
+    };
+    let errors: Vec<OPTCode<'static>> = downstream_opt
+        .opt_codes
+        .iter()
+        .filter(|option| option.code == EDE_OPTION_CODE)
+        .map(|option| option.clone().into_owned())
+        .collect();
+    if errors.is_empty() {
+        return;
+    }
+    let opt = reply.opt_mut().get_or_insert_with(|| OPT {
+        opt_codes: vec![],
+        udp_packet_size: EDNS_UDP_PAYLOAD_SIZE,
+        version: 0,
+    });
+    opt.opt_codes.extend(errors);
+}
+
+/// Info code and extra text of the first Extended DNS Error in the packet.
+#[cfg(test)]
+pub fn get_extended_error(packet: &Packet<'_>) -> Option<(u16, String)> {
+    let option = packet
+        .opt()?
+        .opt_codes
+        .iter()
+        .find(|option| option.code == EDE_OPTION_CODE)?;
+    let code = u16::from_be_bytes(option.data.get(0..2)?.try_into().ok()?);
+    let extra_text = String::from_utf8_lossy(&option.data[2..]).to_string();
+    Some((code, extra_text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolution::test_helpers::set_opt;
+
+    #[test]
+    fn extended_error_roundtrip() {
+        let mut query = Packet::new_query(0);
+        set_opt(&mut query, vec![]);
+        let mut reply = Packet::new_reply(0);
+        add_extended_error(&mut reply, &query, ExtendedDnsError::Other, "Test");
+
+        let bytes = reply.build_bytes_vec().unwrap();
+        let reply = Packet::parse(&bytes).unwrap();
+        assert_eq!(get_extended_error(&reply), Some((0, "Test".to_string())));
+    }
+
+    #[test]
+    fn no_extended_error_without_edns() {
+        let query = Packet::new_query(0);
+        let mut reply = Packet::new_reply(0);
+        add_extended_error(&mut reply, &query, ExtendedDnsError::Other, "Test");
+        assert!(reply.opt().is_none());
+    }
+}
@@ -0,0 +1,109 @@
+use super::ParsedPacket;
+use pkarr::dns::{Packet, ResourceRecord, RCODE};
+
+/// DNSSEC record types (DS, RRSIG, NSEC, DNSKEY, NSEC3) a DO-bit client needs to validate an answer.
+const DNSSEC_TYPES: [u16; 5] = [43, 46, 47, 48, 50];
+
+fn is_dnssec_record(record: &ResourceRecord<'_>) -> bool {
+    DNSSEC_TYPES.contains(&u16::from(record.rdata.type_code()))
+}
+
+/// Removes the authority and additional records a positive answer doesn't need.
+/// Negative answers and referrals are kept as they are because their authority section is the answer.
+/// If the client set the DO bit, DNSSEC records are kept so minimal responses never break validation.
+pub fn minimize_reply(reply: Vec<u8>, dnssec_ok: bool) -> Vec<u8> {
+    let Ok(parsed) = ParsedPacket::new(reply.clone()) else {
+        return reply;
+    };
+    let mut packet = parsed.parsed().clone();
+    if packet.rcode() != RCODE::NoError || packet.answers.is_empty() {
+        return reply;
+    }
+    let before = packet.name_servers.len() + packet.additional_records.len();
+    packet
+        .name_servers
+        .retain(|record| dnssec_ok && is_dnssec_record(record));
+    packet
+        .additional_records
+        .retain(|record| dnssec_ok && is_dnssec_record(record));
+    if packet.name_servers.len() + packet.additional_records.len() == before {
+        return reply;
+    }
+    packet.build_bytes_vec_compressed().unwrap_or(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolution::dns_packets::ParsedQuery;
+    use crate::resolution::test_helpers::set_opt;
+    use pkarr::dns::{
+        rdata::{RData, A, NS, NULL},
+        Name, Question, CLASS, QCLASS, QTYPE, TYPE,
+    };
+
+    fn query(dnssec_ok: bool) -> ParsedQuery {
+        let mut query = Packet::new_query(0);
+        query.questions.push(Question::new(
+            Name::new("example.com").unwrap(),
+            QTYPE::TYPE(TYPE::A),
+            QCLASS::CLASS(CLASS::IN),
+            false,
+        ));
+        set_opt(&mut query, vec![]);
+        let mut bytes = query.build_bytes_vec().unwrap();
+        if dnssec_ok {
+            // The OPT record is last and has no options. Its TTL ends 2 bytes before the end, DO is the top bit
+            // of the third TTL byte.
+            let len = bytes.len();
+            bytes[len - 4] |= 0x80;
+        }
+        ParsedQuery::new(bytes).unwrap()
+    }
+
+    fn reply() -> Vec<u8> {
+        let name = Name::new("example.com").unwrap();
+        let mut reply = Packet::new_reply(0);
+        let address = A { address: 0x5db8d822 };
+        reply.answers.push(ResourceRecord::new(
+            name.clone(),
+            CLASS::IN,
+            300,
+            RData::A(address.clone()),
+        ));
+        let ns_name = Name::new("ns.example.com").unwrap();
+        reply.name_servers.push(ResourceRecord::new(
+            name.clone(),
+            CLASS::IN,
+            300,
+            RData::NS(NS(ns_name.clone())),
+        ));
+        reply
+            .additional_records
+            .push(ResourceRecord::new(ns_name, CLASS::IN, 300, RData::A(address)));
+        let signature = NULL::new(&[1, 2, 3]).unwrap();
+        reply
+            .additional_records
+            .push(ResourceRecord::new(name, CLASS::IN, 300, RData::NULL(46, signature)));
+        reply.build_bytes_vec().unwrap()
+    }
+
+    #[test]
+    fn dnssec_records_kept_for_do_bit() {
+        assert!(!query(false).dnssec_ok());
+        assert!(query(true).dnssec_ok());
+
+        let minimal = minimize_reply(reply(), query(false).dnssec_ok());
+        let minimal = Packet::parse(&minimal).unwrap();
+        assert_eq!(minimal.answers.len(), 1);
+        assert!(minimal.name_servers.is_empty());
+        assert!(minimal.additional_records.is_empty());
+
+        let minimal = minimize_reply(reply(), query(true).dnssec_ok());
+        let minimal = Packet::parse(&minimal).unwrap();
+        assert_eq!(minimal.answers.len(), 1);
+        assert!(minimal.name_servers.is_empty());
+        assert_eq!(minimal.additional_records.len(), 1);
+        assert_eq!(u16::from(minimal.additional_records[0].rdata.type_code()), 46);
+    }
+}
@@ -1,5 +1,11 @@
+mod extended_error;
+mod minimal_responses;
 mod parsed_packet;
 mod parsed_query;
 
+#[cfg(test)]
+pub use extended_error::get_extended_error;
+pub use extended_error::{add_extended_error, copy_extended_errors, ExtendedDnsError};
+pub use minimal_responses::minimize_reply;
 pub use parsed_packet::ParsedPacket;
-pub use parsed_query::{ParseQueryError, ParsedQuery};
+pub use parsed_query::{ParseQueryError, ParsedQuery, FRESH_LOOKUP_OPTION_CODE};
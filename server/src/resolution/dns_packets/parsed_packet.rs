@@ -1,14 +1,88 @@
+use super::extended_error::{add_extended_error, ExtendedDnsError};
 use anyhow::anyhow;
 use chrono::format::Parsed;
-use pkarr::dns::{Packet, PacketFlag};
+use pkarr::dns::{Packet, PacketFlag, SimpleDnsError, QTYPE, TYPE};
 use self_cell::self_cell;
 use std::{fmt::Display, pin::Pin};
 
+/**
+ * Raw bytes of a packet.
+ * simple-dns refuses to parse questions with a qtype it doesn't know, for example TYPE65283. Those packets are
+ * parsed from a copy with the qtypes replaced by A. The original qtypes are put back on the parsed questions.
+ */
+#[derive(Debug)]
+pub struct WireBytes {
+    raw: Vec<u8>,
+    /// Copy of `raw` simple-dns can parse. None if `raw` can be parsed.
+    parseable: Option<Vec<u8>>,
+    /// Index and qtype of the questions with a qtype simple-dns doesn't know.
+    unknown_qtypes: Vec<(usize, u16)>,
+}
+
+impl WireBytes {
+    fn new(raw: Vec<u8>) -> Self {
+        let mut wire = Self {
+            raw,
+            parseable: None,
+            unknown_qtypes: vec![],
+        };
+        if let Err(SimpleDnsError::InvalidQType(_)) = Packet::parse(&wire.raw) {
+            let mut parseable = wire.raw.clone();
+            if let Some(unknown_qtypes) = replace_unknown_qtypes(&mut parseable) {
+                wire.parseable = Some(parseable);
+                wire.unknown_qtypes = unknown_qtypes;
+            }
+        }
+        wire
+    }
+
+    fn parse(&self) -> Result<Packet<'_>, SimpleDnsError> {
+        let mut packet = Packet::parse(self.parseable.as_ref().unwrap_or(&self.raw))?;
+        for (index, qtype) in self.unknown_qtypes.iter() {
+            packet.questions[*index].qtype = QTYPE::TYPE(TYPE::Unknown(*qtype));
+        }
+        Ok(packet)
+    }
+}
+
+/// Position after the name that starts at `position`.
+pub(super) fn skip_name(data: &[u8], mut position: usize) -> Option<usize> {
+    loop {
+        let length = *data.get(position)? as usize;
+        if length == 0 {
+            return Some(position + 1);
+        }
+        if length & 0xC0 == 0xC0 {
+            // Compression pointer
+            return Some(position + 2);
+        }
+        position += 1 + length;
+    }
+}
+
+/// Replaces the qtypes simple-dns doesn't know with A.
+/// Returns the index and the original qtype of every replaced question. None if the packet is malformed.
+fn replace_unknown_qtypes(data: &mut [u8]) -> Option<Vec<(usize, u16)>> {
+    let questions = u16::from_be_bytes([*data.get(4)?, *data.get(5)?]);
+    let mut replaced = vec![];
+    let mut position = 12;
+    for index in 0..questions as usize {
+        position = skip_name(data, position)?;
+        let qtype = u16::from_be_bytes([*data.get(position)?, *data.get(position + 1)?]);
+        if QTYPE::try_from(qtype).is_err() {
+            data[position..position + 2].copy_from_slice(&u16::from(TYPE::A).to_be_bytes());
+            replaced.push((index, qtype));
+        }
+        position += 4;
+    }
+    Some(replaced)
+}
+
 // Struct to hold the bytes and the packet in one place
 // to avoid lifetimes
 self_cell!(
     pub struct Inner {
-        owner: Vec<u8>,
+        owner: WireBytes,
 
         #[covariant]
         dependent: Packet,
@@ -20,7 +94,7 @@ self_cell!(
 impl Inner {
     /// Try to parse the packet from bytes
     pub fn try_from_bytes(bytes: Vec<u8>) -> Result<Self, pkarr::dns::SimpleDnsError> {
-        Self::try_new(bytes, |bytes| Packet::parse(&bytes))
+        Self::try_new(WireBytes::new(bytes), |wire| wire.parse())
     }
 
     /// Parsed DNS packet
@@ -30,7 +104,7 @@ impl Inner {
 
     /// Raw bytes the packet is build with
     pub fn raw_bytes(&self) -> &Vec<u8> {
-        &self.borrow_owner()
+        &self.borrow_owner().raw
     }
 }
 
@@ -43,7 +117,7 @@ impl Clone for Inner {
 
 impl Into<Vec<u8>> for Inner {
     fn into(self) -> Vec<u8> {
-        self.into_owner()
+        self.into_owner().raw
     }
 }
 
@@ -97,6 +171,22 @@ impl ParsedPacket {
         *reply.rcode_mut() = pkarr::dns::RCODE::ServerFailure;
         reply.build_bytes_vec_compressed().unwrap()
     }
+
+    /// Create SRVFAIL reply with an Extended DNS Error if the query supports EDNS.
+    pub fn create_server_fail_reply_with_ede(&self, code: ExtendedDnsError, extra_text: &str) -> Vec<u8> {
+        let mut reply = Packet::new_reply(self.id());
+        *reply.rcode_mut() = pkarr::dns::RCODE::ServerFailure;
+        add_extended_error(&mut reply, self.parsed(), code, extra_text);
+        reply.build_bytes_vec_compressed().unwrap()
+    }
+
+    /// Create NOTIMP reply with an Extended DNS Error if the query supports EDNS.
+    pub fn create_not_implemented_reply_with_ede(&self, code: ExtendedDnsError, extra_text: &str) -> Vec<u8> {
+        let mut reply = Packet::new_reply(self.id());
+        *reply.rcode_mut() = pkarr::dns::RCODE::NotImplemented;
+        add_extended_error(&mut reply, self.parsed(), code, extra_text);
+        reply.build_bytes_vec_compressed().unwrap()
+    }
 }
 
 impl Into<Vec<u8>> for ParsedPacket {
@@ -124,4 +214,20 @@ mod tests {
         let parsed = ParsedPacket::new(raw_query).unwrap();
         assert_eq!(parsed.parsed().id(), 0);
     }
+
+    #[tokio::test]
+    async fn unknown_qtype_parsed() {
+        let mut query = Packet::new_query(0);
+        let qname = Name::new("example.com").unwrap();
+        let qtype = pkarr::dns::QTYPE::TYPE(pkarr::dns::TYPE::Unknown(65283));
+        let qclass = pkarr::dns::QCLASS::CLASS(pkarr::dns::CLASS::IN);
+        query.questions = vec![Question::new(qname, qtype, qclass, false)];
+        let raw_query = query.build_bytes_vec_compressed().unwrap();
+        assert!(Packet::parse(&raw_query).is_err());
+
+        let parsed = ParsedPacket::new(raw_query.clone()).unwrap();
+        assert_eq!(parsed.parsed().questions[0].qtype, qtype);
+        assert_eq!(parsed.raw_bytes(), &raw_query);
+        assert_eq!(parsed.parsed().build_bytes_vec_compressed().unwrap(), raw_query);
+    }
 }
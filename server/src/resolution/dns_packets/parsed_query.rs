@@ -1,9 +1,16 @@
 use std::fmt::Display;
 
-use super::ParsedPacket;
+use super::{extended_error::EDE_OPTION_CODE, parsed_packet::skip_name, ParsedPacket};
 use anyhow::anyhow;
 use pkarr::dns::{Packet, PacketFlag, Question, QTYPE};
 
+/// EDNS option that asks for a fresh DHT lookup instead of a cached answer. From the local/experimental
+/// range of RFC 6891. The option data is ignored.
+pub const FRESH_LOOKUP_OPTION_CODE: u16 = 65100;
+
+/// EDNS option codes pkdns understands. Others are ignored as required by RFC 6891.
+const SUPPORTED_EDNS_OPTIONS: [u16; 2] = [EDE_OPTION_CODE, FRESH_LOOKUP_OPTION_CODE];
+
 #[derive(thiserror::Error, Debug)]
 pub enum ParseQueryError {
     #[error("Dns packet parse error: {0}")]
@@ -54,11 +61,64 @@ impl ParsedQuery {
         self.question().qtype == QTYPE::ANY
     }
 
+    /// If this query contains an EDNS OPT record.
+    pub fn has_edns(&self) -> bool {
+        self.packet.parsed().opt().is_some()
+    }
+
+    /// Number of EDNS options in this query that pkdns doesn't understand.
+    pub fn unsupported_edns_options(&self) -> usize {
+        self.packet.parsed().opt().map_or(0, |opt| {
+            opt.opt_codes
+                .iter()
+                .filter(|option| !SUPPORTED_EDNS_OPTIONS.contains(&option.code))
+                .count()
+        })
+    }
+
+    /// If this query carries the fresh lookup EDNS option.
+    pub fn requests_fresh_lookup(&self) -> bool {
+        self.packet.parsed().opt().is_some_and(|opt| {
+            opt.opt_codes
+                .iter()
+                .any(|option| option.code == FRESH_LOOKUP_OPTION_CODE)
+        })
+    }
+
+    /// If the DNSSEC OK bit is set in the OPT record of this query.
+    /// simple-dns doesn't expose the EDNS flags so the bit is read from the wire format.
+    pub fn dnssec_ok(&self) -> bool {
+        read_dnssec_ok(self.packet.raw_bytes()).unwrap_or(false)
+    }
+
     pub fn is_recursion_desired(&self) -> bool {
         self.packet.parsed().has_flags(PacketFlag::RECURSION_DESIRED)
     }
 }
 
+/// DO bit of the OPT record. None if the packet is malformed.
+fn read_dnssec_ok(data: &[u8]) -> Option<bool> {
+    let read_u16 = |position: usize| Some(u16::from_be_bytes([*data.get(position)?, *data.get(position + 1)?]));
+    let questions = read_u16(4)?;
+    let records = read_u16(6)? as usize + read_u16(8)? as usize + read_u16(10)? as usize;
+    let mut position = 12;
+    for _ in 0..questions {
+        position = skip_name(data, position)? + 4;
+    }
+    for _ in 0..records {
+        position = skip_name(data, position)?;
+        let record_type = read_u16(position)?;
+        if record_type == 41 {
+            // The TTL of OPT holds the extended rcode, the version and the flags. DO is the top flag bit.
+            let flags = read_u16(position + 6)?;
+            return Some(flags & 0x8000 != 0);
+        }
+        let rdata_length = read_u16(position + 8)? as usize;
+        position += 10 + rdata_length;
+    }
+    Some(false)
+}
+
 impl Display for ParsedQuery {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let question = self.question();
@@ -1,32 +1,49 @@
 #![allow(unused)]
 use crate::{
-    config::get_global_config,
-    resolution::{helpers::replace_packet_id, pkd::CustomHandlerError},
+    config::{expand_tilde, get_global_config, CacheableTypeList, ReservedTldPolicy, UnsupportedKeyPolicy},
+    metrics::METRICS,
+    resolution::{
+        helpers::replace_packet_id,
+        pkd::{CustomHandlerError, PkarrResolverError},
+    },
 };
 use rand::Rng;
 use tracing_subscriber::fmt::format;
 
 use super::{
-    dns_packets::{ParsedPacket, ParsedQuery},
+    dns_packets::{copy_extended_errors, minimize_reply, ExtendedDnsError, ParsedPacket, ParsedQuery},
+    forward_server::ForwardServer,
     pending_request::{PendingRequest, PendingRequestStore},
-    pkd::{PkarrResolver, ResolverSettings, TopLevelDomain},
+    pkd::{
+        parse_record_type, read_packet_dir, CacheImport, CacheableTypes, DirSharedCache, DnameParent, ParentResolver,
+        PkarrResolver, RelayFallback, RelaySet, RepublishSettings, ResolverSettings, SoaTemplate, TldSettings,
+        TopLevelDomain, DNAME_TYPE_CODE,
+    },
     query_id_manager::QueryIdManager,
     rate_limiter::{RateLimiter, RateLimiterBuilder},
     response_cache::IcannLruCache,
 };
-use pkarr::dns::{
-    rdata::{RData, A, AAAA, NS},
-    Packet, PacketFlag, SimpleDnsError, QTYPE, RCODE,
+use pkarr::{
+    dns::{
+        rdata::{RData, A, AAAA, CNAME, NS},
+        Name, Packet, PacketFlag, SimpleDnsError, QTYPE, RCODE,
+    },
+    PublicKey, SignedPacket,
 };
 use std::{
+    collections::HashSet,
     hash::{Hash, Hasher},
     num::NonZeroU64,
+    path::Path,
     thread::current,
 };
 use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     num::NonZeroU32,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 use std::{
@@ -40,6 +57,9 @@ use tokio::{
 };
 use tracing::Level;
 
+/// How often the republisher checks for due packets.
+const REPUBLISH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Any error related to receiving and sending DNS packets on the UDP socket.
 #[derive(thiserror::Error, Debug)]
 pub enum DnsSocketError {
@@ -56,6 +76,31 @@ pub enum DnsSocketError {
     RxReceiedErr(#[from] oneshot::error::RecvError),
 }
 
+/// Type codes of the record types of the config. The names are validated when reading the config.
+fn parse_record_types(names: &[String]) -> HashSet<u16> {
+    names
+        .iter()
+        .map(|name| parse_record_type(name).expect("Record type is validated when reading the config."))
+        .collect()
+}
+
+/// Number of records in all sections of the packet.
+fn count_records(packet: &Packet<'_>) -> usize {
+    packet.answers.len() + packet.name_servers.len() + packet.additional_records.len()
+}
+
+/// The query collected more records than `max_records_per_query` allows.
+#[derive(Debug)]
+struct RecordBudgetExceeded;
+
+/// Number of CNAME records. Every CNAME is one step of the resolution, no matter which source resolved it.
+fn count_cnames(records: &[pkarr::dns::ResourceRecord<'_>]) -> usize {
+    records
+        .iter()
+        .filter(|record| matches!(record.rdata, RData::CNAME(_)))
+        .count()
+}
+
 /**
  * DNS UDP socket
  */
@@ -64,12 +109,28 @@ pub struct DnsSocket {
     socket: Arc<UdpSocket>,
     pending: PendingRequestStore,
     pkarr_resolver: PkarrResolver,
-    icann_fallback: SocketAddr,
+    icann_fallback: ForwardServer,
     id_manager: QueryIdManager,
     rate_limiter: Arc<RateLimiter>,
     disable_any_queries: bool,
     icann_cache: IcannLruCache,
     max_recursion_depth: u8,
+    /// Maximum number of CNAMEs and referrals followed across pkarr and ICANN. 0 = unlimited.
+    max_resolution_depth: u8,
+    /// Maximum number of records collected for one query over all replies of the recursion. 0 = unlimited.
+    max_records_per_query: usize,
+    require_edns: bool,
+    /// Leave out the authority and additional records positive answers don't need.
+    minimal_responses: bool,
+    query_timeout: Duration,
+    resolve_all_questions: bool,
+    randomize_forward_port: bool,
+    reserved_tld_policy: ReservedTldPolicy,
+    unsupported_key_policy: UnsupportedKeyPolicy,
+    /// Queries received on a trusted socket bypass the rate limits.
+    trusted: bool,
+    /// Standby nodes keep their cache warm but refuse queries until they are promoted. Shared by all clones.
+    standby: Arc<AtomicBool>,
 }
 
 impl DnsSocket {
@@ -104,6 +165,35 @@ impl DnsSocket {
         .await
     }
 
+    /// Dns socket with a random local port that resolves pkarr packets with the given resolver.
+    /// Doesn't touch the network unless a query is forwarded to ICANN. Made for testing.
+    #[cfg(test)]
+    pub async fn random_socket_with_resolver(pkarr_resolver: PkarrResolver) -> tokio::io::Result<Self> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            pending: PendingRequestStore::new(),
+            pkarr_resolver,
+            icann_fallback: ForwardServer::new("8.8.8.8:53".parse().unwrap()),
+            id_manager: QueryIdManager::new(),
+            rate_limiter: Arc::new(RateLimiterBuilder::new().build()),
+            disable_any_queries: false,
+            icann_cache: IcannLruCache::new(1, 0, 0),
+            max_recursion_depth: 5,
+            max_resolution_depth: 20,
+            max_records_per_query: 1000,
+            require_edns: false,
+            minimal_responses: false,
+            query_timeout: Duration::from_millis(10_000),
+            resolve_all_questions: false,
+            randomize_forward_port: true,
+            reserved_tld_policy: ReservedTldPolicy::Forward,
+            unsupported_key_policy: UnsupportedKeyPolicy::NotImp,
+            trusted: false,
+            standby: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
     // Create a new DNS socket
     pub async fn new(
         listening: SocketAddr,
@@ -120,11 +210,14 @@ impl DnsSocket {
         max_recursion_depth: u8,
     ) -> tokio::io::Result<Self> {
         let socket = UdpSocket::bind(listening).await?;
+        let config = get_global_config();
+
         let limiter = RateLimiterBuilder::new()
             .max_per_second(max_queries_per_ip_per_second)
-            .burst_size(max_queries_per_ip_burst);
-
-        let config = get_global_config();
+            .burst_size(max_queries_per_ip_burst)
+            .subnet_max_per_second(config.dns.query_rate_limit_subnet)
+            .subnet_burst_size(config.dns.query_rate_limit_subnet_burst)
+            .subnet_prefix_len(config.dns.subnet_ipv4_prefix_len, config.dns.subnet_ipv6_prefix_len);
 
         let resolver_settings = ResolverSettings {
             max_ttl,
@@ -134,18 +227,102 @@ impl DnsSocket {
             max_dht_queries_per_ip_per_second,
             max_dht_queries_per_ip_burst,
             top_level_domain: top_level_domain,
+            lock_timeout_ms: config.dht.dht_lock_timeout_ms,
+            tld_overrides: config
+                .dht
+                .tld_overrides
+                .iter()
+                .map(|(label, tld_override)| TldSettings {
+                    tld: TopLevelDomain::new(label.clone()),
+                    min_ttl: tld_override.min_ttl,
+                    max_ttl: tld_override.max_ttl,
+                })
+                .collect(),
+            dname_parents: config
+                .dht
+                .dname_parents
+                .iter()
+                .map(|parent| DnameParent::new(parent).expect("DNAME parent is validated when reading the config."))
+                .collect(),
+            any_policy: config.dns.any_policy,
+            min_dht_responses_for_ready: config.dht.min_dht_responses_for_ready,
+            debug_keys: config
+                .dht
+                .debug_keys
+                .iter()
+                .map(|key| PublicKey::try_from(key.as_str()).expect("Debug key is validated when reading the config."))
+                .collect(),
+            lenient_parsing: config.dht.lenient_parsing,
+            max_records_per_packet: config.dht.max_records_per_packet,
+            oversized_packet_policy: config.dht.oversized_packet_policy,
+            staleness_warn_s: config.dht.staleness_warn_s,
+            log_stale_answers: config.dht.log_stale_answers,
+            reserved_tlds: config.dns.reserved_tlds.iter().map(|tld| tld.to_lowercase()).collect(),
+            nodata_soa: config.dht.nodata_soa,
+            block_private_ips: config.dht.block_private_ips,
+            fully_qualify_owner_names: config.dht.fully_qualify_owner_names,
+            allow_fresh_lookups: config.dht.allow_fresh_lookups,
+            randomize_dht_port: config.general.randomize_source_ports,
+            soa_template: config.dht.soa.clone(),
+            cacheable_types: match &config.dht.cacheable_types {
+                None => CacheableTypes::All,
+                Some(CacheableTypeList::Allow(types)) => CacheableTypes::Allow(parse_record_types(types)),
+                Some(CacheableTypeList::Deny(types)) => CacheableTypes::Deny(parse_record_types(types)),
+            },
+            relay_fallback: (!config.dht.relay_sets.is_empty()).then(|| {
+                let sets = config
+                    .dht
+                    .relay_sets
+                    .iter()
+                    .map(|set| RelaySet::new(set.name.clone(), set.relays.clone()))
+                    .collect();
+                RelayFallback::new(
+                    sets,
+                    Duration::from_millis(config.dht.relay_timeout_ms),
+                    Duration::from_secs(config.dht.relay_set_cooldown_s),
+                )
+            }),
+            republish: (config.dht.republish_interval_s > 0).then(|| RepublishSettings {
+                interval: Duration::from_secs(config.dht.republish_interval_s),
+                jitter: Duration::from_secs(config.dht.republish_jitter_s),
+                max_per_second: config.dht.max_republishes_per_second,
+                max_packets: config.dht.max_republished_packets,
+                max_age: Duration::from_secs(config.dht.republish_max_age_s),
+            }),
+            top_keys_tracked: config.dht.top_keys_tracked,
+            parent_resolver: config.dht.parent_resolver.map(|addr| {
+                ParentResolver::new(
+                    addr,
+                    Duration::from_millis(config.dht.relay_timeout_ms),
+                    Duration::from_secs(config.dht.relay_set_cooldown_s),
+                )
+            }),
         };
-        let pkarr_resolver = PkarrResolver::new(resolver_settings).await;
+        let mut pkarr_resolver = PkarrResolver::new(resolver_settings).await;
+        if let Some(dir) = config.dht.shared_cache_dir.as_ref().map(expand_tilde) {
+            pkarr_resolver = pkarr_resolver.with_shared_cache(Box::new(DirSharedCache::new(dir)?));
+        }
         Ok(Self {
             socket: Arc::new(socket),
             pending: PendingRequestStore::new(),
             pkarr_resolver: pkarr_resolver,
-            icann_fallback: icann_resolver,
+            icann_fallback: ForwardServer::new(icann_resolver),
             id_manager: QueryIdManager::new(),
             rate_limiter: Arc::new(limiter.build()),
             disable_any_queries: config.dns.disable_any_queries,
             icann_cache: IcannLruCache::new(icann_cache_mb, min_ttl, max_ttl),
             max_recursion_depth,
+            max_resolution_depth: config.dns.max_resolution_depth,
+            max_records_per_query: config.dns.max_records_per_query,
+            require_edns: config.dns.require_edns,
+            minimal_responses: config.dns.minimal_responses,
+            query_timeout: Duration::from_millis(config.dns.query_timeout_ms),
+            resolve_all_questions: config.dns.resolve_all_questions,
+            randomize_forward_port: config.general.randomize_source_ports,
+            reserved_tld_policy: config.dns.reserved_tld_policy,
+            unsupported_key_policy: config.dns.unsupported_key_policy,
+            trusted: config.general.socket_trusted,
+            standby: Arc::new(AtomicBool::new(config.general.standby)),
         })
     }
 
@@ -225,15 +402,120 @@ impl DnsSocket {
         }
 
         let mut socket = self.clone();
+        let rate_limited_ip = (!self.trusted).then(|| from.ip());
         tokio::spawn(async move {
             let start = Instant::now();
-            let reply = socket.query_me_recursively_with_log(&query, Some(from.ip())).await;
+            let reply = socket.query_me_recursively_with_log(&query, rate_limited_ip).await;
             socket.send_to(&reply, &from).await;
         });
 
         Ok(())
     }
 
+    /// True if the socket serves queries and the DHT answered enough lookups.
+    /// Standby nodes are not ready until they are promoted.
+    pub fn is_ready(&self) -> bool {
+        !self.is_standby() && self.pkarr_resolver.is_ready()
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::Relaxed)
+    }
+
+    /// Puts the node into standby or promotes it to serve queries again.
+    pub fn set_standby(&self, standby: bool) {
+        self.standby.store(standby, Ordering::Relaxed);
+    }
+
+    /// Refreshes due cache entries every `interval` while the node is in standby. Stops once it's promoted.
+    pub fn start_standby_prefetch(&self, interval: Duration) {
+        let mut socket = self.clone();
+        tokio::spawn(async move {
+            while socket.is_standby() {
+                tokio::time::sleep(interval).await;
+                if !socket.is_standby() {
+                    break;
+                }
+                let refreshed = socket.pkarr_resolver.prefetch_expired().await;
+                tracing::debug!("Standby prefetch refreshed {refreshed} pkarr packets.");
+            }
+        });
+    }
+
+    /// Re-resolves the hostname of the forward server every `interval` and follows address changes.
+    pub fn start_forward_re_resolution(&self, hostname: String, interval: Duration) {
+        self.icann_fallback.start_re_resolution(hostname, interval);
+    }
+
+    /// Republishes the packets published through this node whenever they are due.
+    pub fn start_republisher(&self) {
+        let socket = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(REPUBLISH_CHECK_INTERVAL).await;
+                let republished = socket.pkarr_resolver.republish_due().await;
+                if republished > 0 {
+                    tracing::debug!("Republished {republished} pkarr packets.");
+                }
+            }
+        });
+    }
+
+    /// Reports the memory footprint of the pkarr packet and reply caches as gauges.
+    pub async fn update_cache_gauges(&self) {
+        self.pkarr_resolver.update_cache_gauges().await
+    }
+
+    /// Most queried public keys with their query counts, most queried first.
+    pub fn top_keys(&self) -> Vec<(PublicKey, u64)> {
+        self.pkarr_resolver.top_keys()
+    }
+
+    /// Signed packet of a public key from the pkarr cache or the DHT.
+    pub async fn resolve_signed_packet(
+        &mut self,
+        pubkey: &PublicKey,
+        from: Option<IpAddr>,
+    ) -> Result<Option<SignedPacket>, CustomHandlerError> {
+        self.pkarr_resolver.resolve_signed_packet(pubkey, from).await
+    }
+
+    /// Publishes a signed packet on the DHT and caches it. Publishes count against the DHT rate limit of `from`.
+    pub async fn publish_signed_packet(
+        &mut self,
+        packet: SignedPacket,
+        from: Option<IpAddr>,
+    ) -> Result<(), PkarrResolverError> {
+        self.pkarr_resolver.publish_signed_packet(packet, from).await
+    }
+
+    /// Loads the pre-signed packets of a directory. They are served without DHT lookups.
+    /// Returns the number of loaded packets. Packets with too many records are skipped.
+    pub fn load_local_packets(&self, dir: &Path) -> std::io::Result<usize> {
+        let packets = read_packet_dir(dir)?;
+        let mut count = 0;
+        for packet in packets {
+            let pubkey = packet.public_key();
+            if self.pkarr_resolver.add_local_packet(packet) {
+                count += 1;
+            } else {
+                tracing::warn!("Skip local packet [{pubkey}]. It has more records than allowed.");
+            }
+        }
+        Ok(count)
+    }
+
+    /// Writes the pkarr cache to a file so a new pkdns process can start with a warm cache.
+    pub fn save_pkarr_cache(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, self.pkarr_resolver.export_cache())
+    }
+
+    /// Loads a pkarr cache written by `save_pkarr_cache`. Corrupt entries are skipped unless `strict` is set.
+    pub async fn load_pkarr_cache(&mut self, path: &Path, strict: bool) -> Result<CacheImport, anyhow::Error> {
+        let data = std::fs::read(path)?;
+        Ok(self.pkarr_resolver.import_cache(&data, strict).await?)
+    }
+
     /// Queries recursively with a byte query. If the query can't be parsed, return a server fail.
     pub async fn query_me_recursively_raw(&mut self, query: Vec<u8>, from: Option<IpAddr>) -> Vec<u8> {
         let packet = ParsedPacket::new(query);
@@ -248,29 +530,129 @@ impl DnsSocket {
         }
     }
 
-    /// Queries recursively with a log.
+    /// Queries recursively with a log. Replies with SERVFAIL if the query takes longer than the query timeout.
     pub async fn query_me_recursively_with_log(&mut self, query: &ParsedQuery, from: Option<IpAddr>) -> Vec<u8> {
+        if self.is_standby() {
+            tracing::trace!("Refused {query}. Node is in standby.");
+            return query.packet.create_refused_reply();
+        }
+        let unsupported_options = query.unsupported_edns_options();
+        if unsupported_options > 0 {
+            tracing::trace!("Ignored {unsupported_options} unsupported EDNS options. {query}");
+            METRICS.unsupported_edns_options.inc_by(unsupported_options as u64);
+        }
         let start = Instant::now();
-        let reply = self.query_me_recursively(&query, from).await;
+        let query_timeout = self.query_timeout;
+        let reply = match tokio::time::timeout(query_timeout, self.query_questions(query, from)).await {
+            Ok(reply) => reply,
+            Err(_) => {
+                METRICS.query_timeouts.inc();
+                tracing::warn!("{query} timed out after {}ms.", query_timeout.as_millis());
+                query
+                    .packet
+                    .create_server_fail_reply_with_ede(ExtendedDnsError::Other, "Query timed out.")
+            }
+        };
         tracing::debug!("{query} processed within {}ms.", start.elapsed().as_millis());
-        reply
+        if self.minimal_responses {
+            minimize_reply(reply, query.dnssec_ok())
+        } else {
+            reply
+        }
+    }
+
+    /// SERVFAIL for a query that collected more records than `max_records_per_query`.
+    fn record_budget_exceeded_reply(query: &ParsedQuery) -> Vec<u8> {
+        tracing::debug!("Query exceeded its record budget. {query}");
+        METRICS.query_budget_exceeded.inc();
+        query
+            .packet
+            .create_server_fail_reply_with_ede(ExtendedDnsError::Other, "Query record budget exceeded.")
+    }
+
+    /// Answers the first question of the query. If `resolve_all_questions` is enabled, resolves
+    /// every question one after another and merges the answers into one reply.
+    /// All questions share the record budget of the query.
+    async fn query_questions(&mut self, query: &ParsedQuery, from: Option<IpAddr>) -> Vec<u8> {
+        let mut remaining = (self.max_records_per_query > 0).then_some(self.max_records_per_query);
+        let questions = &query.packet.parsed().questions;
+        if !self.resolve_all_questions || questions.len() < 2 {
+            return self
+                .query_me_recursively(query, from, remaining)
+                .await
+                .unwrap_or_else(|_| Self::record_budget_exceeded_reply(query));
+        }
+
+        let mut replies: Vec<Vec<u8>> = vec![];
+        for question in questions {
+            let mut single = query.packet.parsed().clone();
+            single.questions = vec![question.clone()];
+            let single = match single.build_bytes_vec().map(ParsedQuery::new) {
+                Ok(Ok(single)) => single,
+                _ => return query.packet.create_server_fail_reply(),
+            };
+            let Ok(reply) = self.query_me_recursively(&single, from, remaining).await else {
+                return Self::record_budget_exceeded_reply(query);
+            };
+            let records = ParsedPacket::new(reply.clone()).map_or(0, |reply| count_records(reply.parsed()));
+            remaining = remaining.map(|remaining| remaining.saturating_sub(records));
+            replies.push(reply);
+        }
+
+        let mut parsed_replies = vec![];
+        for reply in replies {
+            match ParsedPacket::new(reply) {
+                Ok(parsed) => parsed_replies.push(parsed),
+                Err(e) => {
+                    tracing::debug!("Failed to parse reply of a single question {e}. {query}");
+                    return query.packet.create_server_fail_reply();
+                }
+            }
+        }
+
+        // The first reply decides the flags and the rcode.
+        let mut parsed_replies = parsed_replies.iter().map(ParsedPacket::parsed);
+        let mut merged = parsed_replies.next().expect("At least two questions.").clone();
+        merged.questions = questions.clone();
+        for reply in parsed_replies {
+            merged.answers.extend(reply.answers.iter().cloned());
+            merged.name_servers.extend(reply.name_servers.iter().cloned());
+            merged
+                .additional_records
+                .extend(reply.additional_records.iter().cloned());
+        }
+        merged.build_bytes_vec().unwrap_or_else(|e| {
+            tracing::debug!("Failed to build merged reply {e}. {query}");
+            query.packet.create_server_fail_reply()
+        })
     }
 
     /// Queries recursively. This is the main query function of this socket.
-    async fn query_me_recursively(&mut self, query: &ParsedQuery, from: Option<IpAddr>) -> Vec<u8> {
+    /// Fails as soon as the reply collects more than `max_records`. None = unlimited.
+    async fn query_me_recursively(
+        &mut self,
+        query: &ParsedQuery,
+        from: Option<IpAddr>,
+        max_records: Option<usize>,
+    ) -> Result<Vec<u8>, RecordBudgetExceeded> {
         // Rate limit check
         if let Some(ip) = &from {
             if self.rate_limiter.check_is_limited_and_increase(ip) {
                 tracing::trace!("Rate limited {}. query_id={}", query.packet.id(), ip);
-                return query.packet.create_refused_reply();
+                return Ok(query.packet.create_refused_reply());
             };
         }
 
+        if self.require_edns && !query.has_edns() {
+            tracing::trace!("Query without EDNS refused. {query}");
+            return Ok(query.packet.create_refused_reply());
+        }
+
         // Based on https://datatracker.ietf.org/doc/html/rfc1034#section-4.3.2
 
         let client_query = query;
         let client_query_data: Vec<u8> = client_query.packet.clone().into();
-        let mut client_reply = Packet::parse(&client_query_data).unwrap().into_reply();
+        let mut client_reply = client_query.packet.parsed().clone().into_reply();
         if self.is_recursion_available() {
             client_reply.set_flags(PacketFlag::RECURSION_AVAILABLE);
         } else {
@@ -278,6 +660,7 @@ impl DnsSocket {
         }
         let mut next_name_server: Option<SocketAddr> = None; // Name server to target. If none, falls back to default and DHT
         let mut next_raw_query: Vec<u8> = client_query_data.clone();
+        let mut referrals = 0; // Glued name servers followed so far
         for i in 0..self.max_recursion_depth {
             let current_query = ParsedQuery::new(next_raw_query.clone()).unwrap();
             tracing::trace!(
@@ -285,17 +668,52 @@ impl DnsSocket {
                 self.max_recursion_depth,
             );
             // println!("Recursive lookup {i}/{} NS:{next_name_server:?} - {:?}", self.max_recursion_depth, current_query.question());
-            let reply = self.query_me_once(&current_query, from.clone(), next_name_server).await;
+            let remaining = max_records
+                .map(|max| {
+                    max.checked_sub(count_records(&client_reply))
+                        .ok_or(RecordBudgetExceeded)
+                })
+                .transpose()?;
+            let reply = self
+                .query_me_once(&current_query, from, next_name_server, remaining)
+                .await?;
             next_name_server = None; // Reset target DNS
-            let parsed_reply = Packet::parse(&reply).expect("Reply must be a valid dns packet.");
+            let reply_packet = ParsedPacket::new(reply.clone()).expect("Reply must be a valid dns packet.");
+            let parsed_reply = reply_packet.parsed().clone();
+            // Replies of other name servers can't be cut off while they are collected.
+            if remaining.is_some_and(|remaining| count_records(&parsed_reply) > remaining) {
+                return Err(RecordBudgetExceeded);
+            }
 
             if !self.is_recursion_available() {
                 tracing::trace!("Recursion not available return.");
-                return reply;
+                return Ok(reply);
             }
             if !client_query.is_recursion_desired() {
                 tracing::trace!("Recursion not desired. return.");
-                return reply;
+                return Ok(reply);
+            }
+
+            let depth = referrals + count_cnames(&client_reply.answers) + count_cnames(&parsed_reply.answers);
+            if self.max_resolution_depth > 0 && depth > self.max_resolution_depth as usize {
+                tracing::debug!("Max resolution depth {depth} exceeded. {query}");
+                return Ok(client_query
+                    .packet
+                    .create_server_fail_reply_with_ede(ExtendedDnsError::Other, "Maximum resolution depth exceeded."));
+            }
+
+            // ICANN CNAME chain that ends in a public key domain. The forward server can't resolve the end,
+            // often replies NXDOMAIN, so continue with the end of the chain via pkarr.
+            if let Some((chain, target)) = self.cname_chain_to_public_key_domain(&parsed_reply, &current_query) {
+                tracing::trace!("Recursion: ICANN CNAME chain ends in the public key domain {target}.");
+                client_reply.answers.extend(chain);
+                let mut question = current_query.question().clone().into_owned();
+                question.qname = target;
+                let mut next_query = current_query.packet.parsed().clone();
+                next_query.questions = vec![question];
+                next_query.set_flags(PacketFlag::RECURSION_DESIRED);
+                next_raw_query = next_query.build_bytes_vec().unwrap();
+                continue;
             }
 
             if parsed_reply.rcode() != RCODE::NoError {
@@ -305,13 +723,15 @@ impl DnsSocket {
                     parsed_reply.rcode()
                 );
                 *client_reply.rcode_mut() = parsed_reply.rcode();
-                return client_reply.build_bytes_vec().unwrap();
+                copy_extended_errors(&mut client_reply, &parsed_reply);
+                return Ok(client_reply.build_bytes_vec().unwrap());
             }
 
             if parsed_reply.answers.len() == 0 && parsed_reply.name_servers.len() == 0 {
                 // No answers and NS received.
                 tracing::warn!("Empty reply {current_query}");
-                return client_reply.build_bytes_vec().unwrap();
+                copy_extended_errors(&mut client_reply, &parsed_reply);
+                return Ok(client_reply.build_bytes_vec().unwrap());
             }
 
             let matching_answers_names: Vec<&pkarr::dns::ResourceRecord<'_>> = parsed_reply
@@ -331,6 +751,7 @@ impl DnsSocket {
                 // We found answers matching the name and the type.
                 // Copy everything over and return.
                 tracing::trace!("Recursion final answer found.");
+                copy_extended_errors(&mut client_reply, &parsed_reply);
 
                 for answer in parsed_reply.answers {
                     client_reply.answers.push(answer.into_owned());
@@ -341,7 +762,7 @@ impl DnsSocket {
                 for ns in parsed_reply.name_servers {
                     client_reply.name_servers.push(ns.into_owned());
                 }
-                return client_reply.build_bytes_vec().unwrap();
+                return Ok(client_reply.build_bytes_vec().unwrap());
             }
 
             // No direct answer matches
@@ -355,6 +776,14 @@ impl DnsSocket {
                 // Matching CNAME
                 tracing::trace!("Recursion: Matching CNAME {rr:?}");
                 if let pkarr::dns::rdata::RData::CNAME(val) = &rr.rdata {
+                    // Keep the DNAME the CNAME got synthesized from.
+                    for dname in parsed_reply
+                        .answers
+                        .iter()
+                        .filter(|answer| u16::from(answer.rdata.type_code()) == DNAME_TYPE_CODE)
+                    {
+                        client_reply.answers.push(dname.clone().into_owned());
+                    }
                     // Clone CNAME answer to main reply.
                     client_reply.answers.push(rr.clone().into_owned());
                     // Replace question with the content of the cname
@@ -382,10 +811,11 @@ impl DnsSocket {
             if ns_matches.is_empty() {
                 // No NS matches either; Copy additional and return main reply.
                 tracing::trace!("No direct and no ns matches");
+                copy_extended_errors(&mut client_reply, &parsed_reply);
                 for additional in parsed_reply.additional_records {
                     client_reply.additional_records.push(additional.into_owned());
                 }
-                return client_reply.build_bytes_vec().unwrap();
+                return Ok(client_reply.build_bytes_vec().unwrap());
             }
 
             tracing::trace!("NS matches. {parsed_reply:?}");
@@ -420,102 +850,198 @@ impl DnsSocket {
             if let Some(socket) = &found_name_server {
                 tracing::trace!("Found glued nameserver {socket}");
                 next_name_server = found_name_server;
+                referrals += 1;
                 continue;
             };
 
             // Unhandled NS response. Probably SOA. Return
+            copy_extended_errors(&mut client_reply, &parsed_reply);
             for ns in parsed_reply.name_servers.iter() {
                 client_reply.name_servers.push(ns.clone().into_owned());
             }
-            return client_reply.build_bytes_vec().unwrap();
+            return Ok(client_reply.build_bytes_vec().unwrap());
         }
 
         // Max recursion exceeded
         tracing::debug!("Max recursion exceeded. {query}");
-        client_query.packet.create_server_fail_reply()
+        Ok(client_query
+            .packet
+            .create_server_fail_reply_with_ede(ExtendedDnsError::Other, "Maximum recursion depth exceeded."))
     }
 
     /// Query this DNS for data once without recursion.
     /// from: Client ip used for rate limiting. None disables rate limiting
     /// target_dns: dns server to query. None falls back to the default fallback DNS
+    /// max_records: Records the reply may have at most. None = unlimited
     async fn query_me_once(
         &mut self,
         query: &ParsedQuery,
         from: Option<IpAddr>,
         target_dns: Option<SocketAddr>,
-    ) -> Vec<u8> {
+        max_records: Option<usize>,
+    ) -> Result<Vec<u8>, RecordBudgetExceeded> {
         // Only try the DHT first if no target_dns is manually specified.
         if let None = &target_dns {
             tracing::trace!("Trying to resolve the query with the custom handler.");
-            let result = self.pkarr_resolver.resolve(&query, from).await;
-            if result.is_ok() {
-                tracing::trace!("Custom handler resolved the query.");
-                // All good. Handler handled the query
-                return result.unwrap();
-            }
+            let result = self.pkarr_resolver.resolve(query, from, max_records).await;
+            let err = match result {
+                Ok(reply) => {
+                    tracing::trace!("Custom handler resolved the query.");
+                    // All good. Handler handled the query
+                    return Ok(reply);
+                }
+                Err(e) => e,
+            };
 
-            match result.unwrap_err() {
+            match err {
                 CustomHandlerError::Unhandled => {
                     tracing::trace!("Custom handler rejected the query. {query}");
                 }
                 CustomHandlerError::Failed(err) => {
                     tracing::error!("Internal error {query}: {}", err);
-                    return query.packet.create_server_fail_reply();
+                    return Ok(query.packet.create_server_fail_reply());
                 }
                 CustomHandlerError::RateLimited(ip) => {
                     tracing::error!("IP is rate limited {query}: {}", ip);
-                    return query.packet.create_refused_reply();
+                    return Ok(query.packet.create_refused_reply());
+                }
+                CustomHandlerError::ReservedTld(tld) => {
+                    if self.reserved_tld_policy == ReservedTldPolicy::Refuse {
+                        tracing::debug!("Refused query for the reserved tld .{tld}. {query}");
+                        return Ok(query.packet.create_refused_reply());
+                    }
+                    tracing::trace!("Forward query for the reserved tld .{tld}. {query}");
+                }
+                CustomHandlerError::RecordBudgetExceeded => return Err(RecordBudgetExceeded),
+                CustomHandlerError::UnsupportedKey(key) => {
+                    if self.unsupported_key_policy == UnsupportedKeyPolicy::NotImp {
+                        tracing::debug!("Unsupported key type of {key}. {query}");
+                        return Ok(query.packet.create_not_implemented_reply_with_ede(
+                            ExtendedDnsError::Other,
+                            "Public key type is not supported.",
+                        ));
+                    }
+                    tracing::trace!("Forward query for the unsupported key {key}. {query}");
                 }
             };
         }
 
         // Forward to ICANN
-        let dns_socket = target_dns.unwrap_or(self.icann_fallback.clone());
+        let dns_socket = target_dns.unwrap_or(self.icann_fallback.get());
         match self
             .forward_to_icann(&query.packet.clone().into(), dns_socket, Duration::from_secs(5))
             .await
         {
-            Ok(reply) => reply,
+            Ok(reply) => Ok(reply),
             Err(e) => {
                 tracing::warn!("Forwarding dns query failed. {e} {query}");
-                query.packet.create_server_fail_reply()
+                Ok(query.packet.create_server_fail_reply())
             }
         }
     }
 
+    /// CNAME chain in an ICANN reply that starts at the question and ends in a public key domain
+    /// the reply doesn't answer. Returns the CNAMEs of the chain and the public key domain.
+    fn cname_chain_to_public_key_domain(
+        &self,
+        reply: &Packet<'_>,
+        query: &ParsedQuery,
+    ) -> Option<(Vec<pkarr::dns::ResourceRecord<'static>>, Name<'static>)> {
+        let question = query.question();
+        if self.pkarr_resolver.is_public_key_domain(&question.qname) {
+            return None; // Pkarr reply. CNAMEs are handled by the regular recursion.
+        }
+        let mut chain = vec![];
+        let mut name = question.qname.clone();
+        while let Some(cname) = reply
+            .answers
+            .iter()
+            .find(|answer| answer.name == name && answer.match_qtype(QTYPE::TYPE(pkarr::dns::TYPE::CNAME)))
+        {
+            let RData::CNAME(CNAME(target)) = &cname.rdata else {
+                break;
+            };
+            chain.push(cname.clone().into_owned());
+            name = target.clone();
+            if chain.len() > reply.answers.len() {
+                return None; // CNAME loop
+            }
+        }
+        let is_answered = reply
+            .answers
+            .iter()
+            .any(|answer| answer.name == name && answer.match_qtype(question.qtype));
+        if chain.is_empty() || is_answered || !self.pkarr_resolver.is_public_key_domain(&name) {
+            return None;
+        }
+        Some((chain, name.into_owned()))
+    }
+
     /// Send dns request to configured forward server
     pub async fn forward(
         &mut self,
-        query: &Vec<u8>,
+        query: &[u8],
         to: &SocketAddr,
         timeout: Duration,
     ) -> Result<Vec<u8>, DnsSocketError> {
-        let packet = Packet::parse(&query)?;
-        let (tx, rx) = oneshot::channel::<Vec<u8>>();
-        let forward_id = self.id_manager.get_next(to);
+        let packet = ParsedPacket::new(query.to_vec())?;
         let original_id = packet.id();
-        tracing::trace!("Fallback to forward server {to:?}. orignal_id={original_id} forward_id={forward_id}");
-        let request = PendingRequest {
-            original_query_id: original_id,
-            forward_query_id: forward_id,
-            sent_at: Instant::now(),
-            to: to.clone(),
-            tx,
+        let query = packet.parsed().build_bytes_vec_compressed()?;
+
+        let reply = if self.randomize_forward_port {
+            // Each query has its own socket so the id doesn't need to be unique.
+            let forward_id = rand::thread_rng().gen::<u16>();
+            tracing::trace!("Fallback to forward server {to:?}. orignal_id={original_id} forward_id={forward_id}");
+            let query = replace_packet_id(&query, forward_id)?;
+            tokio::time::timeout(timeout, Self::forward_from_random_port(&query, to, forward_id)).await??
+        } else {
+            let (tx, rx) = oneshot::channel::<Vec<u8>>();
+            let forward_id = self.id_manager.get_next(to);
+            tracing::trace!("Fallback to forward server {to:?}. orignal_id={original_id} forward_id={forward_id}");
+            let request = PendingRequest {
+                original_query_id: original_id,
+                forward_query_id: forward_id,
+                sent_at: Instant::now(),
+                to: to.clone(),
+                tx,
+            };
+            let query = replace_packet_id(&query, forward_id)?;
+            self.pending.insert(request);
+            self.send_to(&query, to).await?;
+            // Wait on response
+            tokio::time::timeout(timeout, rx).await??
         };
-
-        let query = packet.build_bytes_vec_compressed()?;
-        let query = replace_packet_id(&query, forward_id)?;
-
-        self.pending.insert(request);
-        self.send_to(&query, to).await?;
-
-        // Wait on response
-        let reply = tokio::time::timeout(timeout, rx).await??;
         let reply = replace_packet_id(&reply, original_id)?;
 
         Ok(reply)
     }
 
+    /// Sends the query from a new socket with a random source port and waits for the reply.
+    async fn forward_from_random_port(
+        query: &[u8],
+        to: &SocketAddr,
+        forward_id: u16,
+    ) -> Result<Vec<u8>, DnsSocketError> {
+        let bind_addr: SocketAddr = if to.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+        let socket = UdpSocket::bind(bind_addr).await?;
+        // Connected sockets only receive datagrams from the forward server.
+        socket.connect(to).await?;
+        tracing::debug!(
+            "Forward query forward_id={forward_id} to {to} from source port {}.",
+            socket.local_addr()?.port()
+        );
+        socket.send(query).await?;
+
+        let mut buffer = [0; 4096];
+        loop {
+            let size = socket.recv(&mut buffer).await?;
+            if size >= 2 && u16::from_be_bytes([buffer[0], buffer[1]]) == forward_id {
+                return Ok(buffer[..size].to_vec());
+            }
+            tracing::debug!("Received reply with an unexpected id from {to}. Ignore.");
+        }
+    }
+
     /// Forward query to icann
     pub async fn forward_to_icann(
         &mut self,
@@ -526,7 +1052,7 @@ impl DnsSocket {
         // Check cache first before forwarding
         if let Ok(opt_item) = self.icann_cache.get(query).await {
             if let Some(item) = opt_item {
-                let query_packet = Packet::parse(query)?;
+                let query_packet = ParsedPacket::new(query.clone())?;
                 let new_response = replace_packet_id(&item.response, query_packet.id())?;
                 return Ok(new_response);
             };
@@ -543,7 +1069,7 @@ impl DnsSocket {
 
     // Extracts the id of the query
     fn extract_query_id(&self, query: &Vec<u8>) -> Result<u16, SimpleDnsError> {
-        Packet::parse(query).map(|packet| packet.id())
+        ParsedPacket::new(query.clone()).map(|packet| packet.id())
     }
 
     /// Create a REFUSED reply
@@ -567,34 +1093,58 @@ impl DnsSocket {
             socket: Arc::new(socket),
             pending: PendingRequestStore::new(),
             pkarr_resolver: PkarrResolver::default().await,
-            icann_fallback: "8.8.8.8:53".parse().unwrap(),
+            icann_fallback: ForwardServer::new("8.8.8.8:53".parse().unwrap()),
             id_manager: QueryIdManager::new(),
             rate_limiter: Arc::new(RateLimiterBuilder::new().build()),
             disable_any_queries: config.dns.disable_any_queries,
             icann_cache: IcannLruCache::new(100, config.dns.min_ttl, config.dns.max_ttl),
             max_recursion_depth: 5,
+            max_resolution_depth: config.dns.max_resolution_depth,
+            max_records_per_query: config.dns.max_records_per_query,
+            require_edns: config.dns.require_edns,
+            minimal_responses: config.dns.minimal_responses,
+            query_timeout: Duration::from_millis(config.dns.query_timeout_ms),
+            resolve_all_questions: config.dns.resolve_all_questions,
+            randomize_forward_port: config.general.randomize_source_ports,
+            reserved_tld_policy: config.dns.reserved_tld_policy,
+            unsupported_key_policy: config.dns.unsupported_key_policy,
+            trusted: config.general.socket_trusted,
+            standby: Arc::new(AtomicBool::new(config.general.standby)),
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::resolution::dns_packets::ParsedQuery;
-    use crate::resolution::pkd::{PkarrResolver, TopLevelDomain};
-    use pkarr::dns::rdata::{RData, NS};
+    use crate::metrics::METRICS;
+    use crate::resolution::dns_packets::{
+        get_extended_error, ExtendedDnsError, ParsedPacket, ParsedQuery, FRESH_LOOKUP_OPTION_CODE,
+    };
+    use crate::resolution::pkd::{
+        DnameParent, MockDht, PkarrResolver, ResolverSettings, TopLevelDomain, DNAME_TYPE_CODE,
+    };
+    use pkarr::dns::rdata::{OPTCode, RData, NS, NULL};
     use pkarr::dns::{
         rdata::{A, CNAME},
-        Name, Packet, PacketFlag, Question, ResourceRecord, RCODE,
+        Name, Packet, PacketFlag, Question, ResourceRecord, QTYPE, RCODE, TYPE,
     };
     use pkarr::{Keypair, PkarrClient, SignedPacket};
     use std::{
+        collections::HashSet,
         net::{Ipv4Addr, SocketAddr},
         num::NonZeroU64,
-        time::Duration,
+        time::{Duration, Instant},
     };
+    use tokio::net::UdpSocket;
     use tracing_test::traced_test;
 
-    use super::DnsSocket;
+    use super::{count_cnames, DnsSocket};
+    use crate::resolution::rate_limiter::RateLimiterBuilder;
+    use crate::resolution::test_helpers::{
+        a_packet, a_query, apex_a_packet, offline_socket, offline_socket_with_settings, set_opt,
+    };
+    use crate::resolution::ForwardServer;
+    use std::sync::Arc;
 
     async fn publish_domain() {
         // Public key csjbhp9jpbomwh3m5eyrj1py41m8sjpkzzqmzpj5madsi7sc4mto
@@ -691,11 +1241,661 @@ mod tests {
         let mut socket = DnsSocket::default_random_socket().await.unwrap();
         let join_handle = socket.start_receive_loop();
         let parsed_query = ParsedQuery::new(query).unwrap();
-        let result = socket.query_me_recursively(&parsed_query, None).await;
+        let result = socket.query_me_recursively(&parsed_query, None, None).await.unwrap();
         join_handle.send(());
         result
     }
 
+    #[tokio::test]
+    async fn unknown_qtype_answered_from_wire() {
+        let keypair = Keypair::random();
+        let mut packet = Packet::new_reply(0);
+        packet.answers.push(ResourceRecord::new(
+            Name::new(".").unwrap(),
+            pkarr::dns::CLASS::IN,
+            300,
+            RData::A(Ipv4Addr::new(127, 0, 0, 1).into()),
+        ));
+        packet.answers.push(ResourceRecord::new(
+            Name::new(".").unwrap(),
+            pkarr::dns::CLASS::IN,
+            300,
+            RData::NULL(65283, NULL::new(&[1, 2, 3, 4]).unwrap()),
+        ));
+        let dht = MockDht::new();
+        dht.add_packet(SignedPacket::from_packet(&keypair, &packet).unwrap());
+        let mut socket = offline_socket(dht).await;
+        let domain = keypair.to_z32();
+        let mut query = a_query(&domain);
+        query.questions[0].qtype = QTYPE::TYPE(TYPE::Unknown(65283));
+        let query = query.build_bytes_vec().unwrap();
+
+        let reply = socket.query_me_recursively_raw(query, None).await;
+        let reply = ParsedPacket::new(reply).unwrap();
+        let reply = reply.parsed();
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert_eq!(reply.questions[0].qtype, QTYPE::TYPE(TYPE::Unknown(65283)));
+        // Only the record of the queried type, the unknown qtype is not treated like ANY.
+        assert_eq!(reply.answers.len(), 1);
+        assert!(matches!(&reply.answers[0].rdata, RData::NULL(65283, data) if data.get_data() == [1, 2, 3, 4]));
+    }
+
+    #[tokio::test]
+    async fn no_edns_best_effort_by_default() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut socket = offline_socket(dht).await;
+
+        let pubkey = keypair.to_z32();
+        let query = a_query(&pubkey).build_bytes_vec().unwrap();
+        let reply = socket.query_me_recursively_raw(query, None).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert_eq!(reply.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn no_edns_refused_when_edns_required() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut socket = offline_socket(dht).await;
+        socket.require_edns = true;
+
+        let pubkey = keypair.to_z32();
+        let query = a_query(&pubkey).build_bytes_vec().unwrap();
+        let reply = socket.query_me_recursively_raw(query, None).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::Refused);
+        assert_eq!(reply.answers.len(), 0);
+
+        let mut query = a_query(&pubkey);
+        set_opt(&mut query, vec![]);
+        let reply = socket
+            .query_me_recursively_raw(query.build_bytes_vec().unwrap(), None)
+            .await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert_eq!(reply.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unknown_edns_option_ignored() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut socket = offline_socket(dht).await;
+
+        let pubkey = keypair.to_z32();
+        let mut query = a_query(&pubkey);
+        set_opt(
+            &mut query,
+            vec![OPTCode {
+                code: 65001,
+                data: vec![1, 2, 3].into(),
+            }],
+        );
+        let options_before = METRICS.unsupported_edns_options.get();
+        let reply = socket
+            .query_me_recursively_raw(query.build_bytes_vec().unwrap(), None)
+            .await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert_eq!(reply.answers.len(), 1);
+        assert!(METRICS.unsupported_edns_options.get() > options_before);
+    }
+
+    #[tokio::test]
+    async fn pkarr_answer_ad_bit_clear() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut socket = offline_socket(dht).await;
+
+        let pubkey = keypair.to_z32();
+        for recursion_desired in [true, false] {
+            let mut query = a_query(&pubkey);
+            // Clients set AD in queries to signal they understand the bit (RFC 6840).
+            query.set_flags(PacketFlag::AUTHENTIC_DATA);
+            if !recursion_desired {
+                query.remove_flags(PacketFlag::RECURSION_DESIRED);
+            }
+            let reply = socket
+                .query_me_recursively_raw(query.build_bytes_vec().unwrap(), None)
+                .await;
+            let reply = Packet::parse(&reply).unwrap();
+            assert_eq!(reply.answers.len(), 1);
+            assert!(!reply.has_flags(PacketFlag::AUTHENTIC_DATA));
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_query_times_out() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new().with_delay(Duration::from_secs(30));
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut socket = offline_socket(dht).await;
+        socket.query_timeout = Duration::from_millis(100);
+
+        let pubkey = keypair.to_z32();
+        let mut query = a_query(&pubkey);
+        set_opt(&mut query, vec![]);
+        let timeouts_before = METRICS.query_timeouts.get();
+        let start = Instant::now();
+        let reply = socket
+            .query_me_recursively_raw(query.build_bytes_vec().unwrap(), None)
+            .await;
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::ServerFailure);
+        assert_eq!(
+            get_extended_error(&reply),
+            Some((ExtendedDnsError::Other as u16, "Query timed out.".to_string()))
+        );
+        assert!(METRICS.query_timeouts.get() - timeouts_before >= 1);
+    }
+
+    #[tokio::test]
+    async fn unsupported_key_not_implemented() {
+        // Canonical zbase32 of 32 bytes that are not a point on the ed25519 curve.
+        let key = (0..=u8::MAX)
+            .map(|byte| zbase32::encode_full_bytes(&[byte; 32]))
+            .find(|key| pkarr::PublicKey::try_from(key.as_str()).is_err())
+            .unwrap();
+        let mut socket = offline_socket(MockDht::new()).await;
+
+        for domain in [key.clone(), format!("{key}.key")] {
+            let mut query = a_query(&domain);
+            set_opt(&mut query, vec![]);
+            let reply = socket
+                .query_me_recursively_raw(query.build_bytes_vec().unwrap(), None)
+                .await;
+            let reply = Packet::parse(&reply).unwrap();
+            assert_eq!(reply.rcode(), RCODE::NotImplemented);
+            assert_eq!(
+                get_extended_error(&reply),
+                Some((
+                    ExtendedDnsError::Other as u16,
+                    "Public key type is not supported.".to_string()
+                ))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fresh_lookup_marked_with_ede() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut settings = ResolverSettings::default();
+        settings.allow_fresh_lookups = true;
+        let mut socket = offline_socket_with_settings(dht.clone(), settings).await;
+
+        let domain = keypair.to_z32();
+        let query = |fresh: bool| {
+            let mut query = a_query(&domain);
+            let opt_codes = if fresh {
+                vec![OPTCode {
+                    code: FRESH_LOOKUP_OPTION_CODE,
+                    data: vec![].into(),
+                }]
+            } else {
+                vec![]
+            };
+            set_opt(&mut query, opt_codes);
+            query.build_bytes_vec().unwrap()
+        };
+        let reply = socket.query_me_recursively_raw(query(false), None).await;
+        assert_eq!(get_extended_error(&Packet::parse(&reply).unwrap()), None);
+
+        // A newer packet on the DHT is only seen by the fresh lookup.
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        dht.add_packet(a_packet(&keypair, Ipv4Addr::new(127, 0, 0, 2)));
+        let lookups_before = dht.lookup_count();
+
+        let reply = socket.query_me_recursively_raw(query(false), None).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert!(
+            matches!(reply.answers[0].rdata, RData::A(A { address }) if address == Ipv4Addr::new(127, 0, 0, 1).to_bits())
+        );
+        assert_eq!(dht.lookup_count(), lookups_before);
+
+        let reply = socket.query_me_recursively_raw(query(true), None).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert!(
+            matches!(reply.answers[0].rdata, RData::A(A { address }) if address == Ipv4Addr::new(127, 0, 0, 2).to_bits())
+        );
+        assert_eq!(dht.lookup_count(), lookups_before + 1);
+        assert_eq!(
+            get_extended_error(&reply),
+            Some((ExtendedDnsError::Other as u16, "Forced fresh lookup.".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn forward_hostname_resolved_and_used() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let hostname = format!("localhost:{}", upstream.local_addr().unwrap().port());
+        let addr = ForwardServer::resolve_hostname(&hostname).await.unwrap();
+        assert_eq!(addr, upstream.local_addr().unwrap());
+
+        let mut socket = offline_socket(MockDht::new()).await;
+        socket.icann_fallback.set(addr);
+        tokio::spawn(async move {
+            let mut buffer = [0; 1024];
+            let (size, from) = upstream.recv_from(&mut buffer).await.unwrap();
+            let mut reply = Packet::parse(&buffer[..size]).unwrap().into_reply();
+            reply.answers.push(ResourceRecord::new(
+                Name::new("example.com").unwrap(),
+                pkarr::dns::CLASS::IN,
+                300,
+                RData::A(Ipv4Addr::new(93, 184, 216, 34).into()),
+            ));
+            upstream.send_to(&reply.build_bytes_vec().unwrap(), from).await.unwrap();
+        });
+
+        let query = a_query("example.com").build_bytes_vec().unwrap();
+        let reply = socket.query_me_recursively_raw(query, None).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert_eq!(reply.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn icann_cname_to_public_key_domain() {
+        let keypair = Keypair::random();
+        let mut packet = Packet::new_reply(0);
+        packet.answers.push(ResourceRecord::new(
+            Name::new("name").unwrap(),
+            pkarr::dns::CLASS::IN,
+            300,
+            RData::A(A {
+                address: Ipv4Addr::new(127, 0, 0, 2).to_bits(),
+            }),
+        ));
+        let dht = MockDht::new();
+        dht.add_packet(SignedPacket::from_packet(&keypair, &packet).unwrap());
+        let mut socket = offline_socket(dht).await;
+
+        // Upstream that knows the CNAME but not its pkarr target.
+        let target = format!("name.{}", keypair.to_z32());
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.icann_fallback.set(upstream.local_addr().unwrap());
+        let cname_target = target.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0; 1024];
+            let (size, from) = upstream.recv_from(&mut buffer).await.unwrap();
+            let mut reply = Packet::parse(&buffer[..size]).unwrap().into_reply();
+            reply.answers.push(ResourceRecord::new(
+                Name::new("something.com").unwrap(),
+                pkarr::dns::CLASS::IN,
+                300,
+                RData::CNAME(CNAME(Name::new(&cname_target).unwrap())),
+            ));
+            *reply.rcode_mut() = RCODE::NameError;
+            upstream.send_to(&reply.build_bytes_vec().unwrap(), from).await.unwrap();
+        });
+
+        let query = a_query("something.com").build_bytes_vec().unwrap();
+        let reply = socket.query_me_recursively_raw(query, None).await;
+        let reply = Packet::parse(&reply).unwrap();
+
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert_eq!(reply.answers.len(), 2);
+        assert_eq!(reply.answers[0].rdata, RData::CNAME(CNAME(Name::new(&target).unwrap())));
+        assert_eq!(reply.answers[1].name.to_string(), target);
+        assert_eq!(
+            reply.answers[1].rdata,
+            RData::A(A {
+                address: Ipv4Addr::new(127, 0, 0, 2).to_bits()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn combined_depth_exceeded() {
+        // pkarr: name -> other -> A
+        let keypair = Keypair::random();
+        let pubkey = keypair.to_z32();
+        let other = format!("other.{pubkey}");
+        let mut packet = Packet::new_reply(0);
+        packet.answers.push(ResourceRecord::new(
+            Name::new("name").unwrap(),
+            pkarr::dns::CLASS::IN,
+            300,
+            RData::CNAME(CNAME(Name::new(&other).unwrap())),
+        ));
+        packet.answers.push(ResourceRecord::new(
+            Name::new("other").unwrap(),
+            pkarr::dns::CLASS::IN,
+            300,
+            RData::A(A {
+                address: Ipv4Addr::new(127, 0, 0, 2).to_bits(),
+            }),
+        ));
+        let dht = MockDht::new();
+        dht.add_packet(SignedPacket::from_packet(&keypair, &packet).unwrap());
+        let mut socket = offline_socket(dht).await;
+
+        // ICANN: first.com -> second.com -> name.pubkey
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.icann_fallback.set(upstream.local_addr().unwrap());
+        let target = format!("name.{pubkey}");
+        tokio::spawn(async move {
+            let mut buffer = [0; 1024];
+            loop {
+                let (size, from) = upstream.recv_from(&mut buffer).await.unwrap();
+                let mut reply = Packet::parse(&buffer[..size]).unwrap().into_reply();
+                reply.answers.push(ResourceRecord::new(
+                    Name::new("first.com").unwrap(),
+                    pkarr::dns::CLASS::IN,
+                    300,
+                    RData::CNAME(CNAME(Name::new("second.com").unwrap())),
+                ));
+                reply.answers.push(ResourceRecord::new(
+                    Name::new("second.com").unwrap(),
+                    pkarr::dns::CLASS::IN,
+                    300,
+                    RData::CNAME(CNAME(Name::new(&target).unwrap())),
+                ));
+                *reply.rcode_mut() = RCODE::NameError;
+                upstream.send_to(&reply.build_bytes_vec().unwrap(), from).await.unwrap();
+            }
+        });
+
+        let mut query = a_query("first.com");
+        set_opt(&mut query, vec![]);
+        let query = query.build_bytes_vec().unwrap();
+
+        // Three CNAMEs in total. Neither source alone exceeds 2.
+        socket.max_resolution_depth = 3;
+        let reply = socket.query_me_recursively_raw(query.clone(), None).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert_eq!(count_cnames(&reply.answers), 3);
+
+        socket.max_resolution_depth = 2;
+        let reply = socket.query_me_recursively_raw(query, None).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::ServerFailure);
+        assert_eq!(
+            get_extended_error(&reply),
+            Some((
+                ExtendedDnsError::Other as u16,
+                "Maximum resolution depth exceeded.".to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn query_record_budget_exceeded() {
+        // Pathological packet with far more answers than the budget allows.
+        let keypair = Keypair::random();
+        let mut packet = Packet::new_reply(0);
+        for i in 0..20 {
+            packet.answers.push(ResourceRecord::new(
+                Name::new("big").unwrap(),
+                pkarr::dns::CLASS::IN,
+                300,
+                RData::A(A {
+                    address: Ipv4Addr::new(127, 0, 0, i).to_bits(),
+                }),
+            ));
+        }
+        let dht = MockDht::new();
+        dht.add_packet(SignedPacket::from_packet(&keypair, &packet).unwrap());
+        let mut socket = offline_socket(dht).await;
+        let domain = format!("big.{}", keypair.to_z32());
+        let mut query = a_query(&domain);
+        set_opt(&mut query, vec![]);
+        let query = query.build_bytes_vec().unwrap();
+
+        let reply = socket.query_me_recursively_raw(query.clone(), None).await;
+        assert_eq!(Packet::parse(&reply).unwrap().answers.len(), 20);
+
+        socket.max_records_per_query = 10;
+        let exceeded_before = METRICS.query_budget_exceeded.get();
+        let reply = socket.query_me_recursively_raw(query, None).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::ServerFailure);
+        assert!(reply.answers.is_empty());
+        assert_eq!(
+            get_extended_error(&reply),
+            Some((
+                ExtendedDnsError::Other as u16,
+                "Query record budget exceeded.".to_string()
+            ))
+        );
+        assert!(METRICS.query_budget_exceeded.get() > exceeded_before);
+    }
+
+    #[tokio::test]
+    async fn recursion_depth_exceeded_ede() {
+        // pkarr: loop -> loop, resolved again on every recursion step.
+        let keypair = Keypair::random();
+        let domain = format!("loop.{}", keypair.to_z32());
+        let mut packet = Packet::new_reply(0);
+        packet.answers.push(ResourceRecord::new(
+            Name::new("loop").unwrap(),
+            pkarr::dns::CLASS::IN,
+            300,
+            RData::CNAME(CNAME(Name::new(&domain).unwrap())),
+        ));
+        let dht = MockDht::new();
+        dht.add_packet(SignedPacket::from_packet(&keypair, &packet).unwrap());
+        let mut socket = offline_socket(dht).await;
+        socket.max_recursion_depth = 3;
+        socket.max_resolution_depth = 100;
+
+        let mut query = a_query(&domain);
+        set_opt(&mut query, vec![]);
+        let reply = socket
+            .query_me_recursively_raw(query.build_bytes_vec().unwrap(), None)
+            .await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::ServerFailure);
+        assert_eq!(
+            get_extended_error(&reply),
+            Some((
+                ExtendedDnsError::Other as u16,
+                "Maximum recursion depth exceeded.".to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn forward_source_ports_randomized() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let queries = 5;
+        let server_task = tokio::spawn(async move {
+            let mut ports = HashSet::new();
+            let mut buffer = [0; 1024];
+            for _ in 0..queries {
+                let (size, from) = server.recv_from(&mut buffer).await.unwrap();
+                ports.insert(from.port());
+                let mut reply = Packet::parse(&buffer[..size]).unwrap().into_reply();
+                *reply.rcode_mut() = RCODE::NameError;
+                server.send_to(&reply.build_bytes_vec().unwrap(), from).await.unwrap();
+            }
+            ports
+        });
+
+        let mut socket = offline_socket(MockDht::new()).await;
+        let query = a_query("example.com").build_bytes_vec().unwrap();
+        for _ in 0..queries {
+            let reply = socket
+                .forward(&query, &server_addr, Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert_eq!(Packet::parse(&reply).unwrap().rcode(), RCODE::NameError);
+        }
+
+        let ports = server_task.await.unwrap();
+        assert!(ports.len() > 1, "All forwards used the same source port.");
+        assert!(!ports.contains(&socket.socket.local_addr().unwrap().port()));
+        // Random port forwards don't take ids from the shared socket.
+        assert_eq!(socket.id_manager.get_next(&server_addr), 1);
+    }
+
+    #[tokio::test]
+    async fn trusted_listener_not_rate_limited() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut public = offline_socket(dht).await;
+        public.rate_limiter = Arc::new(RateLimiterBuilder::new().max_per_second(1).burst_size(1).build());
+        let mut trusted = public.clone();
+        trusted.socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        trusted.trusted = true;
+        let _public_loop = public.start_receive_loop();
+        let _trusted_loop = trusted.start_receive_loop();
+
+        let query = a_query(&keypair.to_z32()).build_bytes_vec().unwrap();
+        async fn rcodes(listener: SocketAddr, query: &[u8]) -> Vec<RCODE> {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut buffer = [0; 1024];
+            let mut rcodes = vec![];
+            for _ in 0..3 {
+                client.send_to(query, listener).await.unwrap();
+                let (size, _) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buffer))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                rcodes.push(Packet::parse(&buffer[..size]).unwrap().rcode());
+            }
+            rcodes
+        }
+
+        let public_rcodes = rcodes(public.socket.local_addr().unwrap(), &query).await;
+        assert_eq!(public_rcodes, vec![RCODE::NoError, RCODE::Refused, RCODE::Refused]);
+        let trusted_rcodes = rcodes(trusted.socket.local_addr().unwrap(), &query).await;
+        assert_eq!(trusted_rcodes, vec![RCODE::NoError; 3]);
+    }
+
+    #[tokio::test]
+    async fn resolve_all_questions() {
+        let first = Keypair::random();
+        let second = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&first));
+        dht.add_packet(apex_a_packet(&second));
+        let mut socket = offline_socket(dht).await;
+
+        let first_pubkey = first.to_z32();
+        let second_pubkey = second.to_z32();
+        let mut query = a_query(&first_pubkey);
+        query.questions.push(Question::new(
+            Name::new(&second_pubkey).unwrap(),
+            pkarr::dns::QTYPE::TYPE(pkarr::dns::TYPE::A),
+            pkarr::dns::QCLASS::CLASS(pkarr::dns::CLASS::IN),
+            false,
+        ));
+        let query = query.build_bytes_vec().unwrap();
+
+        // Default: only the first question is answered.
+        let reply = socket.query_me_recursively_raw(query.clone(), None).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].name.to_string(), first_pubkey);
+
+        socket.resolve_all_questions = true;
+        let reply = socket.query_me_recursively_raw(query, None).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert_eq!(reply.questions.len(), 2);
+        let names: Vec<String> = reply.answers.iter().map(|answer| answer.name.to_string()).collect();
+        assert_eq!(names, vec![first_pubkey, second_pubkey]);
+    }
+
+    #[tokio::test]
+    async fn record_budget_shared_by_all_questions() {
+        let dht = MockDht::new();
+        let mut pubkeys = vec![];
+        for _ in 0..2 {
+            let keypair = Keypair::random();
+            let mut packet = Packet::new_reply(0);
+            for i in 0..6 {
+                packet.answers.push(ResourceRecord::new(
+                    Name::new(".").unwrap(),
+                    pkarr::dns::CLASS::IN,
+                    300,
+                    RData::A(A {
+                        address: Ipv4Addr::new(127, 0, 0, i).to_bits(),
+                    }),
+                ));
+            }
+            dht.add_packet(SignedPacket::from_packet(&keypair, &packet).unwrap());
+            pubkeys.push(keypair.to_z32());
+        }
+        let mut socket = offline_socket(dht).await;
+        socket.resolve_all_questions = true;
+        socket.max_records_per_query = 10;
+        let mut query = a_query(&pubkeys[0]);
+        let single = query.build_bytes_vec().unwrap();
+        query.questions.push(Question::new(
+            Name::new(&pubkeys[1]).unwrap(),
+            pkarr::dns::QTYPE::TYPE(pkarr::dns::TYPE::A),
+            pkarr::dns::QCLASS::CLASS(pkarr::dns::CLASS::IN),
+            false,
+        ));
+        set_opt(&mut query, vec![]);
+        let both = query.build_bytes_vec().unwrap();
+
+        // Each question fits into the budget on its own.
+        let reply = socket.query_me_recursively_raw(single, None).await;
+        assert_eq!(Packet::parse(&reply).unwrap().answers.len(), 6);
+
+        let reply = socket.query_me_recursively_raw(both, None).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::ServerFailure);
+        assert_eq!(
+            get_extended_error(&reply),
+            Some((
+                ExtendedDnsError::Other as u16,
+                "Query record budget exceeded.".to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn dname_parent() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut settings = ResolverSettings::default();
+        settings.dname_parents = vec![DnameParent::new("pk.example.com").unwrap()];
+        let mut socket = offline_socket_with_settings(dht, settings).await;
+
+        let pubkey = keypair.to_z32();
+        let qname = format!("{pubkey}.pk.example.com");
+        let query = a_query(&qname).build_bytes_vec().unwrap();
+        let reply = socket.query_me_recursively_raw(query, None).await;
+        let reply = Packet::parse(&reply).unwrap();
+
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert_eq!(reply.answers.len(), 3);
+        let dname = &reply.answers[0];
+        assert_eq!(dname.name.to_string(), "pk.example.com");
+        assert_eq!(u16::from(dname.rdata.type_code()), DNAME_TYPE_CODE);
+        if let RData::NULL(_, target) = &dname.rdata {
+            assert_eq!(target.get_data(), &[3, b'k', b'e', b'y', 0]);
+        } else {
+            panic!("DNAME should be raw rdata.");
+        }
+        let cname = &reply.answers[1];
+        assert_eq!(cname.name.to_string(), qname);
+        assert_eq!(
+            cname.rdata,
+            RData::CNAME(CNAME(Name::new(&format!("{pubkey}.key")).unwrap()))
+        );
+        let a = &reply.answers[2];
+        assert_eq!(a.name.to_string(), format!("{pubkey}.key"));
+        assert!(a.match_qtype(pkarr::dns::QTYPE::TYPE(pkarr::dns::TYPE::A)));
+    }
+
     #[tokio::test]
     async fn recursion_cname_icann() {
         publish_domain().await;
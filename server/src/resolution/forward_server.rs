@@ -0,0 +1,57 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::net::lookup_host;
+
+/**
+ * ICANN forward server. Shared by all clones so a forward server given by hostname
+ * can be re-resolved while the DNS socket runs.
+ */
+#[derive(Clone, Debug)]
+pub struct ForwardServer(Arc<RwLock<SocketAddr>>);
+
+impl ForwardServer {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self(Arc::new(RwLock::new(addr)))
+    }
+
+    pub fn get(&self) -> SocketAddr {
+        *self.0.read().unwrap()
+    }
+
+    pub fn set(&self, addr: SocketAddr) {
+        *self.0.write().unwrap() = addr;
+    }
+
+    /// Resolves a host:port like `dns.example.com:53` with the OS resolver. Prefers IPv4 addresses.
+    pub async fn resolve_hostname(hostname: &str) -> Result<SocketAddr, std::io::Error> {
+        let addrs: Vec<SocketAddr> = lookup_host(hostname).await?.collect();
+        addrs
+            .iter()
+            .find(|addr| addr.is_ipv4())
+            .or(addrs.first())
+            .copied()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("{hostname} has no address.")))
+    }
+
+    /// Resolves the hostname every `interval` and switches to the new address if it changed.
+    /// Keeps the current address if the resolution fails.
+    pub fn start_re_resolution(&self, hostname: String, interval: Duration) {
+        let server = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                match Self::resolve_hostname(&hostname).await {
+                    Ok(addr) if addr != server.get() => {
+                        tracing::info!("Forward server {hostname} moved to {addr}.");
+                        server.set(addr);
+                    }
+                    Ok(_) => {}
+                    Err(err) => tracing::warn!("Failed to re-resolve the forward server {hostname}. {err}"),
+                }
+            }
+        });
+    }
+}
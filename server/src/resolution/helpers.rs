@@ -1,3 +1,4 @@
+use super::dns_packets::ParsedPacket;
 use pkarr::dns::{Packet, SimpleDnsError};
 
 /// Replaces the id of a dns packet.
@@ -7,6 +8,6 @@ pub fn replace_packet_id(packet: &Vec<u8>, new_id: u16) -> Result<Vec<u8>, Simpl
     std::mem::replace(&mut cloned[0], id_bytes[0]);
     std::mem::replace(&mut cloned[1], id_bytes[1]);
 
-    let parsed_packet = Packet::parse(&cloned)?;
-    Ok(parsed_packet.build_bytes_vec()?)
+    let parsed_packet = ParsedPacket::new(cloned)?;
+    Ok(parsed_packet.parsed().build_bytes_vec()?)
 }
@@ -6,6 +6,7 @@
  */
 mod dns_socket;
 mod dns_socket_builder;
+mod forward_server;
 mod helpers;
 mod pending_request;
 mod pkd;
@@ -15,6 +16,16 @@ mod response_cache;
 
 mod dns_packets;
 
+pub use dns_packets::ParsedPacket;
 pub use dns_socket::{DnsSocket, DnsSocketError};
 pub use dns_socket_builder::DnsSocketBuilder;
+pub use forward_server::ForwardServer;
+pub use pkd::{
+    parse_record_type, CustomHandlerError, PkarrResolverError, SoaTemplate, DEFAULT_RESERVED_TLDS, MAX_TOP_KEYS_TRACKED,
+};
 pub use rate_limiter::{RateLimiter, RateLimiterBuilder};
+
+#[cfg(test)]
+pub mod test_helpers;
+#[cfg(test)]
+pub use pkd::{MockDht, PkarrResolver, ResolverSettings};
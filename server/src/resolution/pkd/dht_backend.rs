@@ -0,0 +1,95 @@
+use async_trait::async_trait;
+use dyn_clone::DynClone;
+use pkarr::{Error as PkarrError, PkarrClientAsync, PublicKey, SignedPacket};
+use std::fmt::Debug;
+
+/**
+ * Source of signed pkarr packets.
+ * Implemented by the mainline DHT client. Makes it possible to swap the DHT for something else,
+ * for example a mock in tests.
+ */
+#[async_trait]
+pub trait DhtBackend: DynClone + Debug + Send + Sync {
+    /// Lookup the most recent signed packet of a public key. None if nothing is found.
+    async fn resolve(&self, pubkey: &PublicKey) -> Result<Option<SignedPacket>, PkarrError>;
+
+    /// Publish a signed packet.
+    async fn publish(&self, packet: &SignedPacket) -> Result<(), PkarrError>;
+}
+
+dyn_clone::clone_trait_object!(DhtBackend);
+
+#[async_trait]
+impl DhtBackend for PkarrClientAsync {
+    async fn resolve(&self, pubkey: &PublicKey) -> Result<Option<SignedPacket>, PkarrError> {
+        PkarrClientAsync::resolve(self, pubkey).await
+    }
+
+    async fn publish(&self, packet: &SignedPacket) -> Result<(), PkarrError> {
+        PkarrClientAsync::publish(self, packet).await
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    /**
+     * In-memory DHT used in tests. Counts the number of lookups.
+     */
+    #[derive(Clone, Debug, Default)]
+    pub struct MockDht {
+        packets: Arc<Mutex<HashMap<PublicKey, SignedPacket>>>,
+        lookups: Arc<AtomicUsize>,
+        delay: Option<Duration>,
+    }
+
+    impl MockDht {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Delay every lookup by the given duration to simulate a slow DHT.
+        pub fn with_delay(mut self, delay: Duration) -> Self {
+            self.delay = Some(delay);
+            self
+        }
+
+        pub fn add_packet(&self, packet: SignedPacket) {
+            self.packets.lock().unwrap().insert(packet.public_key(), packet);
+        }
+
+        pub fn contains(&self, pubkey: &PublicKey) -> bool {
+            self.packets.lock().unwrap().contains_key(pubkey)
+        }
+
+        /// Number of lookups made so far.
+        pub fn lookup_count(&self) -> usize {
+            self.lookups.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl DhtBackend for MockDht {
+        async fn resolve(&self, pubkey: &PublicKey) -> Result<Option<SignedPacket>, PkarrError> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            if let Some(delay) = self.delay {
+                tokio::time::sleep(delay).await;
+            }
+            Ok(self.packets.lock().unwrap().get(pubkey).cloned())
+        }
+
+        async fn publish(&self, packet: &SignedPacket) -> Result<(), PkarrError> {
+            self.add_packet(packet.clone());
+            Ok(())
+        }
+    }
+}
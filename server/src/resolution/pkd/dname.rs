@@ -0,0 +1,91 @@
+use pkarr::dns::{
+    rdata::{RData, CNAME, NULL},
+    Name, Packet, Question, ResourceRecord, CLASS,
+};
+
+use super::{pubkey_parser::parse_pkarr_uri, top_level_domain::TopLevelDomain};
+use crate::resolution::dns_packets::ParsedPacket;
+
+/// DNAME type code (RFC 6672). Not supported by simple-dns and therefore written as raw rdata.
+pub const DNAME_TYPE_CODE: u16 = 39;
+
+/// TTL of the synthesized DNAME and CNAME records.
+const SYNTHESIZED_TTL: u32 = 300;
+
+/// ICANN domain like `pk.example.com` that serves public key domains below it.
+/// `<sub>.<pubkey>.pk.example.com` is answered with a DNAME to the top level domain,
+/// a synthesized CNAME to `<sub>.<pubkey>.<tld>` and the records of the CNAME target.
+/// The ICANN domain must be delegated to pkdns.
+#[derive(Clone, Debug)]
+pub struct DnameParent(Name<'static>);
+
+impl DnameParent {
+    pub fn new(parent: &str) -> Result<Self, pkarr::dns::SimpleDnsError> {
+        Ok(Self(Name::new(parent)?.into_owned()))
+    }
+
+    /// Rewrites the question `<sub>.<pubkey>.<parent>` to `<sub>.<pubkey>.<tld>`.
+    /// Returns the rewritten qname. None if the question is not a public key domain below this parent.
+    pub fn rewrite(&self, packet: &mut Packet<'_>, tld: Option<&TopLevelDomain>) -> Option<Name<'static>> {
+        let question = packet.questions.first()?;
+        if !question.qname.is_subdomain_of(&self.0) {
+            return None;
+        }
+        let labels = question.qname.get_labels();
+        let relative_labels = &labels[..labels.len() - self.0.get_labels().len()];
+        parse_pkarr_uri(&relative_labels.last()?.to_string()).ok()?;
+
+        let mut target: Vec<String> = relative_labels.iter().map(|label| label.to_string()).collect();
+        if let Some(tld) = tld {
+            target.push(tld.label().to_string());
+        }
+        let target = Name::new(&target.join(".")).ok()?.into_owned();
+
+        let mut question = question.clone().into_owned();
+        question.qname = target.clone();
+        packet.questions = vec![question];
+        Some(target)
+    }
+
+    /// Restores the original question and prepends the DNAME and the synthesized CNAME to the answers.
+    pub fn add_records(
+        &self,
+        reply: &[u8],
+        original_question: &Question<'_>,
+        target: &Name<'_>,
+        tld: Option<&TopLevelDomain>,
+    ) -> Vec<u8> {
+        let parsed = ParsedPacket::new(reply.to_vec()).expect("Reply must be a valid dns packet.");
+        let mut reply = parsed.parsed().clone();
+
+        let dname_target: Vec<&str> = tld.map(|tld| tld.label()).into_iter().collect();
+        let dname_rdata = encode_name(&dname_target);
+        let dname = ResourceRecord::new(
+            self.0.clone(),
+            CLASS::IN,
+            SYNTHESIZED_TTL,
+            RData::NULL(DNAME_TYPE_CODE, NULL::new(&dname_rdata).unwrap().into_owned()),
+        );
+        let cname = ResourceRecord::new(
+            original_question.qname.clone(),
+            CLASS::IN,
+            SYNTHESIZED_TTL,
+            RData::CNAME(CNAME(target.clone())),
+        );
+
+        reply.questions = vec![original_question.clone()];
+        reply.answers.splice(0..0, [dname, cname]);
+        reply.build_bytes_vec().unwrap()
+    }
+}
+
+/// Uncompressed wire format of a domain name.
+fn encode_name(labels: &[&str]) -> Vec<u8> {
+    let mut data = vec![];
+    for label in labels {
+        data.push(label.len() as u8);
+        data.extend_from_slice(label.as_bytes());
+    }
+    data.push(0);
+    data
+}
@@ -0,0 +1,22 @@
+use pkarr::{bytes::Bytes, SignedPacket};
+use std::path::Path;
+
+/**
+ * Reads pre-signed pkarr packets from a directory. Every file contains one signed packet in the
+ * pkarr wire format `<public key><signature><timestamp><dns packet>`. Invalid files are skipped.
+ */
+pub fn read_packet_dir(dir: &Path) -> std::io::Result<Vec<SignedPacket>> {
+    let mut packets = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let data = std::fs::read(&path)?;
+        match SignedPacket::from_bytes(&Bytes::from(data)) {
+            Ok(packet) => packets.push(packet),
+            Err(e) => tracing::warn!("Skip {}. Not a valid signed pkarr packet. {e}", path.display()),
+        };
+    }
+    Ok(packets)
+}
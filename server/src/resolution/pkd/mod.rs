@@ -1,10 +1,38 @@
 mod bootstrap_nodes;
+mod dht_backend;
+mod dname;
+mod local_packets;
+mod parent_resolver;
 mod pkarr_cache;
 mod pkarr_resolver;
 mod pubkey_parser;
 mod query_matcher;
+mod relay_fallback;
+mod republisher;
+mod response_cache;
+mod shared_cache;
+mod soa;
+mod top_keys;
 mod top_level_domain;
 
-pub use pkarr_resolver::{CustomHandlerError, PkarrResolver, PkarrResolverError, ResolverSettings};
+pub use pkarr_resolver::{
+    CustomHandlerError, PkarrResolver, PkarrResolverError, ResolverSettings, TldSettings, DEFAULT_RESERVED_TLDS,
+};
 
+pub use dht_backend::DhtBackend;
+pub use dname::{DnameParent, DNAME_TYPE_CODE};
+pub use local_packets::read_packet_dir;
+pub use parent_resolver::ParentResolver;
+pub use pkarr_cache::CacheImport;
+pub use relay_fallback::{RelayFallback, RelaySet};
+pub use republisher::RepublishSettings;
+pub use response_cache::{parse_record_type, CacheableTypes};
+pub use shared_cache::{DirSharedCache, SharedCache};
+pub use soa::SoaTemplate;
+pub use top_keys::MAX_TOP_KEYS_TRACKED;
 pub use top_level_domain::TopLevelDomain;
+
+#[cfg(test)]
+pub use dht_backend::mock::MockDht;
+#[cfg(test)]
+pub use shared_cache::mock::MockSharedCache;
@@ -0,0 +1,77 @@
+use super::relay_fallback::{HttpRelay, RelayError};
+use pkarr::{PublicKey, SignedPacket};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/**
+ * Parent pkdns of a tiered deployment. Packets missing in the cache are requested from the relay listener
+ * of the parent first, which may have a warmer cache or better DHT connectivity than this node.
+ * Packets are signed so the parent doesn't need to be trusted.
+ * A parent that failed is skipped for the cooldown so cache misses don't wait on it over and over again.
+ */
+#[derive(Clone, Debug)]
+pub struct ParentResolver {
+    relay: HttpRelay,
+    /// Timeout of a single request to the parent.
+    timeout: Duration,
+    /// How long the parent is skipped after it failed.
+    cooldown: Duration,
+    /// When the parent failed the last time. Shared by all clones.
+    failed_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl ParentResolver {
+    pub fn new(addr: SocketAddr, timeout: Duration, cooldown: Duration) -> Self {
+        Self {
+            relay: HttpRelay::new(addr.to_string()),
+            timeout,
+            cooldown,
+            failed_at: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        match *self.failed_at.lock().unwrap() {
+            Some(failed_at) => failed_at.elapsed() >= self.cooldown,
+            None => true,
+        }
+    }
+
+    /// Most recent packet of the public key. None if the parent doesn't know the key.
+    pub async fn resolve(&self, pubkey: &PublicKey) -> Result<Option<SignedPacket>, RelayError> {
+        if !self.is_healthy() {
+            return Err(RelayError::CoolingDown);
+        }
+        let result = match tokio::time::timeout(self.timeout, self.relay.get(pubkey)).await {
+            Ok(result) => result,
+            Err(elapsed) => Err(elapsed.into()),
+        };
+        *self.failed_at.lock().unwrap() = result.is_err().then(Instant::now);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolution::test_helpers::silent_relay;
+    use pkarr::Keypair;
+
+    #[tokio::test]
+    async fn failed_parent_skipped_for_cooldown() {
+        let parent = ParentResolver::new(
+            silent_relay().await,
+            Duration::from_millis(100),
+            Duration::from_secs(60),
+        );
+        let pubkey = Keypair::random().public_key();
+
+        assert!(matches!(parent.resolve(&pubkey).await, Err(RelayError::Timeout(_))));
+        let start = Instant::now();
+        assert!(matches!(parent.resolve(&pubkey).await, Err(RelayError::CoolingDown)));
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
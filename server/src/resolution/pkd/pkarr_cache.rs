@@ -1,7 +1,11 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    mem::size_of,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use crate::metrics::METRICS;
 use moka::future::Cache;
-use pkarr::{PublicKey, SignedPacket};
+use pkarr::{bytes::Bytes, dns::ResourceRecord, PublicKey, SignedPacket};
 
 /**
  * Goal1: Cache things as long as possible to make any attack on the DHT unfeasible.
@@ -17,6 +21,57 @@ fn get_timestamp_seconds() -> u64 {
     since_the_epoch.as_secs() as u64
 }
 
+/// Bookkeeping moka keeps per entry: hash table slot, access order and write order deque nodes,
+/// entry info and the Arcs around key and value. An estimate from the moka 0.12 data structures, moka
+/// doesn't report it. The cache size gauges are estimates because of it.
+pub(super) const MOKA_ENTRY_OVERHEAD: usize = 160;
+
+/// Memory every cache entry needs on top of the packet itself.
+pub const ENTRY_OVERHEAD_BYTES: usize = MOKA_ENTRY_OVERHEAD + size_of::<PublicKey>() + size_of::<CacheItem>();
+
+/// Version of the exported cache state format.
+const CACHE_STATE_VERSION: u8 = 1;
+
+const NOT_FOUND_KIND: u8 = 0;
+const PACKET_KIND: u8 = 1;
+
+#[derive(thiserror::Error, Debug)]
+pub enum CacheStateError {
+    #[error("Unsupported cache state version {0}.")]
+    UnsupportedVersion(u8),
+
+    #[error("Cache state is truncated.")]
+    Truncated,
+
+    #[error("Unknown cache item kind {0}.")]
+    UnknownKind(u8),
+
+    #[error("Invalid public key or signed packet in cache state: {0}")]
+    InvalidItem(#[from] Box<pkarr::Error>),
+}
+
+/// Reads `len` bytes from the front of the data and advances it.
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], CacheStateError> {
+    if data.len() < len {
+        return Err(CacheStateError::Truncated);
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Ok(taken)
+}
+
+/// Result of importing a cache state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheImport {
+    /// Number of imported items.
+    pub loaded: usize,
+    /// Number of corrupt entries that have been skipped. Everything after an entry
+    /// with a broken framing counts as one skipped entry.
+    pub skipped: usize,
+    /// Number of valid packets that have not been accepted, for example because of too many records.
+    pub rejected: usize,
+}
+
 /**
  * Caches pkarr packets and not found pkarr packets.
  * Not found is important to avoid calling the DHT over and over again.
@@ -180,23 +235,98 @@ impl CacheItem {
     }
 
     /**
-     * Size of the cached value in the memory.
+     * Size of the cache entry in the memory. Includes the key and the overhead of the cache itself.
+     * Packets additionally hold their raw bytes and the parsed records.
      */
     pub fn memory_size(&self) -> usize {
         match self {
             CacheItem::NotFound {
                 public_key: _,
                 last_updated_at: _,
+            } => ENTRY_OVERHEAD_BYTES,
+            CacheItem::Packet {
+                packet,
+                last_updated_at: _,
+            } => {
+                let records = packet.packet().answers.capacity() * size_of::<ResourceRecord>();
+                ENTRY_OVERHEAD_BYTES + packet.as_bytes().len() + records
+            }
+        }
+    }
+
+    /**
+     * Serializes the item including its last_updated_at.
+     * Format: kind u8, last_updated_at u64, then a public key (not found) or a length prefixed signed packet.
+     */
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            CacheItem::NotFound {
+                public_key,
+                last_updated_at,
             } => {
-                32 + 8 // Public key 32 + cached_at 8
+                out.push(NOT_FOUND_KIND);
+                out.extend_from_slice(&last_updated_at.to_be_bytes());
+                out.extend_from_slice(public_key.as_bytes());
             }
             CacheItem::Packet {
                 packet,
-                last_updated_at: _,
-            } => packet.as_bytes().len() + 8,
+                last_updated_at,
+            } => {
+                out.push(PACKET_KIND);
+                out.extend_from_slice(&last_updated_at.to_be_bytes());
+                out.extend_from_slice(&(packet.as_bytes().len() as u32).to_be_bytes());
+                out.extend_from_slice(packet.as_bytes());
+            }
+        }
+    }
+
+    /**
+     * Deserializes an item written by `write_to` and advances the data. Verifies the packet signature.
+     */
+    fn read_from(data: &mut &[u8]) -> Result<Self, CacheStateError> {
+        let kind = take(data, 1)?[0];
+        let last_updated_at = u64::from_be_bytes(take(data, 8)?.try_into().unwrap());
+        match kind {
+            NOT_FOUND_KIND => Ok(CacheItem::NotFound {
+                public_key: PublicKey::try_from(take(data, 32)?).map_err(Box::new)?,
+                last_updated_at,
+            }),
+            PACKET_KIND => {
+                let len = u32::from_be_bytes(take(data, 4)?.try_into().unwrap()) as usize;
+                let bytes = Bytes::copy_from_slice(take(data, len)?);
+                Ok(CacheItem::Packet {
+                    packet: SignedPacket::from_bytes(&bytes).map_err(Box::new)?,
+                    last_updated_at,
+                })
+            }
+            kind => Err(CacheStateError::UnknownKind(kind)),
         }
     }
 
+    /**
+     * Serialized item including its last_updated_at.
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.write_to(&mut out);
+        out
+    }
+
+    /**
+     * Deserializes an item serialized with `to_bytes`. Verifies the packet signature.
+     */
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CacheStateError> {
+        let mut data = data;
+        Self::read_from(&mut data)
+    }
+
+    /**
+     * Seconds since the item got added to the cache or the cache got updated.
+     */
+    pub fn age_seconds(&self) -> u64 {
+        get_timestamp_seconds().saturating_sub(self.last_updated_at())
+    }
+
     /**
      * When the next refresh of this cached element is needed.
      */
@@ -207,12 +337,7 @@ impl CacheItem {
 
         let ttl = if ttl > max_ttl { max_ttl } else { ttl };
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
-
-        let age_seconds = now - self.last_updated_at();
+        let age_seconds = self.age_seconds();
         if age_seconds > ttl {
             0
         } else {
@@ -227,16 +352,19 @@ impl CacheItem {
 #[derive(Clone, Debug)]
 pub struct PkarrPacketLruCache {
     cache: Cache<PublicKey, CacheItem>, // Moka Cache is thread safe
+    budget_bytes: u64,
 }
 
 impl PkarrPacketLruCache {
     pub fn new(cache_size_mb: Option<u64>) -> Self {
         let cache_size_mb = cache_size_mb.unwrap_or(100); // 100MB by default
+        let budget_bytes = cache_size_mb * 1024 * 1024;
         PkarrPacketLruCache {
             cache: Cache::builder()
                 .weigher(|_key, value: &CacheItem| -> u32 { value.memory_size() as u32 })
-                .max_capacity(cache_size_mb * 1024 * 1024)
+                .max_capacity(budget_bytes)
                 .build(),
+            budget_bytes,
         }
     }
 
@@ -267,6 +395,24 @@ impl PkarrPacketLruCache {
         new_item
     }
 
+    /**
+     * Adds an item that has been cached elsewhere, for example in the shared cache. Keeps its last_updated_at.
+     * Makes sure to not override newer instances in the cache.
+     */
+    pub async fn add_cached_item(&mut self, item: CacheItem) -> CacheItem {
+        if let Some(already_cached) = self.get(&item.public_key()).await {
+            let is_older = item.controller_timestamp() < already_cached.controller_timestamp();
+            let same_age_but_staler = item.controller_timestamp() == already_cached.controller_timestamp()
+                && item.last_updated_at() <= already_cached.last_updated_at();
+            if is_older || same_age_but_staler {
+                return already_cached;
+            }
+        };
+
+        self.cache.insert(item.public_key(), item.clone()).await;
+        item
+    }
+
     /**
      * Adds packet. Makes sure to not override newer instances in the cache.
      */
@@ -299,6 +445,99 @@ impl PkarrPacketLruCache {
         self.cache.weighted_size()
     }
 
+    /**
+     * Size of the cache in bytes after all pending inserts and evictions are applied.
+     */
+    pub async fn size_bytes(&self) -> u64 {
+        self.cache.run_pending_tasks().await;
+        self.cache.weighted_size()
+    }
+
+    /**
+     * Reports size, budget and number of entries of the cache as gauges.
+     */
+    pub async fn update_gauges(&self) {
+        METRICS.pkarr_cache_size_bytes.set(self.size_bytes().await);
+        METRICS.pkarr_cache_budget_bytes.set(self.budget_bytes);
+        METRICS.pkarr_cache_entries.set(self.cache.entry_count());
+    }
+
+    /**
+     * Serializes all cached items so they can be imported by another pkdns process.
+     */
+    pub fn export_state(&self) -> Vec<u8> {
+        let mut out = vec![CACHE_STATE_VERSION];
+        for (_, item) in self.cache.iter() {
+            item.write_to(&mut out);
+        }
+        out
+    }
+
+    /**
+     * Imports items exported with `export_state`. Items keep their last_updated_at so their TTLs are preserved.
+     * Packets for which `accept` returns false are left out. Returns the number of imported items.
+     */
+    pub async fn import_state(
+        &mut self,
+        data: &[u8],
+        strict: bool,
+        accept: impl Fn(&SignedPacket) -> bool,
+    ) -> Result<CacheImport, CacheStateError> {
+        let mut data = data;
+        let version = take(&mut data, 1)?[0];
+        if version != CACHE_STATE_VERSION {
+            return Err(CacheStateError::UnsupportedVersion(version));
+        }
+
+        let mut items = vec![];
+        let mut skipped = 0;
+        while !data.is_empty() {
+            match CacheItem::read_from(&mut data) {
+                Ok(item) => items.push(item),
+                Err(e) if strict => return Err(e),
+                Err(e @ CacheStateError::InvalidItem(_)) => {
+                    // The entry has been read completely so the next one can still be loaded.
+                    tracing::warn!("Skipped a corrupt entry of the cache state. {e}");
+                    METRICS.cache_state_skipped_entries.inc();
+                    skipped += 1;
+                }
+                Err(e) => {
+                    tracing::warn!("Skipped the rest of the cache state. {e}");
+                    METRICS.cache_state_skipped_entries.inc();
+                    skipped += 1;
+                    break;
+                }
+            }
+        }
+        let mut import = CacheImport {
+            loaded: 0,
+            skipped,
+            rejected: 0,
+        };
+        for item in items {
+            if let CacheItem::Packet { packet, .. } = &item {
+                if !accept(packet) {
+                    import.rejected += 1;
+                    continue;
+                }
+            }
+            self.add_cached_item(item).await;
+            import.loaded += 1;
+        }
+        Ok(import)
+    }
+
+    /**
+     * Public keys of the cached packets that need a refresh. Not found items are left to expire.
+     */
+    pub fn packets_needing_refresh(&self, min_ttl: u64, max_ttl: u64) -> Vec<PublicKey> {
+        self.cache
+            .iter()
+            .filter(|(_, item)| item.is_found() && item.next_refresh_needed_in_s(min_ttl, max_ttl) == 0)
+            .map(|(pubkey, _)| (*pubkey).clone())
+            .collect()
+    }
+
     #[allow(dead_code)]
     pub fn entry_count(&self) -> u64 {
         self.cache.entry_count()
@@ -339,7 +578,11 @@ mod tests {
     async fn packet_memory_size() {
         let packet = example_signed_packet(Keypair::random());
         let cached = CacheItem::new_packet(packet.clone());
-        assert_eq!(cached.memory_size(), 220);
+        let records = 2 * size_of::<ResourceRecord>();
+        assert_eq!(cached.memory_size(), ENTRY_OVERHEAD_BYTES + 212 + records);
+
+        let not_found = CacheItem::new_not_found(packet.public_key());
+        assert_eq!(not_found.memory_size(), ENTRY_OVERHEAD_BYTES);
     }
 
     #[tokio::test]
@@ -351,7 +594,24 @@ mod tests {
             cache.add_packet(example_signed_packet(Keypair::random())).await;
         }
         cache.cache.run_pending_tasks().await;
-        assert_eq!(cache.approx_size_bytes(), 2200);
+        let entry_size = CacheItem::new_packet(example_signed_packet(Keypair::random())).memory_size() as u64;
+        assert_eq!(cache.approx_size_bytes(), 10 * entry_size);
+    }
+
+    #[tokio::test]
+    async fn size_gauge_tracks_packet_sizes() {
+        let mut cache = PkarrPacketLruCache::new(Some(1));
+        let mut packet_bytes = 0;
+        for _ in 0..50 {
+            let packet = example_signed_packet(Keypair::random());
+            packet_bytes += packet.as_bytes().len() as u64;
+            cache.add_packet(packet).await;
+        }
+
+        let reported = cache.size_bytes().await;
+        let per_entry_tolerance = (ENTRY_OVERHEAD_BYTES + 4 * size_of::<ResourceRecord>()) as u64;
+        assert!(reported >= packet_bytes);
+        assert!(reported <= packet_bytes + 50 * per_entry_tolerance);
     }
 
     #[tokio::test]
@@ -419,4 +679,87 @@ mod tests {
         let cached = cache.get(&key.public_key()).await.unwrap();
         assert_eq!(packet1.timestamp(), cached.controller_timestamp());
     }
+
+    #[tokio::test]
+    async fn export_import_state() {
+        let mut cache = PkarrPacketLruCache::new(Some(1));
+        let packet = example_signed_packet(Keypair::random());
+        let not_found_key = Keypair::random().public_key();
+        let an_hour_ago = get_timestamp_seconds() - 60 * 60;
+        cache
+            .add_cached_item(CacheItem::Packet {
+                packet: packet.clone(),
+                last_updated_at: an_hour_ago,
+            })
+            .await;
+        cache
+            .add_cached_item(CacheItem::NotFound {
+                public_key: not_found_key.clone(),
+                last_updated_at: an_hour_ago,
+            })
+            .await;
+        cache.cache.run_pending_tasks().await;
+
+        let state = cache.export_state();
+        let mut imported = PkarrPacketLruCache::new(Some(1));
+        assert_eq!(
+            imported.import_state(&state, true, |_| true).await.unwrap(),
+            CacheImport {
+                loaded: 2,
+                skipped: 0,
+                rejected: 0
+            }
+        );
+
+        let item = imported.get(&packet.public_key()).await.unwrap();
+        assert_eq!(item.controller_timestamp(), packet.timestamp());
+        assert_eq!(item.last_updated_at(), an_hour_ago);
+        let original = cache.get(&packet.public_key()).await.unwrap();
+        assert_eq!(
+            item.next_refresh_needed_in_s(0, 86400),
+            original.next_refresh_needed_in_s(0, 86400)
+        );
+        let item = imported.get(&not_found_key).await.unwrap();
+        assert!(item.not_found());
+        assert_eq!(item.last_updated_at(), an_hour_ago);
+
+        assert!(matches!(
+            imported.import_state(&state[..state.len() - 1], true, |_| true).await,
+            Err(CacheStateError::Truncated)
+        ));
+    }
+
+    #[tokio::test]
+    async fn import_state_skips_corrupt_entry() {
+        let mut cache = PkarrPacketLruCache::new(Some(1));
+        let corrupt = example_signed_packet(Keypair::random());
+        let valid = example_signed_packet(Keypair::random());
+        cache.add_packet(corrupt.clone()).await;
+        let mut state = cache.export_state();
+        // Flip a bit of the signature so the entry fails verification.
+        state[1 + 1 + 8 + 4 + 32] ^= 1;
+        CacheItem::new_packet(valid.clone()).write_to(&mut state);
+
+        let path = std::env::temp_dir().join(format!("pkdns-cache-state-{}", valid.public_key()));
+        std::fs::write(&path, &state).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut imported = PkarrPacketLruCache::new(Some(1));
+        assert!(matches!(
+            imported.import_state(&data, true, |_| true).await,
+            Err(CacheStateError::InvalidItem(_))
+        ));
+        let import = imported.import_state(&data, false, |_| true).await.unwrap();
+        assert_eq!(
+            import,
+            CacheImport {
+                loaded: 1,
+                skipped: 1,
+                rejected: 0
+            }
+        );
+        assert!(imported.get(&valid.public_key()).await.is_some());
+        assert!(imported.get(&corrupt.public_key()).await.is_none());
+    }
 }
@@ -1,22 +1,64 @@
 use super::{
-    pubkey_parser::parse_pkarr_uri, query_matcher::create_domain_not_found_reply, top_level_domain::TopLevelDomain,
+    dname::DnameParent, pubkey_parser::parse_pkarr_uri, query_matcher::create_domain_not_found_reply,
+    top_level_domain::TopLevelDomain,
 };
-use crate::resolution::{dns_packets::ParsedQuery, DnsSocket, DnsSocketError, RateLimiter, RateLimiterBuilder};
-use pkarr::dns::{Name, Question, ResourceRecord};
+use crate::{
+    config::{AnyPolicy, OversizedPacketPolicy},
+    metrics::METRICS,
+    resolution::{
+        dns_packets::{add_extended_error, ExtendedDnsError, ParsedPacket, ParsedQuery},
+        DnsSocket, DnsSocketError, RateLimiter, RateLimiterBuilder,
+    },
+};
+use pkarr::dns::{rdata::RData, Name, Question, ResourceRecord, SimpleDnsError, CLASS, RCODE};
 use std::{
-    collections::HashMap,
-    net::{IpAddr, SocketAddr},
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     num::NonZeroU32,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 use tokio::sync::Mutex;
 
 use super::{
     bootstrap_nodes::MainlineBootstrapResolver,
-    pkarr_cache::{CacheItem, PkarrPacketLruCache},
-    query_matcher::resolve_query,
+    dht_backend::DhtBackend,
+    parent_resolver::ParentResolver,
+    pkarr_cache::{CacheImport, CacheItem, CacheStateError, PkarrPacketLruCache},
+    query_matcher::{resolve_query, ResolveQueryError},
+    relay_fallback::RelayFallback,
+    republisher::{RepublishSettings, Republisher},
+    response_cache::{CacheableTypes, PkarrResponseCache},
+    shared_cache::SharedCache,
+    soa::SoaTemplate,
+    top_keys::TopKeys,
+};
+use pkarr::{
+    dns::Packet, mainline::dht::DhtSettings, Error as PkarrError, PkarrClient, PkarrClientAsync, PublicKey,
+    SignedPacket,
 };
-use pkarr::{dns::Packet, mainline::dht::DhtSettings, Error as PkarrError, PkarrClient, PkarrClientAsync, PublicKey};
+
+/// Special-use tlds reserved by the IETF (RFC 2606, 6761, 6762, 7686, 9476) and `.internal`.
+pub const DEFAULT_RESERVED_TLDS: [&str; 8] = [
+    "alt",
+    "example",
+    "internal",
+    "invalid",
+    "local",
+    "localhost",
+    "onion",
+    "test",
+];
+
+/// Log target of the trace events emitted for `ResolverSettings::debug_keys`.
+/// Always enabled at trace level so the events show up independent of the global log level.
+const DEBUG_KEYS_TARGET: &str = "pkdns::debug_keys";
+
+/// Number of public keys whose last counted oversized packet is remembered.
+const OVERSIZED_SEEN_CAPACITY: u64 = 10_000;
 
 /// Errors that a CustomHandler can return.
 #[derive(thiserror::Error, Debug)]
@@ -33,6 +75,18 @@ pub enum CustomHandlerError {
     /// Handler rate limited the IP. Will return RCODE::Refused.
     #[error("Source ip address {0} is rate limited.")]
     RateLimited(IpAddr),
+
+    /// Query is for a name under a reserved tld. Never resolved as a public key domain.
+    #[error("Query for a name under the reserved tld .{0}.")]
+    ReservedTld(String),
+
+    /// Query is for a public key domain with a key type pkdns doesn't support.
+    #[error("Public key {0} is not a supported key type.")]
+    UnsupportedKey(String),
+
+    /// Reply has more records than the query may still collect. Will return RCODE::ServFail.
+    #[error("Reply exceeds the record budget of the query.")]
+    RecordBudgetExceeded,
 }
 
 #[derive(Clone, Debug)]
@@ -58,6 +112,75 @@ pub struct ResolverSettings {
 
     /// Top level domain like `.pkd`.
     pub top_level_domain: Option<TopLevelDomain>,
+
+    /// Maximum number of milliseconds a query waits on the lock of a public key that is currently refreshed.
+    pub lock_timeout_ms: u64,
+
+    /// Settings overrides per top level domain. Public key domains under these tlds are resolved too.
+    pub tld_overrides: Vec<TldSettings>,
+
+    /// ICANN domains like `pk.example.com` that serve public key domains with a synthesized DNAME.
+    pub dname_parents: Vec<DnameParent>,
+
+    /// How ANY queries are answered.
+    pub any_policy: AnyPolicy,
+
+    /// Number of answers the DHT must have given before the resolver is considered ready. 0 is disabled.
+    pub min_dht_responses_for_ready: usize,
+
+    /// Public keys whose queries are traced in detail. For debugging a single key.
+    pub debug_keys: HashSet<PublicKey>,
+
+    /// Serve the recoverable records of pkarr packets that don't survive being serialized and parsed again.
+    pub lenient_parsing: bool,
+
+    /// Maximum number of records served from a pkarr packet. 0 = unlimited.
+    pub max_records_per_packet: usize,
+
+    /// How packets with more than `max_records_per_packet` records are handled.
+    pub oversized_packet_policy: OversizedPacketPolicy,
+
+    /// Age in seconds after which answers served from a cache entry are counted as stale. 0 = disabled.
+    pub staleness_warn_s: u64,
+
+    /// Log a warning for every stale answer in addition to counting it.
+    pub log_stale_answers: bool,
+
+    /// Tlds like `onion` that are never resolved as public key domains. Lowercase.
+    pub reserved_tlds: HashSet<String>,
+
+    /// Add the apex SOA to NODATA replies, for example AAAA queries for names with only A records.
+    pub nodata_soa: bool,
+
+    /// Remove A and AAAA records with private, loopback or link-local addresses from answers.
+    pub block_private_ips: bool,
+
+    /// Append the tld to the owner names in the authority and additional section too, not only to the answers.
+    pub fully_qualify_owner_names: bool,
+
+    /// Honor the fresh lookup EDNS option that bypasses the caches.
+    pub allow_fresh_lookups: bool,
+
+    /// Pkarr relays asked when the DHT has no packet or fails.
+    pub relay_fallback: Option<RelayFallback>,
+
+    /// Parent pkdns asked on cache misses before the DHT. The DHT is asked if the parent has no packet.
+    pub parent_resolver: Option<ParentResolver>,
+
+    /// Republish the packets published through pkdns. None = never republished.
+    pub republish: Option<RepublishSettings>,
+
+    /// Number of most queried public keys whose query counts are tracked. 0 = disabled.
+    pub top_keys_tracked: usize,
+
+    /// Let the DHT client bind a random port instead of the default port 6881.
+    pub randomize_dht_port: bool,
+
+    /// SOA answered for SOA queries at the apex of zones that don't publish one.
+    pub soa_template: SoaTemplate,
+
+    /// Record types whose replies are cached. Packets are cached independent of this.
+    pub cacheable_types: CacheableTypes,
 }
 
 impl ResolverSettings {
@@ -72,10 +195,45 @@ impl ResolverSettings {
             max_dht_queries_per_ip_per_second: 0,
             max_dht_queries_per_ip_burst: 0,
             top_level_domain: Some(TopLevelDomain("key".to_string())),
+            lock_timeout_ms: 5000,
+            tld_overrides: vec![],
+            dname_parents: vec![],
+            any_policy: AnyPolicy::Expand,
+            min_dht_responses_for_ready: 0,
+            debug_keys: HashSet::new(),
+            lenient_parsing: false,
+            max_records_per_packet: 0,
+            oversized_packet_policy: OversizedPacketPolicy::Truncate,
+            staleness_warn_s: 0,
+            log_stale_answers: false,
+            reserved_tlds: DEFAULT_RESERVED_TLDS.map(String::from).into(),
+            nodata_soa: false,
+            block_private_ips: false,
+            fully_qualify_owner_names: false,
+            allow_fresh_lookups: false,
+            relay_fallback: None,
+            parent_resolver: None,
+            republish: None,
+            top_keys_tracked: 0,
+            randomize_dht_port: true,
+            soa_template: SoaTemplate::default(),
+            cacheable_types: CacheableTypes::All,
         }
     }
 }
 
+/// Overrides a subset of the `ResolverSettings` for public key domains under one top level domain.
+#[derive(Clone, Debug)]
+pub struct TldSettings {
+    pub tld: TopLevelDomain,
+
+    /// Overrides `ResolverSettings::min_ttl`.
+    pub min_ttl: Option<u64>,
+
+    /// Overrides `ResolverSettings::max_ttl`.
+    pub max_ttl: Option<u64>,
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum PkarrResolverError {
     #[error("Failed to query the DHT with pkarr: {0}")]
@@ -83,6 +241,18 @@ pub enum PkarrResolverError {
 
     #[error("Failed to query the DHT with pkarr: {0}")]
     DnsSocket(#[from] DnsSocketError),
+
+    #[error("Timeout. Waited too long on a concurrent lookup of [{0}] and no cached packet is available.")]
+    LockTimeout(PublicKey),
+
+    #[error("A more recent packet of [{0}] is already known.")]
+    OutdatedPacket(PublicKey),
+
+    #[error("Source ip address {0} is rate limited.")]
+    RateLimited(IpAddr),
+
+    #[error("Packet of [{0}] has more records than allowed.")]
+    TooManyRecords(PublicKey),
 }
 
 /**
@@ -90,12 +260,40 @@ pub enum PkarrResolverError {
  */
 #[derive(Clone, Debug)]
 pub struct PkarrResolver {
-    client: PkarrClientAsync,
+    client: Box<dyn DhtBackend>,
     cache: PkarrPacketLruCache,
+    /**
+     * Optional second cache tier, shared with other pkdns instances.
+     */
+    shared_cache: Option<Box<dyn SharedCache>>,
+    /**
+     * Locally provided packets. Served without DHT lookups, never refreshed and never evicted.
+     */
+    local_packets: Arc<std::sync::RwLock<HashMap<PublicKey, SignedPacket>>>,
     /**
      * Locks to use to update pkarr packets. This avoids concurrent updates.
      */
     lock_map: Arc<Mutex<HashMap<PublicKey, Arc<Mutex<()>>>>>,
+    /**
+     * Replies derived from the cached packets.
+     */
+    response_cache: PkarrResponseCache,
+    /**
+     * Keeps the packets published through pkdns alive on the DHT.
+     */
+    republisher: Option<Republisher>,
+    /**
+     * Query counts of the most queried public keys.
+     */
+    top_keys: Option<TopKeys>,
+    /**
+     * Lookups answered with a packet and successful publishes. Proves the DHT is reachable.
+     */
+    dht_responses: Arc<AtomicUsize>,
+    /**
+     * Timestamp of the last oversized packet counted per public key. Repeated ingests of the same packet count once.
+     */
+    oversized_seen: moka::sync::Cache<PublicKey, u64>,
     settings: ResolverSettings,
     rate_limiter: Arc<RateLimiter>,
 }
@@ -129,6 +327,9 @@ impl PkarrResolver {
         let addrs = Self::resolve_bootstrap_nodes(&settings.forward_dns_server);
         let mut dht_settings = DhtSettings::default();
         dht_settings.bootstrap = Some(addrs);
+        if settings.randomize_dht_port {
+            dht_settings.port = Some(0); // Let the OS pick a random port.
+        }
         let client = PkarrClient::builder()
             .minimum_ttl(0)
             .maximum_ttl(0) // Disable Pkarr caching
@@ -136,31 +337,261 @@ impl PkarrResolver {
             .resolvers(None)
             .build()
             .unwrap();
+        Self::with_backend(settings, Box::new(client.as_async()))
+    }
+
+    /// Creates a resolver that pulls pkarr packets from the given backend instead of the mainline DHT.
+    pub fn with_backend(settings: ResolverSettings, backend: Box<dyn DhtBackend>) -> Self {
         let limiter = RateLimiterBuilder::new().max_per_second(settings.max_dht_queries_per_ip_per_second.clone());
         Self {
-            client: client.as_async(),
+            client: backend,
             cache: PkarrPacketLruCache::new(Some(settings.cache_mb)),
+            shared_cache: None,
+            local_packets: Arc::new(std::sync::RwLock::new(HashMap::new())),
             lock_map: Arc::new(Mutex::new(HashMap::new())),
+            response_cache: PkarrResponseCache::new(settings.cacheable_types.clone()),
+            republisher: settings.republish.clone().map(Republisher::new),
+            top_keys: (settings.top_keys_tracked > 0).then(|| TopKeys::new(settings.top_keys_tracked)),
+            dht_responses: Arc::new(AtomicUsize::new(0)),
+            oversized_seen: moka::sync::Cache::new(OVERSIZED_SEEN_CAPACITY),
             rate_limiter: Arc::new(limiter.build()),
             settings,
         }
     }
 
-    fn is_refresh_needed(&self, item: &CacheItem) -> bool {
-        let refresh_needed_in_s = item.next_refresh_needed_in_s(self.settings.min_ttl, self.settings.max_ttl);
-        refresh_needed_in_s == 0
+    /// Adds a shared cache that is consulted on local cache misses before the DHT is queried.
+    pub fn with_shared_cache(mut self, shared_cache: Box<dyn SharedCache>) -> Self {
+        self.shared_cache = Some(shared_cache);
+        self
+    }
+
+    /// Adds a local packet that is served instead of looking up the DHT.
+    /// Returns false if the packet is rejected because of too many records.
+    pub fn add_local_packet(&self, packet: SignedPacket) -> bool {
+        if !self.check_record_limit(&packet) {
+            return false;
+        }
+        self.local_packets.write().unwrap().insert(packet.public_key(), packet);
+        true
+    }
+
+    /// Serialized pkarr cache that can be imported by another pkdns process.
+    pub fn export_cache(&self) -> Vec<u8> {
+        self.cache.export_state()
+    }
+
+    /// Imports a cache exported with `export_cache`. Skips corrupt entries unless `strict` is set.
+    /// Packets with too many records are rejected.
+    pub async fn import_cache(&mut self, data: &[u8], strict: bool) -> Result<CacheImport, CacheStateError> {
+        let resolver = self.clone();
+        self.cache
+            .import_state(data, strict, |packet| resolver.check_record_limit(packet))
+            .await
+    }
+
+    /// Refreshes the cached packets that are due so the cache stays warm without client queries.
+    /// Returns the number of refreshed packets.
+    pub async fn prefetch_expired(&mut self) -> usize {
+        let bounds = (self.settings.min_ttl, self.settings.max_ttl);
+        let due = self.cache.packets_needing_refresh(bounds.0, bounds.1);
+        let mut refreshed = 0;
+        for pubkey in due {
+            let mutex = self.key_lock(&pubkey).await;
+            // A client query is already refreshing this key.
+            let Ok(_guard) = mutex.try_lock() else {
+                continue;
+            };
+            match self.lookup_dht_and_cache_locked(pubkey.clone(), bounds).await {
+                Ok(_) => refreshed += 1,
+                Err(err) => tracing::debug!("Failed to prefetch [{pubkey}]. {err}"),
+            }
+        }
+        refreshed
+    }
+
+    /// Republishes the published packets that are due. Returns the number of republished packets.
+    pub async fn republish_due(&self) -> usize {
+        match &self.republisher {
+            Some(republisher) => republisher.republish_due(self.client.as_ref()).await,
+            None => 0,
+        }
+    }
+
+    /// Reports the memory footprint of the pkarr packet and reply caches as gauges.
+    pub async fn update_cache_gauges(&self) {
+        self.cache.update_gauges().await;
+        self.response_cache.update_gauges().await;
+    }
+
+    /// Most queried public keys with their query counts, most queried first. Empty if tracking is disabled.
+    pub fn top_keys(&self) -> Vec<(PublicKey, u64)> {
+        self.top_keys.as_ref().map(TopKeys::top).unwrap_or_default()
+    }
+
+    /// True once the DHT answered at least `min_dht_responses_for_ready` lookups or publishes.
+    pub fn is_ready(&self) -> bool {
+        self.dht_responses.load(Ordering::Relaxed) >= self.settings.min_dht_responses_for_ready
+    }
+
+    /// Signed packet of a public key. Served from the cache if possible, otherwise looked up on the DHT.
+    pub async fn resolve_signed_packet(
+        &mut self,
+        pubkey: &PublicKey,
+        from: Option<IpAddr>,
+    ) -> Result<Option<SignedPacket>, CustomHandlerError> {
+        let item = self
+            .resolve_pubkey_respect_cache(pubkey, from, self.ttl_bounds(None), false)
+            .await?;
+        if item.not_found() {
+            return Ok(None);
+        }
+        Ok(Some(item.unwrap()))
+    }
+
+    /// Publishes a signed packet on the DHT and caches it. Fails if a more recent packet is already cached.
+    pub async fn publish_signed_packet(
+        &mut self,
+        packet: SignedPacket,
+        from: Option<IpAddr>,
+    ) -> Result<(), PkarrResolverError> {
+        if let Some(ip) = from {
+            if self.rate_limiter.check_is_limited_and_increase(&ip) {
+                tracing::debug!("{ip} is rate limited from publishing on the DHT.");
+                return Err(PkarrResolverError::RateLimited(ip));
+            }
+        }
+
+        let pubkey = packet.public_key();
+        if !self.check_record_limit(&packet) {
+            return Err(PkarrResolverError::TooManyRecords(pubkey));
+        }
+        if let Some(cached) = self.cache.get(&pubkey).await {
+            if cached.is_found() && cached.unwrap().more_recent_than(&packet) {
+                return Err(PkarrResolverError::OutdatedPacket(pubkey));
+            }
+        }
+
+        self.client.publish(&packet).await?;
+        self.dht_responses.fetch_add(1, Ordering::Relaxed);
+        tracing::trace!("Published [{pubkey}] on the DHT.");
+        if let Some(republisher) = &self.republisher {
+            republisher.track(packet.clone());
+        }
+        let item = self.cache.add_packet(packet).await;
+        if let Some(shared_cache) = &self.shared_cache {
+            shared_cache.put(&item).await;
+        }
+        Ok(())
+    }
+
+    /// Min and max ttl for public key domains under the given tld. Respects the tld overrides.
+    fn ttl_bounds(&self, tld: Option<&TopLevelDomain>) -> (u64, u64) {
+        let tld_settings = tld.and_then(|tld| {
+            self.settings
+                .tld_overrides
+                .iter()
+                .find(|settings| settings.tld.label() == tld.label())
+        });
+        match tld_settings {
+            Some(settings) => (
+                settings.min_ttl.unwrap_or(self.settings.min_ttl),
+                settings.max_ttl.unwrap_or(self.settings.max_ttl),
+            ),
+            None => (self.settings.min_ttl, self.settings.max_ttl),
+        }
+    }
+
+    /// Adds the apex SOA to the authority section of empty NOERROR replies (NODATA)
+    /// so clients cache the negative answer and don't retry (RFC 2308).
+    fn add_negative_soa(
+        &self,
+        reply: Vec<u8>,
+        signed_packet: &SignedPacket,
+        apex: &str,
+        serial: u32,
+    ) -> Result<Vec<u8>, SimpleDnsError> {
+        let parsed = ParsedPacket::new(reply.clone())?;
+        let mut packet = parsed.parsed().clone();
+        if packet.rcode() != RCODE::NoError || !packet.answers.is_empty() || !packet.name_servers.is_empty() {
+            return Ok(reply);
+        }
+        let apex_name = Name::new(apex)?;
+        let pubkey = signed_packet.public_key().to_z32();
+        let pubkey_name = Name::new(&pubkey)?;
+        let published = signed_packet
+            .packet()
+            .answers
+            .iter()
+            .find(|record| record.name == pubkey_name && matches!(record.rdata, RData::SOA(_)));
+        let soa = match published {
+            Some(record) => ResourceRecord::new(apex_name, CLASS::IN, record.ttl, record.rdata.clone()),
+            None => self.settings.soa_template.record(apex_name, apex, serial)?,
+        };
+        packet.name_servers.push(soa);
+        packet.build_bytes_vec_compressed()
+    }
+
+    /// Removes A and AAAA records with private addresses. Returns the reply and true if the reply had answers
+    /// but all of them got removed.
+    fn filter_private_ips(reply: Vec<u8>) -> Result<(Vec<u8>, bool), SimpleDnsError> {
+        let parsed = ParsedPacket::new(reply.clone())?;
+        let mut packet = parsed.parsed().clone();
+        let answers_before = packet.answers.len();
+        let additional_before = packet.additional_records.len();
+        packet.answers.retain(|record| !is_private_record(record));
+        packet.additional_records.retain(|record| !is_private_record(record));
+        if packet.answers.len() == answers_before && packet.additional_records.len() == additional_before {
+            return Ok((reply, false));
+        }
+        let all_filtered = answers_before > 0 && packet.answers.is_empty();
+        Ok((packet.build_bytes_vec_compressed()?, all_filtered))
+    }
+
+    /// Counts the answer as stale if its cache entry is older than `staleness_warn_s`.
+    fn check_staleness(&self, pubkey: &PublicKey, item: &CacheItem) {
+        let age_seconds = item.age_seconds();
+        if self.settings.staleness_warn_s == 0 || age_seconds <= self.settings.staleness_warn_s {
+            return;
+        }
+        METRICS.stale_answers_served.inc();
+        if self.settings.log_stale_answers {
+            tracing::warn!("Served a stale answer for [{pubkey}]. Cache entry is {age_seconds}s old.");
+        }
+    }
+
+    /// Checks if the name is a public key domain this resolver answers, with or without a top level domain.
+    pub fn is_public_key_domain(&self, name: &Name<'_>) -> bool {
+        let labels = name.get_labels();
+        let Some(last) = labels.last() else {
+            return false;
+        };
+        if self.settings.reserved_tlds.contains(&last.to_string().to_lowercase()) {
+            return false;
+        }
+        let override_tlds = self.settings.tld_overrides.iter().map(|settings| &settings.tld);
+        let mut tlds = self.settings.top_level_domain.iter().chain(override_tlds);
+        tlds.any(|tld| tld.name_ends_with_pubkey_tld(name)) || parse_pkarr_uri(&last.to_string()).is_ok()
     }
 
     /**
-     * Resolves a public key. Checks the cache first.
+     * Resolves a public key. Checks the cache first unless `fresh` forces a DHT lookup.
      */
     async fn resolve_pubkey_respect_cache(
         &mut self,
         pubkey: &PublicKey,
         from: Option<IpAddr>,
+        (min_ttl, max_ttl): (u64, u64),
+        fresh: bool,
     ) -> Result<CacheItem, CustomHandlerError> {
-        if let Some(cached) = self.cache.get(pubkey).await {
-            let refresh_needed_in_s = cached.next_refresh_needed_in_s(self.settings.min_ttl, self.settings.max_ttl);
+        if let Some(local) = self.local_packets.read().unwrap().get(pubkey) {
+            tracing::trace!("Pkarr packet [{pubkey}] served from the local packets.");
+            return Ok(CacheItem::new_packet(local.clone()));
+        }
+
+        if fresh {
+            tracing::trace!("Fresh lookup of [{pubkey}] requested. Skip the caches.");
+        } else if let Some(cached) = self.cache.get(pubkey).await {
+            let refresh_needed_in_s = cached.next_refresh_needed_in_s(min_ttl, max_ttl);
 
             if refresh_needed_in_s > 0 {
                 tracing::trace!(
@@ -171,6 +602,12 @@ impl PkarrResolver {
             }
         };
 
+        if !fresh {
+            if let Some(shared) = self.lookup_shared_cache(pubkey, (min_ttl, max_ttl)).await {
+                return Ok(shared);
+            }
+        }
+
         if let Some(ip) = from {
             let is_rate_limited = self.rate_limiter.check_is_limited_and_increase(&ip);
             if is_rate_limited {
@@ -179,68 +616,292 @@ impl PkarrResolver {
             }
         }
 
-        self.lookup_dht_and_cache(pubkey.clone())
+        // Bounds of 0 make any cached packet due so the lookup doesn't return it early.
+        let bounds = if fresh { (0, 0) } else { (min_ttl, max_ttl) };
+        self.lookup_dht_and_cache(pubkey.clone(), bounds)
             .await
             .map_err(|err| CustomHandlerError::Failed(err.into()))
     }
 
-    /// Lookup DHT to pull pkarr packet. Will not check the cache first but store any new value in the cache. Returns cached value if lookup fails.
-    async fn lookup_dht_and_cache(&mut self, pubkey: PublicKey) -> Result<CacheItem, PkarrResolverError> {
+    /// False if the packet has more records than allowed and the policy rejects it.
+    fn check_record_limit(&self, packet: &SignedPacket) -> bool {
+        let max = self.settings.max_records_per_packet;
+        let records = packet.packet().answers.len();
+        if max == 0 || records <= max {
+            return true;
+        }
+        self.count_oversized(packet);
+        let pubkey = packet.public_key();
+        match self.settings.oversized_packet_policy {
+            OversizedPacketPolicy::Truncate => {
+                tracing::debug!("Pkarr packet [{pubkey}] has {records} records. Only the first {max} are served.");
+                true
+            }
+            OversizedPacketPolicy::Reject => {
+                tracing::debug!("Pkarr packet [{pubkey}] has {records} records. More than {max}, rejected.");
+                false
+            }
+        }
+    }
+
+    /// Counts the oversized packet unless it has been counted before. Returns true if it got counted.
+    fn count_oversized(&self, packet: &SignedPacket) -> bool {
+        let pubkey = packet.public_key();
+        if self.oversized_seen.get(&pubkey) == Some(packet.timestamp()) {
+            return false;
+        }
+        self.oversized_seen.insert(pubkey, packet.timestamp());
+        METRICS.oversized_packets.inc();
+        true
+    }
+
+    /// Copy of the packet with only the first `max_records_per_packet` records. None if it's within the limit.
+    fn truncate_records<'a>(&self, packet: &Packet<'a>) -> Option<Packet<'a>> {
+        let max = self.settings.max_records_per_packet;
+        if max == 0 || packet.answers.len() <= max {
+            return None;
+        }
+        let mut truncated = packet.clone();
+        truncated.answers.truncate(max);
+        Some(truncated)
+    }
+
+    /// Adds an Extended DNS Error to replies of forced fresh lookups so clients can tell the cache got bypassed.
+    /// Added after the reply is cached so cached replies never carry it.
+    fn mark_fresh_lookup(reply: Vec<u8>, request: &Packet<'_>, fresh: bool) -> Vec<u8> {
+        if !fresh {
+            return reply;
+        }
+        let parsed = ParsedPacket::new(reply).expect("Reply must be a valid dns packet.");
+        let mut packet = parsed.parsed().clone();
+        add_extended_error(&mut packet, request, ExtendedDnsError::Other, "Forced fresh lookup.");
+        packet.build_bytes_vec().unwrap()
+    }
+
+    /// Checks the shared cache and copies a fresh item into the local cache.
+    async fn lookup_shared_cache(&mut self, pubkey: &PublicKey, (min_ttl, max_ttl): (u64, u64)) -> Option<CacheItem> {
+        let shared_cache = self.shared_cache.as_ref()?;
+        let item = shared_cache.get(pubkey).await?;
+        if item.next_refresh_needed_in_s(min_ttl, max_ttl) == 0 {
+            return None;
+        }
+        if let CacheItem::Packet { packet, .. } = &item {
+            if !self.check_record_limit(packet) {
+                return None;
+            }
+        }
+        tracing::trace!("Pkarr packet [{pubkey}] found in the shared cache.");
+        Some(self.cache.add_cached_item(item).await)
+    }
+
+    /// Per-key lock that serializes the DHT lookups of a public key.
+    async fn key_lock(&self, pubkey: &PublicKey) -> Arc<Mutex<()>> {
         let mut locked_map = self.lock_map.lock().await;
-        let mutex = locked_map
+        locked_map
             .entry(pubkey.clone())
-            .or_insert_with(|| Arc::new(Mutex::new(())));
-        let _guard = mutex.lock().await;
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone()
+    }
+
+    /// Lookup DHT to pull pkarr packet. Will not check the cache first but store any new value in the cache. Returns cached value if lookup fails.
+    async fn lookup_dht_and_cache(
+        &mut self,
+        pubkey: PublicKey,
+        bounds: (u64, u64),
+    ) -> Result<CacheItem, PkarrResolverError> {
+        let mutex = self.key_lock(&pubkey).await;
+
+        let is_debug_key = self.settings.debug_keys.contains(&pubkey);
+        let wait_start = Instant::now();
+        let lock_timeout = Duration::from_millis(self.settings.lock_timeout_ms);
+        let lock_result = tokio::time::timeout(lock_timeout, mutex.lock()).await;
+        METRICS.pkarr_lock_wait_seconds.observe(wait_start.elapsed());
+        if is_debug_key {
+            tracing::trace!(target: DEBUG_KEYS_TARGET, "Debug key [{pubkey}] waited {:?} on the lock.", wait_start.elapsed());
+        }
+        let _guard = match lock_result {
+            Ok(guard) => guard,
+            Err(_) => {
+                METRICS.pkarr_lock_timeouts.inc();
+                tracing::debug!("Timeout while waiting on the lock for [{pubkey}].");
+                return match self.cache.get(&pubkey).await {
+                    Some(stale) => Ok(stale),
+                    None => Err(PkarrResolverError::LockTimeout(pubkey)),
+                };
+            }
+        };
+        self.lookup_dht_and_cache_locked(pubkey, bounds).await
+    }
 
+    /// Same as `lookup_dht_and_cache` but expects the caller to hold the key lock.
+    async fn lookup_dht_and_cache_locked(
+        &mut self,
+        pubkey: PublicKey,
+        (min_ttl, max_ttl): (u64, u64),
+    ) -> Result<CacheItem, PkarrResolverError> {
+        let is_debug_key = self.settings.debug_keys.contains(&pubkey);
         if let Some(cache) = self.cache.get(&pubkey).await {
-            if !self.is_refresh_needed(&cache) {
+            if cache.next_refresh_needed_in_s(min_ttl, max_ttl) > 0 {
                 // Value got updated in the meantime while aquiring the lock.
                 tracing::trace!("Refresh for [{pubkey}] not needed. Value got updated in the meantime.");
                 return Ok(cache);
             }
         }
 
-        tracing::trace!("Lookup [{pubkey}] on the DHT.");
-        let signed_packet = self.client.resolve(&pubkey).await?;
-        if signed_packet.is_none() {
-            tracing::debug!("DHT lookup for [{pubkey}] failed. Nothing found.");
-            return Ok(self.cache.add_not_found(pubkey).await);
+        let parent_packet = self
+            .lookup_parent(&pubkey)
+            .await
+            .filter(|packet| self.check_record_limit(packet));
+        let signed_packet = match parent_packet {
+            Some(packet) => Some(packet),
+            None => self
+                .lookup_dht_with_fallback(&pubkey, is_debug_key)
+                .await?
+                .filter(|packet| self.check_record_limit(packet)),
+        };
+        let item = match signed_packet {
+            Some(new_packet) => {
+                tracing::trace!("Refreshed cache for [{pubkey}].");
+                self.cache.add_packet(new_packet).await
+            }
+            None => {
+                tracing::debug!("DHT lookup for [{pubkey}] failed. Nothing found.");
+                self.cache.add_not_found(pubkey).await
+            }
         };
 
-        tracing::trace!("Refreshed cache for [{pubkey}].");
-        let new_packet = signed_packet.unwrap();
-        Ok(self.cache.add_packet(new_packet).await)
+        if let Some(shared_cache) = &self.shared_cache {
+            shared_cache.put(&item).await;
+        }
+        Ok(item)
     }
 
-    fn remove_tld_if_necessary(&self, mut query: &mut Packet<'_>) -> bool {
-        if let Some(tld) = &self.settings.top_level_domain {
-            if tld.question_ends_with_pubkey_tld(&query) {
-                tld.remove(query);
-                return true;
+    /// Packet of the parent resolver. None if there is no parent, it doesn't know the key or it failed.
+    async fn lookup_parent(&self, pubkey: &PublicKey) -> Option<SignedPacket> {
+        let parent = self.settings.parent_resolver.as_ref()?;
+        match parent.resolve(pubkey).await {
+            Ok(Some(packet)) => {
+                tracing::trace!("Pkarr packet [{pubkey}] found on the parent resolver.");
+                Some(packet)
+            }
+            Ok(None) => {
+                tracing::trace!("Parent resolver has no packet for [{pubkey}].");
+                None
+            }
+            Err(err) => {
+                tracing::debug!("Parent resolver failed for [{pubkey}]. {err}");
+                None
             }
         }
-        return false;
     }
 
-    fn add_tld_if_necessary(&self, mut reply: &mut Packet<'_>) -> bool {
-        if let Some(tld) = &self.settings.top_level_domain {
-            tld.add(reply);
-            return true;
+    /// Packet from the DHT. Asks the fallback relays if the DHT has none or fails.
+    async fn lookup_dht_with_fallback(
+        &self,
+        pubkey: &PublicKey,
+        is_debug_key: bool,
+    ) -> Result<Option<SignedPacket>, PkarrResolverError> {
+        tracing::trace!("Lookup [{pubkey}] on the DHT.");
+        let lookup_start = Instant::now();
+        let signed_packet = self.client.resolve(pubkey).await;
+        if let Ok(Some(_)) = signed_packet {
+            self.dht_responses.fetch_add(1, Ordering::Relaxed);
+        }
+        if is_debug_key {
+            tracing::trace!(
+                target: DEBUG_KEYS_TARGET,
+                "Debug key [{pubkey}] DHT lookup took {:?}. Found: {}",
+                lookup_start.elapsed(),
+                matches!(signed_packet, Ok(Some(_)))
+            );
         }
-        return false;
+        let signed_packet = match (signed_packet, &self.settings.relay_fallback) {
+            (Ok(Some(packet)), _) => Some(packet),
+            (dht_result, Some(relays)) => match relays.resolve(pubkey).await {
+                Ok(Some(packet)) => {
+                    tracing::trace!("Pkarr packet [{pubkey}] found on a fallback relay.");
+                    Some(packet)
+                }
+                Ok(None) => None,
+                Err(err) => {
+                    tracing::debug!("Relay fallback for [{pubkey}] failed. {err}");
+                    dht_result?
+                }
+            },
+            (dht_result, None) => dht_result?,
+        };
+        Ok(signed_packet)
+    }
+
+    /// Removes the tld from the query if the question ends with one of the configured tlds.
+    /// Returns the removed tld.
+    fn remove_tld_if_necessary(&self, query: &mut Packet<'_>) -> Option<TopLevelDomain> {
+        let override_tlds = self.settings.tld_overrides.iter().map(|settings| &settings.tld);
+        let tld = self
+            .settings
+            .top_level_domain
+            .iter()
+            .chain(override_tlds)
+            .find(|tld| tld.question_ends_with_pubkey_tld(query))?
+            .clone();
+        tld.remove(query);
+        Some(tld)
     }
 
     /**
-     * Resolves a domain with pkarr.
+     * Resolves a domain with pkarr. Fails with `RecordBudgetExceeded` as soon as the reply collects
+     * more than `max_records`. None = unlimited.
      */
     pub async fn resolve(
         &mut self,
         query: &ParsedQuery,
         from: Option<IpAddr>,
+        max_records: Option<usize>,
     ) -> std::prelude::v1::Result<Vec<u8>, CustomHandlerError> {
+        if let Some(reserved) = query.question().qname.get_labels().last() {
+            let reserved = reserved.to_string().to_lowercase();
+            if self.settings.reserved_tlds.contains(&reserved) {
+                return Err(CustomHandlerError::ReservedTld(reserved));
+            }
+        }
+
         let mut request = query.packet.parsed().clone();
-        let mut removed_tld = self.remove_tld_if_necessary(&mut request);
-        if removed_tld {
+        let tld = self.settings.top_level_domain.clone();
+        let dname_rewrite = self
+            .settings
+            .dname_parents
+            .iter()
+            .find_map(|parent| Some((parent.clone(), parent.rewrite(&mut request, tld.as_ref())?)));
+        let fresh = self.settings.allow_fresh_lookups && query.requests_fresh_lookup();
+        let reply = self.resolve_request(request, from, fresh, max_records).await?;
+
+        match dname_rewrite {
+            Some((parent, target)) => {
+                tracing::trace!("Synthesized DNAME for {query}.");
+                Ok(parent.add_records(&reply, query.question(), &target, tld.as_ref()))
+            }
+            None => Ok(reply),
+        }
+    }
+
+    /**
+     * Resolves a request that is not below a DNAME parent.
+     */
+    async fn resolve_request(
+        &mut self,
+        mut request: Packet<'_>,
+        from: Option<IpAddr>,
+        fresh: bool,
+        max_records: Option<usize>,
+    ) -> Result<Vec<u8>, CustomHandlerError> {
+        let original_question = request
+            .questions
+            .first()
+            .expect("No question in query in pkarr_resolver.")
+            .clone()
+            .into_owned();
+        let removed_tld = self.remove_tld_if_necessary(&mut request);
+        if removed_tld.is_some() {
             tracing::trace!("Removed tld from question: {:?}", request.questions.first().unwrap());
         }
 
@@ -266,46 +927,165 @@ impl PkarrResolver {
                     tracing::trace!("TLD .{public_key} is a pkarr key but its last bits are invalid.");
                     Ok(create_domain_not_found_reply(request.id()))
                 }
+                super::pubkey_parser::PubkeyParserError::Unsupported(err) => {
+                    tracing::trace!("TLD .{public_key} is not a supported key type. {err}");
+                    Err(CustomHandlerError::UnsupportedKey(public_key))
+                }
             };
         }
 
         let pubkey = parsed_option.unwrap();
+        if let Some(top_keys) = &self.top_keys {
+            top_keys.record(&pubkey);
+        }
 
-        match self.resolve_pubkey_respect_cache(&pubkey, from).await {
+        let is_debug_key = self.settings.debug_keys.contains(&pubkey);
+        if is_debug_key {
+            tracing::trace!(target: DEBUG_KEYS_TARGET, "Debug key [{pubkey}] query {question:?} from {from:?}.");
+        }
+
+        let ttl_bounds = self.ttl_bounds(removed_tld.as_ref());
+        let resolve_start = Instant::now();
+        let result = self
+            .resolve_pubkey_respect_cache(&pubkey, from, ttl_bounds, fresh)
+            .await;
+        if is_debug_key {
+            match &result {
+                Ok(item) => tracing::trace!(
+                    target: DEBUG_KEYS_TARGET,
+                    "Debug key [{pubkey}] resolved in {:?}. Not found: {}",
+                    resolve_start.elapsed(),
+                    item.not_found()
+                ),
+                Err(err) => tracing::trace!(
+                    target: DEBUG_KEYS_TARGET,
+                    "Debug key [{pubkey}] failed after {:?}. {err}",
+                    resolve_start.elapsed()
+                ),
+            };
+        }
+
+        match result {
             Ok(item) => {
                 if item.not_found() {
-                    return Ok(create_domain_not_found_reply(request.id()));
+                    let reply = create_domain_not_found_reply(request.id());
+                    return Ok(Self::mark_fresh_lookup(reply, &request, fresh));
                 };
+                self.check_staleness(&pubkey, &item);
 
                 let signed_packet = item.unwrap();
-                let packet = signed_packet.packet();
-                let reply = resolve_query(packet, &request).await;
+                if !fresh {
+                    if let Some(reply) = self
+                        .response_cache
+                        .get(&signed_packet, &original_question, request.id())
+                        .await
+                    {
+                        return Ok(reply);
+                    }
+                }
+                let apex = match &removed_tld {
+                    Some(tld) => format!("{}.{}", pubkey.to_z32(), tld.label()),
+                    None => pubkey.to_z32(),
+                };
+                let serial = (signed_packet.timestamp() / 1_000_000) as u32;
+                let truncated = self.truncate_records(signed_packet.packet());
+                let packet = truncated.as_ref().unwrap_or(signed_packet.packet());
+                let with_soa = self
+                    .settings
+                    .soa_template
+                    .add_to_packet(packet, &question, &apex, serial)
+                    .map_err(|err| CustomHandlerError::Failed(err.into()))?;
+                let packet = with_soa.as_ref().unwrap_or(packet);
+                let reply = resolve_query(
+                    packet,
+                    &request,
+                    self.settings.any_policy,
+                    self.settings.lenient_parsing,
+                    max_records,
+                )
+                .await
+                .map_err(|err| match err {
+                    ResolveQueryError::RecordBudgetExceeded => CustomHandlerError::RecordBudgetExceeded,
+                    err => CustomHandlerError::Failed(err.into()),
+                })?;
 
-                let reply = if removed_tld {
-                    let mut packet = Packet::parse(&reply).unwrap();
-                    self.add_tld_if_necessary(&mut packet);
-                    packet.build_bytes_vec().unwrap()
+                let reply = if let Some(tld) = removed_tld {
+                    let parsed = ParsedPacket::new(reply).map_err(|err| CustomHandlerError::Failed(err.into()))?;
+                    let mut packet = parsed.parsed().clone();
+                    if self.settings.fully_qualify_owner_names {
+                        tld.add_to_all_sections(&mut packet);
+                    } else {
+                        tld.add(&mut packet);
+                    }
+                    packet
+                        .build_bytes_vec()
+                        .map_err(|err| CustomHandlerError::Failed(err.into()))?
+                } else {
+                    reply
+                };
+                let (reply, all_filtered) = if self.settings.block_private_ips {
+                    Self::filter_private_ips(reply).map_err(|err| CustomHandlerError::Failed(err.into()))?
+                } else {
+                    (reply, false)
+                };
+                if all_filtered {
+                    // The name exists but has nothing left to answer. NODATA, not NXDOMAIN.
+                    tracing::trace!("All answers for {question:?} got filtered out.");
+                    METRICS.all_answers_filtered.inc();
+                }
+                let reply = if self.settings.nodata_soa || all_filtered {
+                    self.add_negative_soa(reply, &signed_packet, &apex, serial)
+                        .map_err(|err| CustomHandlerError::Failed(err.into()))?
                 } else {
                     reply
                 };
-                Ok(reply)
+                self.response_cache
+                    .add(&signed_packet, &original_question, &reply)
+                    .await;
+                Ok(Self::mark_fresh_lookup(reply, &request, fresh))
             }
             Err(err) => Err(err),
         }
     }
 }
 
+/// A or AAAA record with an address that is not reachable on the public internet.
+fn is_private_record(record: &ResourceRecord<'_>) -> bool {
+    match &record.rdata {
+        RData::A(a) => {
+            let ip = Ipv4Addr::from(a.address);
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+        }
+        RData::AAAA(aaaa) => {
+            let ip = Ipv6Addr::from(aaaa.address);
+            let is_unique_local = (ip.segments()[0] & 0xfe00) == 0xfc00;
+            let is_link_local = (ip.segments()[0] & 0xffc0) == 0xfe80;
+            ip.is_loopback() || ip.is_unspecified() || is_unique_local || is_link_local
+        }
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use chrono::{DateTime, Utc};
     use pkarr::{
-        dns::{Name, Packet, Question, ResourceRecord},
+        dns::{
+            rdata::{RData, SOA},
+            Name, Packet, Question, ResourceRecord,
+        },
         Keypair, Settings, SignedPacket,
     };
 
     // use pkarr::dns::{Name, Question, Packet};
     use super::*;
-    use std::net::Ipv4Addr;
+    use crate::resolution::pkd::{read_packet_dir, MockDht, MockSharedCache};
+    use crate::resolution::test_helpers::{a_query, apex_a_packet, parent_relay};
+    use std::{
+        net::Ipv4Addr,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+    use tracing_test::traced_test;
     use zbase32;
 
     trait SignedPacketTimestamp {
@@ -357,6 +1137,629 @@ mod tests {
         result.expect("Should have published.");
     }
 
+    /// Resolver with a slow mock DHT and a short lock timeout so concurrent lookups contend for the lock.
+    fn contended_resolver(dht: MockDht) -> PkarrResolver {
+        let mut settings = ResolverSettings::default();
+        settings.lock_timeout_ms = 50;
+        PkarrResolver::with_backend(settings, Box::new(dht.with_delay(Duration::from_millis(500))))
+    }
+
+    async fn lookup_concurrently(resolver: &PkarrResolver, pubkey: &PublicKey, count: usize) -> Vec<bool> {
+        let handles: Vec<_> = (0..count)
+            .map(|_| {
+                let mut resolver = resolver.clone();
+                let pubkey = pubkey.clone();
+                let ttl_bounds = resolver.ttl_bounds(None);
+                tokio::spawn(async move { resolver.lookup_dht_and_cache(pubkey, ttl_bounds).await.is_ok() })
+            })
+            .collect();
+        let mut results = vec![];
+        for handle in handles {
+            results.push(handle.await.unwrap());
+        }
+        results
+    }
+
+    #[tokio::test]
+    async fn lock_timeout_without_cache() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let resolver = contended_resolver(dht.clone());
+
+        let waits_before = METRICS.pkarr_lock_wait_seconds.count();
+        let timeouts_before = METRICS.pkarr_lock_timeouts.get();
+        let results = lookup_concurrently(&resolver, &keypair.public_key(), 10).await;
+
+        // One lookup holds the lock and queries the DHT. All others give up.
+        assert_eq!(dht.lookup_count(), 1);
+        assert_eq!(results.iter().filter(|ok| **ok).count(), 1);
+        assert!(METRICS.pkarr_lock_wait_seconds.count() - waits_before >= 10);
+        assert!(METRICS.pkarr_lock_timeouts.get() - timeouts_before >= 9);
+    }
+
+    #[tokio::test]
+    async fn lock_timeout_serves_stale_cache() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut resolver = contended_resolver(dht.clone());
+        resolver.settings.max_ttl = 0; // Every cached packet is outdated immediately.
+        resolver.cache.add_packet(apex_a_packet(&keypair)).await;
+
+        let results = lookup_concurrently(&resolver, &keypair.public_key(), 10).await;
+
+        assert_eq!(dht.lookup_count(), 1);
+        assert!(results.iter().all(|ok| *ok));
+    }
+
+    fn apex_a_query(domain: &str) -> ParsedQuery {
+        ParsedQuery::new(a_query(domain).build_bytes_vec().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tld_override_max_ttl() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut settings = ResolverSettings::default();
+        settings.tld_overrides = vec![TldSettings {
+            tld: TopLevelDomain::new("pkd".to_string()),
+            min_ttl: Some(0),
+            max_ttl: Some(0), // Always refresh names under .pkd.
+        }];
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht.clone()));
+
+        let key_query = apex_a_query(&format!("{}.key", keypair.to_z32()));
+        resolver.resolve(&key_query, None, None).await.unwrap();
+        resolver.resolve(&key_query, None, None).await.unwrap();
+        assert_eq!(
+            dht.lookup_count(),
+            1,
+            ".key uses the default max_ttl and is served from the cache."
+        );
+
+        let pkd_query = apex_a_query(&format!("{}.pkd", keypair.to_z32()));
+        let reply = resolver.resolve(&pkd_query, None, None).await.unwrap();
+        resolver.resolve(&pkd_query, None, None).await.unwrap();
+        assert_eq!(
+            dht.lookup_count(),
+            3,
+            ".pkd has a max_ttl of 0 and refreshes every time."
+        );
+
+        let reply = Packet::parse(&reply).unwrap();
+        let answer = reply.answers.first().unwrap();
+        assert_eq!(answer.name.to_string(), format!("{}.pkd", keypair.to_z32()));
+    }
+
+    #[tokio::test]
+    async fn local_miss_served_from_shared_cache() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        let shared_cache = MockSharedCache::new();
+        shared_cache.put(&CacheItem::new_packet(apex_a_packet(&keypair))).await;
+        let mut resolver = PkarrResolver::with_backend(ResolverSettings::default(), Box::new(dht.clone()))
+            .with_shared_cache(Box::new(shared_cache));
+
+        let reply = resolver
+            .resolve(&apex_a_query(&keypair.to_z32()), None, None)
+            .await
+            .unwrap();
+
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(dht.lookup_count(), 0);
+        assert!(resolver.cache.get(&keypair.public_key()).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn dht_lookup_written_to_shared_cache() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let shared_cache = MockSharedCache::new();
+        let mut resolver = PkarrResolver::with_backend(ResolverSettings::default(), Box::new(dht.clone()))
+            .with_shared_cache(Box::new(shared_cache.clone()));
+
+        resolver
+            .resolve(&apex_a_query(&keypair.to_z32()), None, None)
+            .await
+            .unwrap();
+
+        assert_eq!(dht.lookup_count(), 1);
+        assert!(shared_cache.contains(&keypair.public_key()));
+    }
+
+    #[tokio::test]
+    async fn cache_miss_answered_by_parent() {
+        let keypair = Keypair::random();
+        let other = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&other));
+        let mut settings = ResolverSettings::default();
+        let parent = parent_relay(apex_a_packet(&keypair)).await;
+        settings.parent_resolver = Some(ParentResolver::new(
+            parent,
+            Duration::from_secs(1),
+            Duration::from_secs(60),
+        ));
+        let shared_cache = MockSharedCache::new();
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht.clone()))
+            .with_shared_cache(Box::new(shared_cache.clone()));
+
+        let reply = resolver
+            .resolve(&apex_a_query(&keypair.to_z32()), None, None)
+            .await
+            .unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(dht.lookup_count(), 0);
+        assert!(resolver.cache.get(&keypair.public_key()).await.is_some());
+        assert!(shared_cache.contains(&keypair.public_key()));
+
+        // The parent doesn't know the other key. The DHT is the final fallback.
+        let reply = resolver
+            .resolve(&apex_a_query(&other.to_z32()), None, None)
+            .await
+            .unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(dht.lookup_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn local_packet_served_without_dht() {
+        let keypair = Keypair::random();
+        let dir = std::env::temp_dir().join(format!("pkdns-local-packets-{}", keypair.to_z32()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("packet.bin"), apex_a_packet(&keypair).as_bytes()).unwrap();
+        std::fs::write(dir.join("invalid.bin"), b"not a packet").unwrap();
+
+        let packets = read_packet_dir(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(packets.len(), 1);
+
+        let dht = MockDht::new();
+        let mut resolver = PkarrResolver::with_backend(ResolverSettings::default(), Box::new(dht.clone()));
+        for packet in packets {
+            resolver.add_local_packet(packet);
+        }
+        let reply = resolver
+            .resolve(&apex_a_query(&keypair.to_z32()), None, None)
+            .await
+            .unwrap();
+
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(dht.lookup_count(), 0);
+    }
+
+    fn apex_soa_query(domain: &str) -> ParsedQuery {
+        let mut query = Packet::new_query(0);
+        query.questions.push(Question::new(
+            Name::new(domain).unwrap(),
+            pkarr::dns::QTYPE::TYPE(pkarr::dns::TYPE::SOA),
+            pkarr::dns::QCLASS::CLASS(pkarr::dns::CLASS::IN),
+            false,
+        ));
+        ParsedQuery::new(query.build_bytes_vec().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn apex_soa_published_or_synthesized() {
+        let published = Keypair::random();
+        let mut packet = Packet::new_reply(0);
+        let published_soa = SOA {
+            mname: Name::new("ns.example.com").unwrap(),
+            rname: Name::new("admin.example.com").unwrap(),
+            serial: 42,
+            refresh: 1,
+            retry: 2,
+            expire: 3,
+            minimum: 4,
+        };
+        packet.answers.push(ResourceRecord::new(
+            Name::new(".").unwrap(),
+            pkarr::dns::CLASS::IN,
+            100,
+            RData::SOA(published_soa.clone()),
+        ));
+        let synthesized = Keypair::random();
+        let synthesized_packet = apex_a_packet(&synthesized);
+        let dht = MockDht::new();
+        dht.add_packet(SignedPacket::from_packet(&published, &packet).unwrap());
+        dht.add_packet(synthesized_packet.clone());
+        let mut resolver = PkarrResolver::with_backend(ResolverSettings::default(), Box::new(dht));
+
+        let reply = resolver
+            .resolve(&apex_soa_query(&published.to_z32()), None, None)
+            .await
+            .unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].rdata, RData::SOA(published_soa));
+
+        let domain = format!("{}.key", synthesized.to_z32());
+        let reply = resolver.resolve(&apex_soa_query(&domain), None, None).await.unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].name.to_string(), domain);
+        let RData::SOA(soa) = &reply.answers[0].rdata else {
+            panic!("Expected a SOA answer.");
+        };
+        assert_eq!(soa.mname.to_string(), domain);
+        assert_eq!(soa.rname.to_string(), format!("hostmaster.{domain}"));
+        assert_eq!(soa.serial, (synthesized_packet.timestamp() / 1_000_000) as u32);
+        assert_eq!(soa.minimum, 300);
+    }
+
+    #[tokio::test]
+    async fn reserved_tld_never_parsed_as_pkarr() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut settings = ResolverSettings::default();
+        // Even a top level domain configured as onion doesn't make it a public key domain.
+        settings.top_level_domain = Some(TopLevelDomain::new("onion".to_string()));
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht.clone()));
+
+        for domain in [
+            format!("{}.onion", keypair.to_z32()),
+            format!("sub.{}.ONION", keypair.to_z32()),
+        ] {
+            let result = resolver.resolve(&apex_a_query(&domain), None, None).await;
+            assert!(matches!(result, Err(CustomHandlerError::ReservedTld(tld)) if tld == "onion"));
+        }
+        assert_eq!(dht.lookup_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn stale_answer_counted() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        let mut settings = ResolverSettings::default();
+        settings.staleness_warn_s = 60;
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht.clone()));
+        let aged = CacheItem::Packet {
+            packet: apex_a_packet(&keypair),
+            last_updated_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() - 200,
+        };
+        resolver.cache.add_cached_item(aged).await;
+
+        let stale_before = METRICS.stale_answers_served.get();
+        resolver
+            .resolve(&apex_a_query(&keypair.to_z32()), None, None)
+            .await
+            .unwrap();
+
+        // Still within the ttl so no refresh, but older than the staleness threshold.
+        assert_eq!(dht.lookup_count(), 0);
+        assert!(METRICS.stale_answers_served.get() - stale_before >= 1);
+    }
+
+    #[tokio::test]
+    async fn denied_type_not_cached() {
+        let keypair = Keypair::random();
+        let mut packet = Packet::new_reply(0);
+        packet.answers.push(ResourceRecord::new(
+            Name::new(".").unwrap(),
+            pkarr::dns::CLASS::IN,
+            100,
+            RData::A(Ipv4Addr::new(127, 0, 0, 1).into()),
+        ));
+        packet.answers.push(ResourceRecord::new(
+            Name::new(".").unwrap(),
+            pkarr::dns::CLASS::IN,
+            100,
+            RData::TXT("hello".try_into().unwrap()),
+        ));
+        let signed_packet = SignedPacket::from_packet(&keypair, &packet).unwrap();
+        let dht = MockDht::new();
+        dht.add_packet(signed_packet.clone());
+        let mut settings = ResolverSettings::default();
+        settings.cacheable_types = CacheableTypes::Deny(HashSet::from([16]));
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht));
+
+        let query = |qtype: pkarr::dns::TYPE| {
+            let mut query = Packet::new_query(7);
+            query.questions.push(Question::new(
+                Name::new(&keypair.to_z32()).unwrap().into_owned(),
+                pkarr::dns::QTYPE::TYPE(qtype),
+                pkarr::dns::QCLASS::CLASS(pkarr::dns::CLASS::IN),
+                false,
+            ));
+            ParsedQuery::new(query.build_bytes_vec().unwrap()).unwrap()
+        };
+        let a_query = query(pkarr::dns::TYPE::A);
+        let txt_query = query(pkarr::dns::TYPE::TXT);
+        let first_a_reply = resolver.resolve(&a_query, None, None).await.unwrap();
+        let first_txt_reply = resolver.resolve(&txt_query, None, None).await.unwrap();
+
+        let a_question = a_query.question().clone();
+        let txt_question = txt_query.question().clone();
+        let cached = resolver.response_cache.get(&signed_packet, &a_question, 7).await;
+        assert_eq!(cached, Some(first_a_reply.clone()));
+        assert!(resolver
+            .response_cache
+            .get(&signed_packet, &txt_question, 7)
+            .await
+            .is_none());
+
+        // The cached reply and the re-run reply are the same.
+        assert_eq!(resolver.resolve(&a_query, None, None).await.unwrap(), first_a_reply);
+        assert_eq!(resolver.resolve(&txt_query, None, None).await.unwrap(), first_txt_reply);
+        assert!(resolver
+            .response_cache
+            .get(&signed_packet, &txt_question, 7)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn aaaa_on_a_only_name_nodata_with_soa() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut settings = ResolverSettings::default();
+        settings.nodata_soa = true;
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht));
+
+        let domain = format!("{}.key", keypair.to_z32());
+        let mut query = Packet::new_query(0);
+        query.questions.push(Question::new(
+            Name::new(&domain).unwrap(),
+            pkarr::dns::QTYPE::TYPE(pkarr::dns::TYPE::AAAA),
+            pkarr::dns::QCLASS::CLASS(pkarr::dns::CLASS::IN),
+            false,
+        ));
+        let query = ParsedQuery::new(query.build_bytes_vec().unwrap()).unwrap();
+        let reply = resolver.resolve(&query, None, None).await.unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert!(reply.answers.is_empty());
+        assert_eq!(reply.name_servers.len(), 1);
+        let soa = &reply.name_servers[0];
+        assert_eq!(soa.name.to_string(), domain);
+        assert!(matches!(&soa.rdata, RData::SOA(soa) if soa.mname.to_string() == domain));
+
+        // Existing records are still answered normally.
+        let reply = resolver.resolve(&apex_a_query(&domain), None, None).await.unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.answers.len(), 1);
+        assert!(reply.name_servers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn prefetch_refreshes_due_packets() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut settings = ResolverSettings::default();
+        settings.min_ttl = 0;
+        settings.max_ttl = 0;
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht.clone()));
+        resolver
+            .resolve(&apex_a_query(&keypair.to_z32()), None, None)
+            .await
+            .unwrap();
+        assert_eq!(dht.lookup_count(), 1);
+
+        assert_eq!(resolver.prefetch_expired().await, 1);
+        assert_eq!(dht.lookup_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn prefetch_skips_locked_keys() {
+        let keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&keypair));
+        let mut settings = ResolverSettings::default();
+        settings.min_ttl = 0;
+        settings.max_ttl = 0;
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht.clone()));
+        resolver
+            .resolve(&apex_a_query(&keypair.to_z32()), None, None)
+            .await
+            .unwrap();
+
+        let mutex = resolver.key_lock(&keypair.public_key()).await;
+        let guard = mutex.lock().await;
+        assert_eq!(resolver.prefetch_expired().await, 0);
+        assert_eq!(dht.lookup_count(), 1);
+
+        drop(guard);
+        assert_eq!(resolver.prefetch_expired().await, 1);
+    }
+
+    #[tokio::test]
+    async fn all_private_answers_filtered_nodata() {
+        let keypair = Keypair::random();
+        let mut packet = Packet::new_reply(0);
+        for ip in ["192.168.1.1", "10.0.0.1"] {
+            let ip: Ipv4Addr = ip.parse().unwrap();
+            packet.answers.push(ResourceRecord::new(
+                Name::new("home").unwrap(),
+                pkarr::dns::CLASS::IN,
+                100,
+                RData::A(ip.into()),
+            ));
+        }
+        let dht = MockDht::new();
+        dht.add_packet(SignedPacket::from_packet(&keypair, &packet).unwrap());
+        let domain = format!("home.{}", keypair.to_z32());
+
+        let mut resolver = PkarrResolver::with_backend(ResolverSettings::default(), Box::new(dht.clone()));
+        let reply = resolver.resolve(&apex_a_query(&domain), None, None).await.unwrap();
+        assert_eq!(Packet::parse(&reply).unwrap().answers.len(), 2);
+
+        let mut settings = ResolverSettings::default();
+        settings.block_private_ips = true;
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht));
+        let filtered_before = METRICS.all_answers_filtered.get();
+        let reply = resolver.resolve(&apex_a_query(&domain), None, None).await.unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert!(reply.answers.is_empty());
+        assert_eq!(reply.name_servers.len(), 1);
+        assert!(matches!(reply.name_servers[0].rdata, RData::SOA(_)));
+        assert!(METRICS.all_answers_filtered.get() > filtered_before);
+    }
+
+    #[tokio::test]
+    async fn records_per_packet_limited() {
+        let keypair = Keypair::random();
+        let mut packet = Packet::new_reply(0);
+        for i in 0..10 {
+            packet.answers.push(ResourceRecord::new(
+                Name::new(".").unwrap(),
+                pkarr::dns::CLASS::IN,
+                100,
+                RData::A(Ipv4Addr::new(93, 184, 216, i).into()),
+            ));
+        }
+        let dht = MockDht::new();
+        dht.add_packet(SignedPacket::from_packet(&keypair, &packet).unwrap());
+        let domain = keypair.to_z32();
+
+        let mut settings = ResolverSettings::default();
+        settings.max_records_per_packet = 3;
+        let mut resolver = PkarrResolver::with_backend(settings.clone(), Box::new(dht.clone()));
+        let oversized_before = METRICS.oversized_packets.get();
+        let reply = resolver.resolve(&apex_a_query(&domain), None, None).await.unwrap();
+        assert_eq!(Packet::parse(&reply).unwrap().answers.len(), 3);
+        assert!(METRICS.oversized_packets.get() > oversized_before);
+
+        settings.oversized_packet_policy = OversizedPacketPolicy::Reject;
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht));
+        let reply = resolver.resolve(&apex_a_query(&domain), None, None).await.unwrap();
+        assert_eq!(Packet::parse(&reply).unwrap().rcode(), RCODE::NameError);
+    }
+
+    #[tokio::test]
+    async fn oversized_packets_rejected_on_every_ingest() {
+        let keypair = Keypair::random();
+        let mut packet = Packet::new_reply(0);
+        for i in 0..10 {
+            packet.answers.push(ResourceRecord::new(
+                Name::new(".").unwrap(),
+                pkarr::dns::CLASS::IN,
+                100,
+                RData::A(Ipv4Addr::new(93, 184, 216, i).into()),
+            ));
+        }
+        let packet = SignedPacket::from_packet(&keypair, &packet).unwrap();
+        let mut exporter = PkarrResolver::with_backend(ResolverSettings::default(), Box::new(MockDht::new()));
+        exporter.publish_signed_packet(packet.clone(), None).await.unwrap();
+        let state = exporter.export_cache();
+
+        let mut settings = ResolverSettings::default();
+        settings.max_records_per_packet = 3;
+        settings.oversized_packet_policy = OversizedPacketPolicy::Reject;
+        let dht = MockDht::new();
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht.clone()));
+
+        let published = resolver.publish_signed_packet(packet.clone(), None).await;
+        assert!(matches!(published, Err(PkarrResolverError::TooManyRecords(_))));
+        assert!(!dht.contains(&keypair.public_key()));
+
+        assert!(!resolver.add_local_packet(packet.clone()));
+
+        let import = resolver.import_cache(&state, true).await.unwrap();
+        assert_eq!(import.loaded, 0);
+        assert_eq!(import.rejected, 1);
+        assert!(resolver.cache.get(&keypair.public_key()).await.is_none());
+
+        // Counted once on the first ingest only.
+        assert!(!resolver.count_oversized(&packet));
+        let newer = SignedPacket::from_packet(&keypair, packet.packet()).unwrap();
+        assert!(resolver.count_oversized(&newer));
+    }
+
+    #[tokio::test]
+    async fn delegation_owner_names_fully_qualified() {
+        let keypair = Keypair::random();
+        let ns_name = format!("ns.sub.{}", keypair.to_z32());
+        let mut packet = Packet::new_reply(0);
+        packet.answers.push(ResourceRecord::new(
+            Name::new("sub").unwrap(),
+            pkarr::dns::CLASS::IN,
+            100,
+            RData::NS(pkarr::dns::rdata::NS(Name::new(&ns_name).unwrap())),
+        ));
+        packet.answers.push(ResourceRecord::new(
+            Name::new("ns.sub").unwrap(),
+            pkarr::dns::CLASS::IN,
+            100,
+            RData::A(Ipv4Addr::new(93, 184, 216, 34).into()),
+        ));
+        let dht = MockDht::new();
+        dht.add_packet(SignedPacket::from_packet(&keypair, &packet).unwrap());
+        let domain = format!("www.sub.{}.key", keypair.to_z32());
+
+        let mut resolver = PkarrResolver::with_backend(ResolverSettings::default(), Box::new(dht.clone()));
+        let reply = resolver.resolve(&apex_a_query(&domain), None, None).await.unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(
+            reply.name_servers[0].name.to_string(),
+            format!("sub.{}", keypair.to_z32())
+        );
+
+        let mut settings = ResolverSettings::default();
+        settings.fully_qualify_owner_names = true;
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht));
+        let reply = resolver.resolve(&apex_a_query(&domain), None, None).await.unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.questions[0].qname.to_string(), domain);
+        assert_eq!(reply.name_servers.len(), 1);
+        assert_eq!(
+            reply.name_servers[0].name.to_string(),
+            format!("sub.{}.key", keypair.to_z32())
+        );
+        let RData::NS(ns) = &reply.name_servers[0].rdata else {
+            panic!("Expected a NS record.");
+        };
+        assert_eq!(ns.0.to_string(), format!("{ns_name}.key"));
+        // The glue still matches the delegation.
+        assert_eq!(reply.additional_records.len(), 1);
+        assert_eq!(reply.additional_records[0].name, ns.0);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn debug_key_traced() {
+        let debug_keypair = Keypair::random();
+        let other_keypair = Keypair::random();
+        let dht = MockDht::new();
+        dht.add_packet(apex_a_packet(&debug_keypair));
+        dht.add_packet(apex_a_packet(&other_keypair));
+        let mut settings = ResolverSettings::default();
+        settings.debug_keys.insert(debug_keypair.public_key());
+        let mut resolver = PkarrResolver::with_backend(settings, Box::new(dht));
+
+        resolver
+            .resolve(&apex_a_query(&other_keypair.to_z32()), None, None)
+            .await
+            .unwrap();
+        assert!(!logs_contain("Debug key"));
+
+        resolver
+            .resolve(&apex_a_query(&debug_keypair.to_z32()), None, None)
+            .await
+            .unwrap();
+        assert!(logs_contain(&format!("Debug key [{}] query", debug_keypair.to_z32())));
+        assert!(logs_contain(&format!(
+            "Debug key [{}] DHT lookup took",
+            debug_keypair.to_z32()
+        )));
+        assert!(logs_contain(&format!(
+            "Debug key [{}] resolved in",
+            debug_keypair.to_z32()
+        )));
+        assert!(!logs_contain(&format!("Debug key [{}]", other_keypair.to_z32())));
+    }
+
     #[tokio::test]
     async fn query_domain() {
         publish_record().await;
@@ -375,7 +1778,7 @@ mod tests {
         let query = ParsedQuery::new(query.build_bytes_vec().unwrap()).unwrap();
 
         let mut resolver = PkarrResolver::default().await;
-        let result = resolver.resolve(&query, None).await;
+        let result = resolver.resolve(&query, None, None).await;
         assert!(result.is_ok());
         let reply_bytes = result.unwrap();
         let reply = Packet::parse(&reply_bytes).unwrap();
@@ -403,7 +1806,7 @@ mod tests {
         query.questions.push(question);
         let query = ParsedQuery::new(query.build_bytes_vec().unwrap()).unwrap();
         let mut resolver = PkarrResolver::default().await;
-        let result = resolver.resolve(&query, None).await;
+        let result = resolver.resolve(&query, None, None).await;
         assert!(result.is_ok());
         let reply_bytes = result.unwrap();
         let reply = Packet::parse(&reply_bytes).unwrap();
@@ -428,7 +1831,7 @@ mod tests {
         query.questions.push(question);
         let query = ParsedQuery::new(query.build_bytes_vec().unwrap()).unwrap();
         let mut resolver = PkarrResolver::default().await;
-        let result = resolver.resolve(&query, None).await;
+        let result = resolver.resolve(&query, None, None).await;
         assert!(result.is_err());
     }
 
@@ -437,7 +1840,9 @@ mod tests {
         let pubkey = parse_pkarr_uri("7fmjpcuuzf54hw18bsgi3zihzyh4awseeuq5tmojefaezjbd64cy").unwrap();
 
         let mut resolver = PkarrResolver::default().await;
-        let _result = resolver.resolve_pubkey_respect_cache(&pubkey, None).await;
+        let _result = resolver
+            .resolve_pubkey_respect_cache(&pubkey, None, resolver.ttl_bounds(None), false)
+            .await;
         // assert!(result.is_some());
     }
 
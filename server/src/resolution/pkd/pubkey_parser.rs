@@ -6,6 +6,9 @@ pub enum PubkeyParserError {
     InvalidKey(String),
     #[error("Key is valid zbase32 and length but the last bits are incorrect.")]
     ValidButDifferent,
+    /// Has the shape of a pkarr key but isn't an ed25519 public key, for example a key of another curve.
+    #[error("Public key type is not supported. {0}")]
+    Unsupported(String),
 }
 
 /// Parses a public key domain from it's zbase32 format.
@@ -29,5 +32,5 @@ pub fn parse_pkarr_uri(uri: &str) -> Result<PublicKey, PubkeyParserError> {
     }
 
     let trying: Result<PublicKey, pkarr::Error> = uri.try_into();
-    trying.map_err(|err| PubkeyParserError::InvalidKey(err.to_string()))
+    trying.map_err(|err| PubkeyParserError::Unsupported(err.to_string()))
 }
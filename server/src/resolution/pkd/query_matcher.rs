@@ -3,12 +3,24 @@ use std::{
     time::Duration,
 };
 
-use crate::resolution::DnsSocket;
+use crate::{
+    config::AnyPolicy,
+    resolution::{dns_packets::ParsedPacket, DnsSocket},
+};
 use pkarr::dns::{
     rdata::{self, RData},
-    Name, Packet, PacketFlag, Question, ResourceRecord, QTYPE, RCODE, TYPE,
+    Name, Packet, PacketFlag, Question, ResourceRecord, SimpleDnsError, CLASS, QCLASS, QTYPE, RCODE, TYPE,
 };
 
+#[derive(thiserror::Error, Debug)]
+pub enum ResolveQueryError {
+    #[error(transparent)]
+    Dns(#[from] SimpleDnsError),
+
+    #[error("Reply has more records than the query may still collect.")]
+    RecordBudgetExceeded,
+}
+
 /**
  * Handles all possible ways on how to resolve a query into a reply.
  * Does not support forwards, only recursive queries.
@@ -16,39 +28,125 @@ use pkarr::dns::{
  */
 
 /**
- * Uses a query to transforms a pkarr reply into an regular reply
+ * Uses a query to transforms a pkarr reply into an regular reply.
+ * Stops collecting records as soon as the reply has more than `max_records`. None = unlimited.
  */
-pub async fn resolve_query<'a>(pkarr_packet: &Packet<'a>, query: &Packet<'a>) -> Vec<u8> {
+pub async fn resolve_query<'a>(
+    pkarr_packet: &Packet<'a>,
+    query: &Packet<'a>,
+    any_policy: AnyPolicy,
+    lenient_parsing: bool,
+    max_records: Option<usize>,
+) -> Result<Vec<u8>, ResolveQueryError> {
     let question = query.questions.first().unwrap(); // Has at least 1 question based on previous checks.
-    let pkarr_reply = resolve_question(pkarr_packet, question).await;
-    let pkarr_reply = Packet::parse(&pkarr_reply).unwrap();
+    if !is_supported_qclass(&question.qclass) {
+        let mut reply = query.clone().into_reply();
+        *reply.rcode_mut() = RCODE::NotImplemented;
+        return Ok(reply.build_bytes_vec_compressed()?);
+    }
+    let mut pkarr_reply = resolve_question(pkarr_packet, question, max_records).await;
+    let unusable = match &pkarr_reply {
+        Ok(reply) => ParsedPacket::new(reply.clone()).is_err(),
+        Err(ResolveQueryError::Dns(_)) => true,
+        Err(ResolveQueryError::RecordBudgetExceeded) => false,
+    };
+    if lenient_parsing && unusable {
+        tracing::debug!("Pkarr reply can't be built or parsed again. Retry with the recoverable records only.");
+        let recovered = recoverable_records(pkarr_packet);
+        pkarr_reply = resolve_question(&recovered, question, max_records).await;
+    }
+    let pkarr_reply = pkarr_reply?;
+    let pkarr_reply = ParsedPacket::new(pkarr_reply)?;
+    let pkarr_reply = pkarr_reply.parsed().clone();
 
     let mut reply = query.clone().into_reply();
     reply.answers = pkarr_reply.answers;
+    if question.qtype == QTYPE::ANY && any_policy == AnyPolicy::Minimal {
+        keep_first_rrset(&mut reply.answers);
+    }
     reply.additional_records = pkarr_reply.additional_records;
     reply.name_servers = pkarr_reply.name_servers;
+    // Pkarr answers are not DNSSEC validated. Never claim authenticated data.
+    reply.remove_flags(PacketFlag::AUTHENTIC_DATA);
 
-    reply.build_bytes_vec_compressed().unwrap()
+    Ok(reply.build_bytes_vec_compressed()?)
+}
+
+/**
+ * Copy of the pkarr packet with only the records that survive being serialized and parsed again.
+ */
+fn recoverable_records<'a>(pkarr_packet: &Packet<'a>) -> Packet<'a> {
+    let mut recovered = Packet::new_reply(0);
+    recovered.answers = pkarr_packet
+        .answers
+        .iter()
+        .filter(|record| {
+            let mut single = Packet::new_reply(0);
+            single.answers.push((*record).clone());
+            single
+                .build_bytes_vec_compressed()
+                .is_ok_and(|bytes| Packet::parse(&bytes).is_ok())
+        })
+        .cloned()
+        .collect();
+    recovered
+}
+
+/**
+ * Pkarr records are of class IN. QCLASS ANY matches them too, all other classes are not implemented.
+ */
+fn is_supported_qclass(qclass: &QCLASS) -> bool {
+    matches!(qclass, QCLASS::ANY | QCLASS::CLASS(CLASS::IN))
+}
+
+/**
+ * Reduces the answers to the RRset of the first answer.
+ */
+fn keep_first_rrset(answers: &mut Vec<ResourceRecord<'_>>) {
+    if let Some(first) = answers.first() {
+        let name = first.name.clone();
+        let type_code = u16::from(first.rdata.type_code());
+        answers.retain(|answer| answer.name == name && u16::from(answer.rdata.type_code()) == type_code);
+    }
+}
+
+/**
+ * Errors if the reply has more records than `max_records`.
+ */
+fn check_record_budget(reply: &Packet<'_>, max_records: Option<usize>) -> Result<(), ResolveQueryError> {
+    let records = reply.answers.len() + reply.name_servers.len() + reply.additional_records.len();
+    match max_records {
+        Some(max) if records > max => Err(ResolveQueryError::RecordBudgetExceeded),
+        _ => Ok(()),
+    }
 }
 
 /**
  * Resolves a question by filtering the pkarr packet and creating a corresponding reply.
+ * Errors as soon as the collected records exceed `max_records`.
  */
-async fn resolve_question<'a>(pkarr_packet: &Packet<'a>, question: &Question<'a>) -> Vec<u8> {
+async fn resolve_question<'a>(
+    pkarr_packet: &Packet<'a>,
+    question: &Question<'a>,
+    max_records: Option<usize>,
+) -> Result<Vec<u8>, ResolveQueryError> {
     let mut reply = Packet::new_reply(0);
 
     let direct_matchs = direct_matches(pkarr_packet, &question.qname, &question.qtype);
     reply.answers.extend(direct_matchs.clone());
+    check_record_budget(&reply, max_records)?;
 
     if reply.answers.len() == 0 {
         // Not found. Maybe it is a cname?
         let cname_matches = resolve_cname_for(pkarr_packet, question);
         reply.answers.extend(cname_matches);
+        check_record_budget(&reply, max_records)?;
     };
 
     if reply.answers.len() == 0 {
         // Not found. Maybe we have a name server?
         reply.name_servers = find_nameserver(pkarr_packet, &question.qname);
+        check_record_budget(&reply, max_records)?;
 
         // Add all glued A/AAAA records to the additional section
         for ns in reply.name_servers.iter() {
@@ -58,11 +156,12 @@ async fn resolve_question<'a>(pkarr_packet: &Packet<'a>, question: &Question<'a>
                 let matches_aaaa = direct_matches(pkarr_packet, name, &QTYPE::TYPE(TYPE::AAAA));
                 let merged_matches: Vec<_> = matches_a.into_iter().chain(matches_aaaa.into_iter()).collect();
                 reply.additional_records.extend(merged_matches);
+                check_record_budget(&reply, max_records)?;
             };
         }
     };
 
-    reply.build_bytes_vec_compressed().unwrap()
+    Ok(reply.build_bytes_vec_compressed()?)
 }
 
 /**
@@ -93,17 +192,78 @@ fn resolve_cname_for<'a>(pkarr_packet: &Packet<'a>, question: &Question<'a>) ->
 
 /**
  * Resolve direct qname and qtype record matches.
+ * Falls back to the wildcard of the closest encloser if the qname doesn't exist (RFC 4592).
+ * Explicit names therefore always take precedence over wildcards.
  */
 fn direct_matches<'a>(pkarr_packet: &Packet<'a>, qname: &Name<'a>, qtype: &QTYPE) -> Vec<ResourceRecord<'a>> {
+    let owner = if name_exists(pkarr_packet, qname) {
+        qname.clone()
+    } else {
+        match find_wildcard(pkarr_packet, qname) {
+            Some(wildcard) => wildcard,
+            None => return vec![],
+        }
+    };
+
     let matches: Vec<ResourceRecord<'_>> = pkarr_packet
         .answers
         .iter()
-        .filter(|record| record.name == *qname && record.match_qtype(*qtype))
-        .map(|record| record.clone())
+        .filter(|record| record.name == owner && match_qtype(record, qtype))
+        .map(|record| {
+            // Wildcard records are synthesized with the qname as owner.
+            let mut record = record.clone();
+            record.name = qname.clone();
+            record
+        })
         .collect();
     matches
 }
 
+/**
+ * Checks if the name owns any records or is an empty non-terminal.
+ */
+fn name_exists(pkarr_packet: &Packet<'_>, name: &Name<'_>) -> bool {
+    pkarr_packet
+        .answers
+        .iter()
+        .any(|record| record.name == *name || record.name.is_subdomain_of(name))
+}
+
+/**
+ * Finds the wildcard `*.<closest encloser>` that synthesizes records for a non-existent qname (RFC 4592).
+ * None if the closest encloser has no wildcard.
+ */
+fn find_wildcard<'a>(pkarr_packet: &Packet<'a>, qname: &Name<'a>) -> Option<Name<'a>> {
+    let labels: Vec<String> = qname.get_labels().iter().map(|label| label.to_string()).collect();
+    let closest_encloser = (1..labels.len()).map(|i| labels[i..].join(".")).find(|ancestor| {
+        Name::new(ancestor)
+            .map(|ancestor| name_exists(pkarr_packet, &ancestor))
+            .unwrap_or(false)
+    })?;
+
+    let wildcard = format!("*.{closest_encloser}");
+    let wildcard = Name::new(&wildcard).ok()?.into_owned();
+    pkarr_packet
+        .answers
+        .iter()
+        .any(|record| record.name == wildcard)
+        .then_some(wildcard)
+}
+
+/**
+ * Matches the record type by its numeric type code. Record types pkdns doesn't know about
+ * are passed through verbatim as long as the qtype matches.
+ * ANY matches all records. Zone transfers are not supported and match nothing.
+ */
+fn match_qtype(record: &ResourceRecord<'_>, qtype: &QTYPE) -> bool {
+    match qtype {
+        QTYPE::TYPE(qtype) => u16::from(record.rdata.type_code()) == u16::from(*qtype),
+        QTYPE::ANY => true,
+        QTYPE::AXFR | QTYPE::IXFR => false,
+        QTYPE::MAILA | QTYPE::MAILB => record.match_qtype(*qtype),
+    }
+}
+
 /**
  * Find nameserver for given qname.
  */
@@ -206,14 +366,18 @@ pub fn create_domain_not_found_reply(query_id: u16) -> Vec<u8> {
 mod tests {
     use std::net::Ipv4Addr;
 
-    use crate::resolution::{pkd::PkarrResolver, DnsSocket};
-    use pkarr::dns::{rdata::RData, Question};
+    use crate::resolution::{pkd::PkarrResolver, DnsSocket, ParsedPacket};
+    use pkarr::dns::{
+        rdata::{RData, NULL},
+        Question, CLASS, QCLASS, QTYPE, RCODE, TYPE,
+    };
     use pkarr::{
         dns::{Name, Packet, ResourceRecord},
-        Keypair, PublicKey,
+        Keypair, PublicKey, SignedPacket,
     };
 
-    use super::{resolve_query, resolve_question};
+    use super::{resolve_query, resolve_question, ResolveQueryError};
+    use crate::config::AnyPolicy;
 
     async fn get_dnssocket() -> DnsSocket {
         DnsSocket::default_random_socket().await.unwrap()
@@ -284,7 +448,7 @@ mod tests {
         );
 
         let mut socket = get_dnssocket().await;
-        let reply = resolve_question(&pkarr_packet, &question).await;
+        let reply = resolve_question(&pkarr_packet, &question, None).await.unwrap();
         let reply = Packet::parse(&reply).unwrap();
         assert_eq!(reply.answers.len(), 1);
         assert_eq!(reply.additional_records.len(), 0);
@@ -294,6 +458,24 @@ mod tests {
         assert!(answer.match_qtype(qtype));
     }
 
+    #[tokio::test]
+    async fn record_budget_cuts_off_collection() {
+        let (pkarr_packet, pubkey) = example_pkarr_reply();
+        let pkarr_packet = Packet::parse(&pkarr_packet).unwrap();
+        let name = format!("www.pknames.p2p.{}", pubkey.to_z32());
+        let question = Question::new(
+            Name::new(&name).unwrap(),
+            pkarr::dns::QTYPE::TYPE(pkarr::dns::TYPE::A),
+            pkarr::dns::QCLASS::CLASS(pkarr::dns::CLASS::IN),
+            false,
+        );
+
+        // CNAME and its A record.
+        let cut_off = resolve_question(&pkarr_packet, &question, Some(1)).await;
+        assert!(matches!(cut_off, Err(ResolveQueryError::RecordBudgetExceeded)));
+        assert!(resolve_question(&pkarr_packet, &question, Some(2)).await.is_ok());
+    }
+
     #[tokio::test]
     async fn a_question_with_cname() {
         let (pkarr_packet, pubkey) = example_pkarr_reply();
@@ -311,7 +493,7 @@ mod tests {
         );
 
         let mut socket = get_dnssocket().await;
-        let reply = resolve_question(&pkarr_packet, &question).await;
+        let reply = resolve_question(&pkarr_packet, &question, None).await.unwrap();
         let reply = Packet::parse(&reply).unwrap();
         assert_eq!(reply.answers.len(), 2);
         assert_eq!(reply.additional_records.len(), 0);
@@ -342,7 +524,7 @@ mod tests {
             false,
         );
         let mut socket = get_dnssocket().await;
-        let reply = resolve_question(&pkarr_packet, &question).await;
+        let reply = resolve_question(&pkarr_packet, &question, None).await.unwrap();
         let reply = Packet::parse(&reply).unwrap();
         assert_eq!(reply.answers.len(), 0);
         assert_eq!(reply.additional_records.len(), 0);
@@ -370,7 +552,7 @@ mod tests {
         );
 
         let mut socket = get_dnssocket().await;
-        let reply = resolve_question(&pkarr_packet, &question).await;
+        let reply = resolve_question(&pkarr_packet, &question, None).await.unwrap();
         let reply = Packet::parse(&reply).unwrap();
         assert_eq!(reply.answers.len(), 0);
         assert_eq!(reply.additional_records.len(), 0);
@@ -381,6 +563,234 @@ mod tests {
         assert!(ns1.match_qtype(pkarr::dns::QTYPE::TYPE(pkarr::dns::TYPE::NS)));
     }
 
+    /// Signed pkarr packet with a record of a type pkdns doesn't know about.
+    fn unknown_type_packet(type_code: u16, data: &[u8]) -> SignedPacket {
+        let keypair = Keypair::random();
+        let mut packet = Packet::new_reply(0);
+        let pubkey_z32 = keypair.to_z32();
+        let name = Name::new(&pubkey_z32).unwrap();
+        let rdata = RData::NULL(type_code, NULL::new(data).unwrap());
+        packet
+            .answers
+            .push(ResourceRecord::new(name, pkarr::dns::CLASS::IN, 100, rdata));
+        SignedPacket::from_packet(&keypair, &packet).unwrap()
+    }
+
+    fn question_for(signed_packet: &SignedPacket, qtype: QTYPE) -> Question<'static> {
+        let name = Name::new(&signed_packet.public_key().to_z32()).unwrap().into_owned();
+        Question::new(name, qtype, QCLASS::CLASS(CLASS::IN), false)
+    }
+
+    fn wildcard_packet() -> SignedPacket {
+        let keypair = Keypair::random();
+        let mut packet = Packet::new_reply(0);
+        let wildcard_ip: Ipv4Addr = "1.1.1.1".parse().unwrap();
+        let explicit_ip: Ipv4Addr = "2.2.2.2".parse().unwrap();
+        packet.answers.push(ResourceRecord::new(
+            Name::new("*.foo").unwrap(),
+            CLASS::IN,
+            100,
+            RData::A(wildcard_ip.into()),
+        ));
+        packet.answers.push(ResourceRecord::new(
+            Name::new("bar.foo").unwrap(),
+            CLASS::IN,
+            100,
+            RData::A(explicit_ip.into()),
+        ));
+        SignedPacket::from_packet(&keypair, &packet).unwrap()
+    }
+
+    async fn resolve_a(signed_packet: &SignedPacket, subdomain: &str) -> Vec<Ipv4Addr> {
+        let name = format!("{subdomain}.{}", signed_packet.public_key().to_z32());
+        let name = Name::new(&name).unwrap();
+        let question = Question::new(name.clone(), QTYPE::TYPE(TYPE::A), QCLASS::CLASS(CLASS::IN), false);
+        let reply = resolve_question(signed_packet.packet(), &question, None).await.unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+        reply
+            .answers
+            .iter()
+            .map(|answer| {
+                assert_eq!(answer.name, name);
+                match &answer.rdata {
+                    RData::A(a) => Ipv4Addr::from(a.address),
+                    _ => panic!("Expected an A record."),
+                }
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn explicit_record_wins_over_wildcard() {
+        let signed_packet = wildcard_packet();
+        let explicit_ip: Ipv4Addr = "2.2.2.2".parse().unwrap();
+        let wildcard_ip: Ipv4Addr = "1.1.1.1".parse().unwrap();
+
+        assert_eq!(resolve_a(&signed_packet, "bar.foo").await, vec![explicit_ip]);
+        assert_eq!(resolve_a(&signed_packet, "baz.foo").await, vec![wildcard_ip]);
+        assert_eq!(resolve_a(&signed_packet, "sub.baz.foo").await, vec![wildcard_ip]);
+        // bar.foo is the closest encloser and has no wildcard.
+        assert!(resolve_a(&signed_packet, "sub.bar.foo").await.is_empty());
+    }
+
+    /// Resolves a query for the qtype that is parsed from the wire format like a client query.
+    async fn resolve_from_wire(signed_packet: &SignedPacket, qtype: QTYPE) -> ParsedPacket {
+        let mut query = Packet::new_query(0);
+        query.questions = vec![question_for(signed_packet, qtype)];
+        let query = ParsedPacket::new(query.build_bytes_vec().unwrap()).unwrap();
+        let reply = resolve_query(signed_packet.packet(), query.parsed(), AnyPolicy::Expand, false, None)
+            .await
+            .unwrap();
+        ParsedPacket::new(reply).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unknown_type_passthrough() {
+        let signed_packet = unknown_type_packet(65283, &[1, 2, 3, 4]);
+
+        let reply = resolve_from_wire(&signed_packet, QTYPE::TYPE(TYPE::Unknown(65283))).await;
+        let reply = reply.parsed();
+        assert_eq!(reply.questions[0].qtype, QTYPE::TYPE(TYPE::Unknown(65283)));
+        assert_eq!(reply.answers.len(), 1);
+        let answer = reply.answers.first().unwrap();
+        assert_eq!(u16::from(answer.rdata.type_code()), 65283);
+        if let RData::NULL(_, data) = &answer.rdata {
+            assert_eq!(data.get_data(), &[1, 2, 3, 4]);
+        } else {
+            panic!("Unknown record type should be passed through verbatim.");
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_type_different_qtype() {
+        let signed_packet = unknown_type_packet(65283, &[1, 2, 3, 4]);
+        let question = question_for(&signed_packet, QTYPE::TYPE(TYPE::Unknown(65284)));
+
+        let reply = resolve_question(signed_packet.packet(), &question, None).await.unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.answers.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn null_type_matched_by_type_code() {
+        // The parser turns NULL records into RData::NULL with the type TYPE::Unknown(10).
+        let signed_packet = unknown_type_packet(10, &[1, 2, 3, 4]);
+        let question = question_for(&signed_packet, QTYPE::TYPE(TYPE::NULL));
+
+        let reply = resolve_question(signed_packet.packet(), &question, None).await.unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.answers.len(), 1);
+    }
+
+    /// Packet with an A, a TXT and an unknown type record at the apex.
+    fn mixed_types_packet() -> SignedPacket {
+        let keypair = Keypair::random();
+        let mut packet = Packet::new_reply(0);
+        let name = Name::new(".").unwrap();
+        let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+        packet
+            .answers
+            .push(ResourceRecord::new(name.clone(), CLASS::IN, 100, RData::A(ip.into())));
+        let txt = pkarr::dns::rdata::TXT::new().with_string("hello").unwrap();
+        packet
+            .answers
+            .push(ResourceRecord::new(name.clone(), CLASS::IN, 100, RData::TXT(txt)));
+        let unknown = RData::NULL(65283, NULL::new(&[1, 2, 3, 4]).unwrap());
+        packet.answers.push(ResourceRecord::new(name, CLASS::IN, 100, unknown));
+        SignedPacket::from_packet(&keypair, &packet).unwrap()
+    }
+
+    async fn resolve_qtype(signed_packet: &SignedPacket, qtype: QTYPE, any_policy: AnyPolicy) -> Vec<u16> {
+        let mut query = Packet::new_query(0);
+        query.questions = vec![question_for(signed_packet, qtype)];
+        let reply = resolve_query(signed_packet.packet(), &query, any_policy, false, None)
+            .await
+            .unwrap();
+        let reply = Packet::parse(&reply).unwrap();
+        reply
+            .answers
+            .iter()
+            .map(|answer| u16::from(answer.rdata.type_code()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn any_query_policies() {
+        let signed_packet = mixed_types_packet();
+
+        let expanded = resolve_qtype(&signed_packet, QTYPE::ANY, AnyPolicy::Expand).await;
+        assert_eq!(expanded.len(), 3);
+
+        let minimal = resolve_qtype(&signed_packet, QTYPE::ANY, AnyPolicy::Minimal).await;
+        assert_eq!(minimal, vec![u16::from(TYPE::A)]);
+
+        // Zone transfers are not treated like ANY.
+        let axfr = resolve_qtype(&signed_packet, QTYPE::AXFR, AnyPolicy::Expand).await;
+        assert!(axfr.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_qtype_not_treated_as_any() {
+        let signed_packet = mixed_types_packet();
+
+        let reply = resolve_from_wire(&signed_packet, QTYPE::TYPE(TYPE::Unknown(65283))).await;
+        let reply = reply.parsed();
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(u16::from(reply.answers[0].rdata.type_code()), 65283);
+    }
+
+    async fn resolve_qclass(signed_packet: &SignedPacket, qclass: QCLASS) -> Vec<u8> {
+        let name = Name::new(&signed_packet.public_key().to_z32()).unwrap().into_owned();
+        let mut query = Packet::new_query(0);
+        query.questions = vec![Question::new(name, QTYPE::TYPE(TYPE::A), qclass, false)];
+        resolve_query(signed_packet.packet(), &query, AnyPolicy::Expand, false, None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn qclass_any_matches_in_records() {
+        let signed_packet = mixed_types_packet();
+
+        let reply = resolve_qclass(&signed_packet, QCLASS::ANY).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::NoError);
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].class, CLASS::IN);
+
+        let reply = resolve_qclass(&signed_packet, QCLASS::CLASS(CLASS::CH)).await;
+        let reply = Packet::parse(&reply).unwrap();
+        assert_eq!(reply.rcode(), RCODE::NotImplemented);
+        assert!(reply.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn lenient_parsing_recovers_records() {
+        // The NULL record is written as a CNAME with a forward compression pointer which can't be parsed again.
+        let mut pkarr_packet = Packet::new_reply(0);
+        let name = Name::new("example").unwrap();
+        let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+        pkarr_packet
+            .answers
+            .push(ResourceRecord::new(name.clone(), CLASS::IN, 100, RData::A(ip.into())));
+        let borderline = RData::NULL(u16::from(TYPE::CNAME), NULL::new(&[0xC0, 0xFF]).unwrap());
+        pkarr_packet
+            .answers
+            .push(ResourceRecord::new(name.clone(), CLASS::IN, 100, borderline));
+        let mut query = Packet::new_query(0);
+        query.questions = vec![Question::new(name, QTYPE::ANY, QCLASS::CLASS(CLASS::IN), false)];
+
+        let strict = resolve_query(&pkarr_packet, &query, AnyPolicy::Expand, false, None).await;
+        assert!(strict.is_err());
+
+        let lenient = resolve_query(&pkarr_packet, &query, AnyPolicy::Expand, true, None)
+            .await
+            .unwrap();
+        let reply = Packet::parse(&lenient).unwrap();
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.answers[0].rdata, RData::A(ip.into()));
+    }
+
     #[tokio::test]
     async fn simple_a_query() {
         let (pkarr_packet, _pubkey) = example_pkarr_reply();
@@ -395,6 +805,6 @@ mod tests {
         )];
 
         let mut socket = get_dnssocket().await;
-        let _reply = resolve_query(&pkarr_packet, &query);
+        let _reply = resolve_query(&pkarr_packet, &query, AnyPolicy::Expand, false, None);
     }
 }
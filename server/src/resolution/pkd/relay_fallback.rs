@@ -0,0 +1,272 @@
+use pkarr::{bytes::Bytes, PublicKey, SignedPacket};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Take},
+    net::TcpStream,
+};
+
+/// Upper limit of the status line and headers of a relay response.
+const MAX_HEADER_SIZE: usize = 4096;
+
+/// Relay payload: 64 bytes signature, 8 bytes timestamp and at most 1000 bytes of dns packet.
+const MAX_PAYLOAD_SIZE: usize = 1072;
+
+#[derive(thiserror::Error, Debug)]
+pub enum RelayError {
+    #[error(transparent)]
+    IO(#[from] tokio::io::Error),
+
+    #[error("Relay did not answer in time.")]
+    Timeout(#[from] tokio::time::error::Elapsed),
+
+    #[error("Relay answered with status {0}.")]
+    Status(u16),
+
+    #[error("Invalid relay response. {0}")]
+    InvalidResponse(&'static str),
+
+    #[error("Invalid signed packet from relay: {0}")]
+    InvalidPacket(#[from] pkarr::Error),
+
+    #[error("No relay set answered.")]
+    AllSetsFailed,
+
+    #[error("Relay failed recently and is skipped.")]
+    CoolingDown,
+}
+
+/**
+ * Pkarr relay reachable over plain HTTP, for example the relay listener of another pkdns instance
+ * in a private network.
+ */
+#[derive(Clone, Debug)]
+pub(super) struct HttpRelay {
+    /// host:port of the relay.
+    addr: String,
+}
+
+impl HttpRelay {
+    pub(super) fn new(addr: String) -> Self {
+        Self { addr }
+    }
+
+    /// Most recent packet of the public key. None if the relay doesn't know the key.
+    pub(super) async fn get(&self, pubkey: &PublicKey) -> Result<Option<SignedPacket>, RelayError> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        let request = format!(
+            "GET /{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            pubkey.to_z32(),
+            self.addr
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        // Never read more than the headers and one payload, no matter what the relay sends.
+        let mut stream = stream.take(MAX_HEADER_SIZE as u64);
+        let mut response = vec![];
+        let header_end = loop {
+            if let Some(position) = response.windows(4).position(|window| window == b"\r\n\r\n") {
+                break position;
+            }
+            let mut chunk = [0; 1024];
+            let read = stream.read(&mut chunk).await?;
+            if read == 0 {
+                return Err(RelayError::InvalidResponse("No end of the headers."));
+            }
+            response.extend_from_slice(&chunk[..read]);
+        };
+        let head = String::from_utf8_lossy(&response[..header_end]).to_lowercase();
+        let status: u16 = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or(RelayError::InvalidResponse("No status code."))?;
+        if head.contains("transfer-encoding: chunked") {
+            return Err(RelayError::InvalidResponse("Chunked bodies are not supported."));
+        }
+        match status {
+            200 => {
+                let body = read_body(&mut stream, response.split_off(header_end + 4), &head).await?;
+                Ok(Some(SignedPacket::from_relay_payload(pubkey, &Bytes::from(body))?))
+            }
+            404 => Ok(None),
+            status => Err(RelayError::Status(status)),
+        }
+    }
+}
+
+/// Reads the rest of the body. Stops at Content-Length and rejects bodies larger than a relay payload.
+async fn read_body(stream: &mut Take<TcpStream>, mut body: Vec<u8>, head: &str) -> Result<Vec<u8>, RelayError> {
+    let content_length = match head.lines().find_map(|line| line.strip_prefix("content-length:")) {
+        Some(value) => Some(
+            value
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| RelayError::InvalidResponse("Invalid Content-Length."))?,
+        ),
+        None => None,
+    };
+    if content_length.is_some_and(|length| length > MAX_PAYLOAD_SIZE) {
+        return Err(RelayError::InvalidResponse("Body too large."));
+    }
+
+    // One byte more than allowed so an oversized body without Content-Length is detected.
+    let wanted = content_length.unwrap_or(MAX_PAYLOAD_SIZE + 1);
+    stream.set_limit(wanted.saturating_sub(body.len()) as u64);
+    stream.read_to_end(&mut body).await?;
+    match content_length {
+        Some(length) if body.len() < length => Err(RelayError::InvalidResponse("Body shorter than Content-Length.")),
+        Some(length) => {
+            body.truncate(length);
+            Ok(body)
+        }
+        None if body.len() > MAX_PAYLOAD_SIZE => Err(RelayError::InvalidResponse("Body too large.")),
+        None => Ok(body),
+    }
+}
+
+/**
+ * Relays of one region. A set that failed completely is skipped for the cooldown
+ * so lookups go straight to the next set.
+ */
+#[derive(Clone, Debug)]
+pub struct RelaySet {
+    pub name: String,
+    relays: Vec<HttpRelay>,
+    /// When the set failed the last time. Shared by all clones.
+    failed_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl RelaySet {
+    /// Relays are tried in the given order. Each one is a host:port.
+    pub fn new(name: String, relays: Vec<String>) -> Self {
+        Self {
+            name,
+            relays: relays.into_iter().map(HttpRelay::new).collect(),
+            failed_at: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn is_healthy(&self, cooldown: Duration) -> bool {
+        match *self.failed_at.lock().unwrap() {
+            Some(failed_at) => failed_at.elapsed() >= cooldown,
+            None => true,
+        }
+    }
+
+    /// First answer of the relays in this set. Err if none of them answered.
+    async fn get(&self, pubkey: &PublicKey, timeout: Duration) -> Result<Option<SignedPacket>, RelayError> {
+        let mut last_error = RelayError::AllSetsFailed;
+        for relay in self.relays.iter() {
+            match tokio::time::timeout(timeout, relay.get(pubkey)).await {
+                Ok(Ok(packet)) => {
+                    *self.failed_at.lock().unwrap() = None;
+                    return Ok(packet);
+                }
+                Ok(Err(err)) => last_error = err,
+                Err(elapsed) => last_error = elapsed.into(),
+            }
+            tracing::debug!("Relay {} of set {} failed. {last_error}", relay.addr, self.name);
+        }
+        *self.failed_at.lock().unwrap() = Some(Instant::now());
+        Err(last_error)
+    }
+}
+
+/**
+ * Pkarr relays that are asked when the DHT has no packet or fails.
+ * Sets are ordered by preference, for example the nearest region first. Healthy sets are tried
+ * first, sets that failed within the cooldown only if all healthy sets fail too.
+ */
+#[derive(Clone, Debug)]
+pub struct RelayFallback {
+    sets: Vec<RelaySet>,
+    /// Timeout of a single relay request.
+    timeout: Duration,
+    /// How long a failed set is skipped.
+    cooldown: Duration,
+}
+
+impl RelayFallback {
+    pub fn new(sets: Vec<RelaySet>, timeout: Duration, cooldown: Duration) -> Self {
+        Self {
+            sets,
+            timeout,
+            cooldown,
+        }
+    }
+
+    pub async fn resolve(&self, pubkey: &PublicKey) -> Result<Option<SignedPacket>, RelayError> {
+        let (healthy, unhealthy): (Vec<&RelaySet>, Vec<&RelaySet>) =
+            self.sets.iter().partition(|set| set.is_healthy(self.cooldown));
+        for set in healthy.into_iter().chain(unhealthy) {
+            match set.get(pubkey, self.timeout).await {
+                Ok(packet) => {
+                    tracing::trace!("Relay set {} answered for [{pubkey}].", set.name);
+                    return Ok(packet);
+                }
+                Err(err) => tracing::debug!("Relay set {} failed for [{pubkey}]. {err}", set.name),
+            }
+        }
+        Err(RelayError::AllSetsFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolution::test_helpers::{apex_a_packet, closed_relay, relay_answering, serving_relay};
+    use pkarr::Keypair;
+
+    #[tokio::test]
+    async fn secondary_set_used_when_primary_fails() {
+        let keypair = Keypair::random();
+        let packet = apex_a_packet(&keypair);
+        let primary = RelaySet::new("primary".to_string(), vec![closed_relay().await.to_string()]);
+        let secondary = RelaySet::new(
+            "secondary".to_string(),
+            vec![serving_relay(packet.clone()).await.to_string()],
+        );
+        let cooldown = Duration::from_secs(60);
+        let fallback = RelayFallback::new(
+            vec![primary.clone(), secondary.clone()],
+            Duration::from_secs(1),
+            cooldown,
+        );
+
+        let resolved = fallback.resolve(&keypair.public_key()).await.unwrap().unwrap();
+        assert_eq!(resolved.as_bytes(), packet.as_bytes());
+        assert!(!primary.is_healthy(cooldown));
+        assert!(secondary.is_healthy(cooldown));
+    }
+
+    #[tokio::test]
+    async fn oversized_body_rejected() {
+        let keypair = Keypair::random();
+        let with_length = b"HTTP/1.1 200 OK\r\nContent-Length: 100000\r\n\r\n".to_vec();
+        let mut without_length = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+        without_length.extend_from_slice(&[0; 100_000]);
+
+        for response in [with_length, without_length] {
+            let relay = HttpRelay::new(relay_answering(response).await.to_string());
+            let result = relay.get(&keypair.public_key()).await;
+            assert!(matches!(result, Err(RelayError::InvalidResponse("Body too large."))));
+        }
+    }
+
+    #[tokio::test]
+    async fn body_read_up_to_content_length() {
+        let keypair = Keypair::random();
+        let packet = apex_a_packet(&keypair);
+        let payload = packet.to_relay_payload();
+        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", payload.len()).into_bytes();
+        response.extend_from_slice(&payload);
+        response.extend_from_slice(b"trailing garbage");
+
+        let relay = HttpRelay::new(relay_answering(response).await.to_string());
+        let resolved = relay.get(&keypair.public_key()).await.unwrap().unwrap();
+        assert_eq!(resolved.as_bytes(), packet.as_bytes());
+    }
+}
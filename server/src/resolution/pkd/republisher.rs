@@ -0,0 +1,225 @@
+use super::dht_backend::DhtBackend;
+use pkarr::{PublicKey, SignedPacket};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Debug)]
+pub struct RepublishSettings {
+    /// How often a packet is republished. DHT nodes drop packets after a few hours.
+    pub interval: Duration,
+    /// Maximum random delay added to the interval of every packet so packets published together
+    /// don't come due together.
+    pub jitter: Duration,
+    /// Maximum number of republishes per second. 0 = unlimited.
+    pub max_per_second: u32,
+    /// Maximum number of tracked packets. When full, the packet published the longest time ago is dropped.
+    pub max_packets: usize,
+    /// Packets that are not published again through pkdns within this time are no longer republished.
+    pub max_age: Duration,
+}
+
+#[derive(Clone, Debug)]
+struct TrackedPacket {
+    packet: SignedPacket,
+    /// When the packet is republished next.
+    due: Instant,
+    /// When the packet was last published through pkdns.
+    tracked_at: Instant,
+}
+
+/**
+ * Republishes the packets published through pkdns before the DHT drops them.
+ * Every packet gets its own jittered schedule and due packets are republished at a limited rate
+ * so many keys never turn into a burst of DHT writes.
+ */
+#[derive(Clone, Debug)]
+pub struct Republisher {
+    settings: RepublishSettings,
+    /// Tracked packets by key. Shared by all clones.
+    packets: Arc<Mutex<HashMap<PublicKey, TrackedPacket>>>,
+}
+
+impl Republisher {
+    pub fn new(settings: RepublishSettings) -> Self {
+        Self {
+            settings,
+            packets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn next_republish(&self, now: Instant) -> Instant {
+        let jitter_ms = self.settings.jitter.as_millis() as u64;
+        let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..=jitter_ms));
+        now + self.settings.interval + jitter
+    }
+
+    /// Republishes the packet from now on. Replaces the previous packet of the same key.
+    pub fn track(&self, packet: SignedPacket) {
+        let now = Instant::now();
+        let pubkey = packet.public_key();
+        let mut packets = self.packets.lock().unwrap();
+        if !packets.contains_key(&pubkey) && packets.len() >= self.settings.max_packets {
+            let oldest = packets
+                .iter()
+                .min_by_key(|(_, tracked)| tracked.tracked_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                tracing::debug!("Republisher is full. Stop republishing [{oldest}].");
+                packets.remove(&oldest);
+            }
+        }
+        let tracked = TrackedPacket {
+            packet,
+            due: self.next_republish(now),
+            tracked_at: now,
+        };
+        packets.insert(pubkey, tracked);
+    }
+
+    /// Due packets, longest overdue first. Schedules their next republish and drops packets older than `max_age`.
+    fn take_due(&self) -> Vec<SignedPacket> {
+        let now = Instant::now();
+        let mut packets = self.packets.lock().unwrap();
+        packets.retain(|_, tracked| now.duration_since(tracked.tracked_at) < self.settings.max_age);
+        let mut due: Vec<&mut TrackedPacket> = packets.values_mut().filter(|tracked| tracked.due <= now).collect();
+        due.sort_by_key(|tracked| tracked.due);
+        due.into_iter()
+            .map(|tracked| {
+                tracked.due = self.next_republish(now);
+                tracked.packet.clone()
+            })
+            .collect()
+    }
+
+    /// Republishes all due packets, at most `max_per_second` of them per second.
+    /// Returns the number of successful republishes.
+    pub async fn republish_due(&self, backend: &dyn DhtBackend) -> usize {
+        let spacing = match self.settings.max_per_second {
+            0 => Duration::ZERO,
+            rate => Duration::from_secs(1) / rate,
+        };
+        let mut republished = 0;
+        for (i, packet) in self.take_due().into_iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(spacing).await;
+            }
+            match backend.publish(&packet).await {
+                Ok(()) => republished += 1,
+                Err(err) => tracing::debug!("Failed to republish [{}]. {err}", packet.public_key()),
+            }
+        }
+        republished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolution::pkd::dht_backend::mock::MockDht;
+    use crate::resolution::test_helpers::apex_a_packet;
+    use pkarr::Keypair;
+
+    fn settings() -> RepublishSettings {
+        RepublishSettings {
+            interval: Duration::from_millis(1),
+            jitter: Duration::ZERO,
+            max_per_second: 0,
+            max_packets: 100,
+            max_age: Duration::from_secs(3600),
+        }
+    }
+
+    #[tokio::test]
+    async fn due_republishes_spread_under_rate() {
+        let republisher = Republisher::new(RepublishSettings {
+            interval: Duration::from_millis(1),
+            jitter: Duration::ZERO,
+            max_per_second: 100,
+            ..settings()
+        });
+        let keypairs: Vec<Keypair> = (0..20).map(|_| Keypair::random()).collect();
+        for keypair in keypairs.iter() {
+            republisher.track(apex_a_packet(keypair));
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let dht = MockDht::new();
+        let start = Instant::now();
+        assert_eq!(republisher.republish_due(&dht).await, 20);
+        // 20 republishes at 100 per second take at least 19 gaps of 10ms.
+        assert!(start.elapsed() >= Duration::from_millis(190));
+        assert!(keypairs.iter().all(|keypair| dht.contains(&keypair.public_key())));
+    }
+
+    #[tokio::test]
+    async fn jitter_spreads_schedule() {
+        let republisher = Republisher::new(RepublishSettings {
+            interval: Duration::from_secs(3600),
+            jitter: Duration::from_secs(3600),
+            max_per_second: 0,
+            ..settings()
+        });
+        for _ in 0..20 {
+            republisher.track(apex_a_packet(&Keypair::random()));
+        }
+        let due: Vec<Instant> = republisher
+            .packets
+            .lock()
+            .unwrap()
+            .values()
+            .map(|tracked| tracked.due)
+            .collect();
+        let earliest = due.iter().min().unwrap();
+        let latest = due.iter().max().unwrap();
+        assert!(*latest - *earliest > Duration::from_secs(60));
+        assert_eq!(republisher.republish_due(&MockDht::new()).await, 0);
+    }
+
+    #[tokio::test]
+    async fn oldest_packet_evicted_when_full() {
+        let republisher = Republisher::new(RepublishSettings {
+            max_packets: 2,
+            ..settings()
+        });
+        let keypairs: Vec<Keypair> = (0..3).map(|_| Keypair::random()).collect();
+        republisher.track(apex_a_packet(&keypairs[0]));
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        republisher.track(apex_a_packet(&keypairs[1]));
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        // Published again, so it is now the most recent one.
+        republisher.track(apex_a_packet(&keypairs[0]));
+        republisher.track(apex_a_packet(&keypairs[2]));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let dht = MockDht::new();
+        assert_eq!(republisher.republish_due(&dht).await, 2);
+        assert!(dht.contains(&keypairs[0].public_key()));
+        assert!(!dht.contains(&keypairs[1].public_key()));
+        assert!(dht.contains(&keypairs[2].public_key()));
+    }
+
+    #[tokio::test]
+    async fn packets_not_published_again_expire() {
+        let republisher = Republisher::new(RepublishSettings {
+            max_age: Duration::from_millis(50),
+            ..settings()
+        });
+        let stale = Keypair::random();
+        let fresh = Keypair::random();
+        republisher.track(apex_a_packet(&stale));
+        republisher.track(apex_a_packet(&fresh));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        republisher.track(apex_a_packet(&fresh));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let dht = MockDht::new();
+        assert_eq!(republisher.republish_due(&dht).await, 1);
+        assert!(dht.contains(&fresh.public_key()));
+        assert!(!dht.contains(&stale.public_key()));
+        assert_eq!(republisher.packets.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,195 @@
+use std::{collections::HashSet, mem::size_of};
+
+use super::pkarr_cache::MOKA_ENTRY_OVERHEAD;
+use crate::{metrics::METRICS, resolution::dns_packets::ParsedPacket};
+use moka::future::Cache;
+use pkarr::{
+    dns::{Packet, Question, QTYPE, TYPE},
+    SignedPacket,
+};
+
+/// Maximum number of cached replies.
+const MAX_ENTRIES: u64 = 10_000;
+
+/// Memory every cached reply needs on top of its key and reply bytes.
+const ENTRY_OVERHEAD_BYTES: usize = MOKA_ENTRY_OVERHEAD + size_of::<String>() + size_of::<Vec<u8>>();
+
+/// Record types that can be configured by name. All others by their number, for example `TYPE65`.
+const NAMED_TYPES: [TYPE; 22] = [
+    TYPE::A,
+    TYPE::AAAA,
+    TYPE::AFSDB,
+    TYPE::CAA,
+    TYPE::CNAME,
+    TYPE::HINFO,
+    TYPE::HTTPS,
+    TYPE::ISDN,
+    TYPE::LOC,
+    TYPE::MB,
+    TYPE::MG,
+    TYPE::MINFO,
+    TYPE::MR,
+    TYPE::MX,
+    TYPE::NAPTR,
+    TYPE::NS,
+    TYPE::PTR,
+    TYPE::RP,
+    TYPE::SOA,
+    TYPE::SRV,
+    TYPE::SVCB,
+    TYPE::TXT,
+];
+
+/// Type code of a record type like `TXT`, `TYPE16` or `16`. Case insensitive.
+pub fn parse_record_type(name: &str) -> Option<u16> {
+    let name = name.to_uppercase();
+    if let Some(named) = NAMED_TYPES.iter().find(|named| format!("{named:?}") == name) {
+        return Some(u16::from(*named));
+    }
+    if name == "ANY" {
+        return Some(255);
+    }
+    name.trim_start_matches("TYPE").parse().ok()
+}
+
+/// Record types whose replies may be cached.
+#[derive(Clone, Debug, Default)]
+pub enum CacheableTypes {
+    #[default]
+    All,
+    /// Only these type codes are cached.
+    Allow(HashSet<u16>),
+    /// These type codes are never cached.
+    Deny(HashSet<u16>),
+}
+
+impl CacheableTypes {
+    pub fn is_cacheable(&self, type_code: u16) -> bool {
+        match self {
+            CacheableTypes::All => true,
+            CacheableTypes::Allow(allowed) => allowed.contains(&type_code),
+            CacheableTypes::Deny(denied) => !denied.contains(&type_code),
+        }
+    }
+}
+
+/**
+ * Replies derived from pkarr packets. Saves running `resolve_query` again for repeated questions.
+ * Keyed by the packet timestamp so a new packet of the same key never hits the replies of the old one.
+ */
+#[derive(Clone, Debug)]
+pub struct PkarrResponseCache {
+    cache: Cache<String, Vec<u8>>,
+    cacheable_types: CacheableTypes,
+}
+
+impl PkarrResponseCache {
+    pub fn new(cacheable_types: CacheableTypes) -> Self {
+        Self {
+            cache: Cache::new(MAX_ENTRIES),
+            cacheable_types,
+        }
+    }
+
+    fn key(packet: &SignedPacket, question: &Question<'_>) -> String {
+        format!(
+            "{}:{}:{}:{:?}:{:?}",
+            packet.public_key(),
+            packet.timestamp(),
+            question.qname,
+            question.qclass,
+            question.qtype
+        )
+    }
+
+    /// Cached reply to the question, with the id of the request.
+    pub async fn get(&self, packet: &SignedPacket, question: &Question<'_>, id: u16) -> Option<Vec<u8>> {
+        let mut reply = self.cache.get(&Self::key(packet, question)).await?;
+        reply[0..2].copy_from_slice(&id.to_be_bytes());
+        Some(reply)
+    }
+
+    /// Caches the reply unless the question or one of the answers is of a type that isn't cacheable.
+    pub async fn add(&self, packet: &SignedPacket, question: &Question<'_>, reply: &[u8]) {
+        let qtype = match question.qtype {
+            QTYPE::TYPE(qtype) => u16::from(qtype),
+            _ => 255, // ANY and the other meta types
+        };
+        if !self.cacheable_types.is_cacheable(qtype) {
+            return;
+        }
+        let Ok(parsed) = ParsedPacket::new(reply.to_vec()) else {
+            return;
+        };
+        let all_cacheable = parsed
+            .parsed()
+            .answers
+            .iter()
+            .all(|answer| self.cacheable_types.is_cacheable(u16::from(answer.rdata.type_code())));
+        if all_cacheable {
+            self.cache.insert(Self::key(packet, question), reply.to_vec()).await;
+        }
+    }
+
+    /// Estimated memory of the cached replies in bytes after all pending inserts and evictions are applied.
+    pub async fn size_bytes(&self) -> u64 {
+        self.cache.run_pending_tasks().await;
+        self.cache
+            .iter()
+            .map(|(key, reply)| (ENTRY_OVERHEAD_BYTES + key.len() + reply.len()) as u64)
+            .sum()
+    }
+
+    /// Reports size and number of entries of the cache as gauges.
+    pub async fn update_gauges(&self) {
+        METRICS.response_cache_size_bytes.set(self.size_bytes().await);
+        METRICS.response_cache_entries.set(self.cache.entry_count());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pkarr::{
+        dns::{rdata::RData, Name, ResourceRecord, CLASS, QCLASS},
+        Keypair,
+    };
+    use std::net::Ipv4Addr;
+
+    #[tokio::test]
+    async fn size_tracks_cached_replies() {
+        let cache = PkarrResponseCache::new(CacheableTypes::All);
+        assert_eq!(cache.size_bytes().await, 0);
+
+        let mut expected = 0;
+        for i in 0..10 {
+            let mut packet = Packet::new_reply(0);
+            packet.answers.push(ResourceRecord::new(
+                Name::new(".").unwrap(),
+                CLASS::IN,
+                300,
+                RData::A(Ipv4Addr::new(127, 0, 0, i).into()),
+            ));
+            let packet = SignedPacket::from_packet(&Keypair::random(), &packet).unwrap();
+            let question = Question::new(
+                Name::new(&packet.public_key().to_z32()).unwrap().into_owned(),
+                QTYPE::TYPE(TYPE::A),
+                QCLASS::CLASS(CLASS::IN),
+                false,
+            );
+            let reply = packet.packet().build_bytes_vec().unwrap();
+            cache.add(&packet, &question, &reply).await;
+            expected += ENTRY_OVERHEAD_BYTES + PkarrResponseCache::key(&packet, &question).len() + reply.len();
+        }
+        assert_eq!(cache.size_bytes().await, expected as u64);
+    }
+
+    #[test]
+    fn record_type_names() {
+        assert_eq!(parse_record_type("txt"), Some(16));
+        assert_eq!(parse_record_type("AAAA"), Some(28));
+        assert_eq!(parse_record_type("TYPE65"), Some(65));
+        assert_eq!(parse_record_type("99"), Some(99));
+        assert_eq!(parse_record_type("NOPE"), None);
+    }
+}
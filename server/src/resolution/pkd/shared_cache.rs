@@ -0,0 +1,142 @@
+use super::pkarr_cache::CacheItem;
+use async_trait::async_trait;
+use dyn_clone::DynClone;
+use pkarr::PublicKey;
+use std::{fmt::Debug, io::ErrorKind, path::PathBuf};
+
+/**
+ * Second cache tier shared between multiple pkdns instances, for example a Redis server.
+ * Consulted when the local cache misses, before the DHT is queried.
+ * New DHT lookups are written back to it.
+ */
+#[async_trait]
+pub trait SharedCache: DynClone + Debug + Send + Sync {
+    /// Cached item of a public key. None if the key is not cached.
+    async fn get(&self, pubkey: &PublicKey) -> Option<CacheItem>;
+
+    /// Stores the item. Failures should be logged by the implementation and not be propagated.
+    async fn put(&self, item: &CacheItem);
+}
+
+dyn_clone::clone_trait_object!(SharedCache);
+
+/**
+ * Shared cache in a directory all pkdns instances can access, for example a volume mounted into
+ * every instance of a deployment. One file per public key. Packets are verified when they are read.
+ */
+#[derive(Clone, Debug)]
+pub struct DirSharedCache {
+    dir: PathBuf,
+}
+
+impl DirSharedCache {
+    /// Creates the directory if it doesn't exist yet.
+    pub fn new(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, pubkey: &PublicKey) -> PathBuf {
+        self.dir.join(pubkey.to_z32())
+    }
+}
+
+#[async_trait]
+impl SharedCache for DirSharedCache {
+    async fn get(&self, pubkey: &PublicKey) -> Option<CacheItem> {
+        let data = match tokio::fs::read(self.path(pubkey)).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                tracing::debug!("Failed to read [{pubkey}] from the shared cache. {e}");
+                return None;
+            }
+        };
+        match CacheItem::from_bytes(&data) {
+            Ok(item) if item.public_key() == *pubkey => Some(item),
+            Ok(_) => {
+                tracing::debug!("Shared cache entry of [{pubkey}] belongs to another key.");
+                None
+            }
+            Err(e) => {
+                tracing::debug!("Invalid shared cache entry of [{pubkey}]. {e}");
+                None
+            }
+        }
+    }
+
+    async fn put(&self, item: &CacheItem) {
+        let path = self.path(&item.public_key());
+        // Write to a temporary file first so other instances never read a partial entry.
+        let tmp = path.with_extension(format!("tmp{}", rand::random::<u32>()));
+        let result = match tokio::fs::write(&tmp, item.to_bytes()).await {
+            Ok(()) => tokio::fs::rename(&tmp, &path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            tracing::debug!("Failed to write [{}] to the shared cache. {e}", item.public_key());
+            let _ = tokio::fs::remove_file(&tmp).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolution::test_helpers::apex_a_packet;
+    use pkarr::Keypair;
+
+    #[tokio::test]
+    async fn dir_cache_shared_between_instances() {
+        let keypair = Keypair::random();
+        let dir = std::env::temp_dir().join(format!("pkdns-shared-cache-{}", keypair.to_z32()));
+        let first = DirSharedCache::new(dir.clone()).unwrap();
+        let second = DirSharedCache::new(dir.clone()).unwrap();
+        assert!(second.get(&keypair.public_key()).await.is_none());
+
+        let packet = apex_a_packet(&keypair);
+        first.put(&CacheItem::new_packet(packet.clone())).await;
+
+        let item = second.get(&keypair.public_key()).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(item.unwrap().as_bytes(), packet.as_bytes());
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    /**
+     * In-memory shared cache used in tests.
+     */
+    #[derive(Clone, Debug, Default)]
+    pub struct MockSharedCache {
+        items: Arc<Mutex<HashMap<PublicKey, CacheItem>>>,
+    }
+
+    impl MockSharedCache {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn contains(&self, pubkey: &PublicKey) -> bool {
+            self.items.lock().unwrap().contains_key(pubkey)
+        }
+    }
+
+    #[async_trait]
+    impl SharedCache for MockSharedCache {
+        async fn get(&self, pubkey: &PublicKey) -> Option<CacheItem> {
+            self.items.lock().unwrap().get(pubkey).cloned()
+        }
+
+        async fn put(&self, item: &CacheItem) {
+            self.items.lock().unwrap().insert(item.public_key(), item.clone());
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use pkarr::dns::{
+    rdata::{RData, SOA},
+    Name, Packet, Question, ResourceRecord, SimpleDnsError, CLASS, QTYPE, TYPE,
+};
+use serde::{Deserialize, Serialize};
+
+/// Template of the SOA record that is synthesized for public key zones that don't publish one.
+/// Names are relative to the apex unless they end with a dot. `@` is the apex itself.
+/// Read from the `[dht.soa]` section of the config.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SoaTemplate {
+    /// Primary name server of the zone.
+    pub mname: String,
+    /// Mailbox of the person responsible for the zone.
+    pub rname: String,
+    pub refresh: i32,
+    pub retry: i32,
+    pub expire: i32,
+    /// Minimum TTL. Also used as TTL of the synthesized record.
+    pub minimum: u32,
+}
+
+impl Default for SoaTemplate {
+    fn default() -> Self {
+        Self {
+            mname: "@".to_string(),
+            rname: "hostmaster".to_string(),
+            refresh: 3600,
+            retry: 600,
+            expire: 604800,
+            minimum: 300,
+        }
+    }
+}
+
+impl SoaTemplate {
+    /// Copy of the pkarr packet with a synthesized SOA at the apex if the question asks for
+    /// the SOA of the apex and the packet doesn't publish one. None otherwise.
+    /// `apex` is the apex as seen by the client, `serial` the packet timestamp in seconds.
+    pub fn add_to_packet<'a>(
+        &self,
+        pkarr_packet: &Packet<'a>,
+        question: &Question<'a>,
+        apex: &str,
+        serial: u32,
+    ) -> Result<Option<Packet<'a>>, SimpleDnsError> {
+        let is_apex_soa_query = question.qtype == QTYPE::TYPE(TYPE::SOA) && question.qname.get_labels().len() == 1;
+        let is_published = pkarr_packet
+            .answers
+            .iter()
+            .any(|record| record.name == question.qname && matches!(record.rdata, RData::SOA(_)));
+        if !is_apex_soa_query || is_published {
+            return Ok(None);
+        }
+
+        let mut packet = pkarr_packet.clone();
+        packet.answers.push(self.record(question.qname.clone(), apex, serial)?);
+        Ok(Some(packet))
+    }
+
+    /// Synthesized SOA record with the given owner name.
+    pub fn record<'a>(&self, owner: Name<'a>, apex: &str, serial: u32) -> Result<ResourceRecord<'a>, SimpleDnsError> {
+        let soa = SOA {
+            mname: Name::new(&relative_to(&self.mname, apex))?.into_owned(),
+            rname: Name::new(&relative_to(&self.rname, apex))?.into_owned(),
+            serial,
+            refresh: self.refresh,
+            retry: self.retry,
+            expire: self.expire,
+            minimum: self.minimum,
+        };
+        Ok(ResourceRecord::new(owner, CLASS::IN, self.minimum, RData::SOA(soa)))
+    }
+}
+
+/// Resolves a template name against the apex like names in a zone file.
+fn relative_to(name: &str, apex: &str) -> String {
+    if name == "@" {
+        apex.to_string()
+    } else if let Some(absolute) = name.strip_suffix('.') {
+        absolute.to_string()
+    } else {
+        format!("{name}.{apex}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::relative_to;
+
+    #[test]
+    fn template_names() {
+        assert_eq!(relative_to("@", "pubkey.key"), "pubkey.key");
+        assert_eq!(relative_to("hostmaster", "pubkey.key"), "hostmaster.pubkey.key");
+        assert_eq!(relative_to("ns.example.com.", "pubkey.key"), "ns.example.com");
+    }
+}
@@ -0,0 +1,222 @@
+use pkarr::PublicKey;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Upper limit of tracked keys.
+pub const MAX_TOP_KEYS_TRACKED: usize = 10_000;
+
+/**
+ * Counts the queries of the most queried public keys with the Space-Saving algorithm.
+ * At most `capacity` keys are tracked no matter how many distinct keys are queried. A new key replaces the key
+ * with the lowest count and takes over its count, so counts may be overestimated but a heavily queried key is
+ * never lost.
+ */
+#[derive(Clone, Debug)]
+pub struct TopKeys {
+    /// Shared by all clones.
+    summary: Arc<Mutex<StreamSummary>>,
+}
+
+impl TopKeys {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            summary: Arc::new(Mutex::new(StreamSummary::new(capacity))),
+        }
+    }
+
+    /// Counts one query of the key. Constant time, independent of the capacity.
+    pub fn record(&self, pubkey: &PublicKey) {
+        self.summary.lock().unwrap().record(pubkey);
+    }
+
+    /// Tracked keys, most queried first.
+    pub fn top(&self) -> Vec<(PublicKey, u64)> {
+        self.summary.lock().unwrap().top()
+    }
+}
+
+/// Keys with the same count.
+#[derive(Debug)]
+struct Bucket {
+    count: u64,
+    keys: Vec<PublicKey>,
+    /// Bucket with the next lower count.
+    prev: Option<usize>,
+    /// Bucket with the next higher count.
+    next: Option<usize>,
+}
+
+/**
+ * Stream-Summary of Space-Saving: Buckets of equal counts in a list ordered by count, so incrementing a key and
+ * finding the key with the lowest count are O(1).
+ */
+#[derive(Debug)]
+struct StreamSummary {
+    capacity: usize,
+    /// Buckets by index. Unused buckets are listed in `free`.
+    buckets: Vec<Bucket>,
+    free: Vec<usize>,
+    /// Bucket with the lowest count.
+    head: Option<usize>,
+    /// Bucket and position in the bucket of every tracked key.
+    positions: HashMap<PublicKey, (usize, usize)>,
+}
+
+impl StreamSummary {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            buckets: vec![],
+            free: vec![],
+            head: None,
+            positions: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn record(&mut self, pubkey: &PublicKey) {
+        if let Some(&(bucket, _)) = self.positions.get(pubkey) {
+            self.increment(pubkey, bucket);
+            return;
+        }
+        if self.positions.len() < self.capacity {
+            let head = match self.head {
+                Some(head) if self.buckets[head].count == 1 => head,
+                head => {
+                    let bucket = self.new_bucket(1, None, head);
+                    self.head = Some(bucket);
+                    bucket
+                }
+            };
+            self.push(pubkey.clone(), head);
+            return;
+        }
+        // Full. Replace a key with the lowest count.
+        let Some(head) = self.head else {
+            return;
+        };
+        let evicted = self.buckets[head].keys.pop().expect("Buckets are never empty.");
+        self.positions.remove(&evicted);
+        self.push(pubkey.clone(), head);
+        self.increment(pubkey, head);
+    }
+
+    /// Moves the key from its bucket to the bucket with the next higher count.
+    fn increment(&mut self, pubkey: &PublicKey, bucket: usize) {
+        let count = self.buckets[bucket].count + 1;
+        let target = match self.buckets[bucket].next {
+            Some(next) if self.buckets[next].count == count => next,
+            next => self.new_bucket(count, Some(bucket), next),
+        };
+        self.remove(pubkey, bucket);
+        self.push(pubkey.clone(), target);
+    }
+
+    fn push(&mut self, pubkey: PublicKey, bucket: usize) {
+        let keys = &mut self.buckets[bucket].keys;
+        self.positions.insert(pubkey.clone(), (bucket, keys.len()));
+        keys.push(pubkey);
+    }
+
+    /// Removes the key from the bucket. Unlinks the bucket if it's empty afterwards.
+    fn remove(&mut self, pubkey: &PublicKey, bucket: usize) {
+        let (_, position) = self.positions.remove(pubkey).expect("Key is tracked.");
+        let keys = &mut self.buckets[bucket].keys;
+        keys.swap_remove(position);
+        if let Some(moved) = keys.get(position) {
+            self.positions.insert(moved.clone(), (bucket, position));
+        }
+        if !self.buckets[bucket].keys.is_empty() {
+            return;
+        }
+        let (prev, next) = (self.buckets[bucket].prev, self.buckets[bucket].next);
+        match prev {
+            Some(prev) => self.buckets[prev].next = next,
+            None => self.head = next,
+        }
+        if let Some(next) = next {
+            self.buckets[next].prev = prev;
+        }
+        self.free.push(bucket);
+    }
+
+    /// Empty bucket linked between `prev` and `next`.
+    fn new_bucket(&mut self, count: u64, prev: Option<usize>, next: Option<usize>) -> usize {
+        let bucket = Bucket {
+            count,
+            keys: vec![],
+            prev,
+            next,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.buckets[index] = bucket;
+                index
+            }
+            None => {
+                self.buckets.push(bucket);
+                self.buckets.len() - 1
+            }
+        };
+        if let Some(prev) = prev {
+            self.buckets[prev].next = Some(index);
+        }
+        if let Some(next) = next {
+            self.buckets[next].prev = Some(index);
+        }
+        index
+    }
+
+    fn top(&self) -> Vec<(PublicKey, u64)> {
+        let mut top = vec![];
+        let mut current = self.head;
+        while let Some(index) = current {
+            let bucket = &self.buckets[index];
+            top.extend(bucket.keys.iter().map(|key| (key.clone(), bucket.count)));
+            current = bucket.next;
+        }
+        top.reverse();
+        top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pkarr::Keypair;
+
+    #[test]
+    fn heavy_key_survives_many_light_keys() {
+        let top_keys = TopKeys::new(10);
+        let heavy = Keypair::random().public_key();
+        // Every sixth query is for the heavy key, the rest for keys that are never queried again.
+        for _ in 0..200 {
+            top_keys.record(&heavy);
+            for _ in 0..5 {
+                top_keys.record(&Keypair::random().public_key());
+            }
+        }
+
+        let top = top_keys.top();
+        assert_eq!(top.len(), 10);
+        assert_eq!(top[0].0, heavy);
+    }
+
+    #[test]
+    fn exact_counts_below_capacity() {
+        let top_keys = TopKeys::new(10);
+        let keys: Vec<PublicKey> = (0..3).map(|_| Keypair::random().public_key()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            for _ in 0..=i * 2 {
+                top_keys.record(key);
+            }
+        }
+
+        let top = top_keys.top();
+        assert_eq!(
+            top,
+            vec![(keys[2].clone(), 5), (keys[1].clone(), 3), (keys[0].clone(), 1)]
+        );
+    }
+}
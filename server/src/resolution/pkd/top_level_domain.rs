@@ -1,6 +1,9 @@
-use pkarr::dns::{Name, Packet, Question, ResourceRecord};
+use pkarr::dns::{
+    rdata::{RData, CNAME, NS},
+    Name, Packet, Question, ResourceRecord,
+};
 
-use super::pubkey_parser::parse_pkarr_uri;
+use super::pubkey_parser::{parse_pkarr_uri, PubkeyParserError};
 
 /// Top Level Domain like .pkd with the capability
 /// to remove and add the top level domain in queries/replies.
@@ -47,9 +50,6 @@ impl TopLevelDomain {
             );
         }
 
-        let second_label = labels.get(labels.len() - 2).expect("Question should have 2 labels");
-        let parse_res = parse_pkarr_uri(&second_label.to_string()).expect("Second label must be a pkarr public key");
-
         let slice = &labels[0..labels.len() - 1];
         let new_domain = slice
             .iter()
@@ -69,6 +69,7 @@ impl TopLevelDomain {
     }
 
     /// Checks if the name ends with a public key domain and the tld.
+    /// Keys of unsupported types count too so they get a clear answer instead of an ICANN lookup.
     pub fn name_ends_with_pubkey_tld(&self, name: &Name<'_>) -> bool {
         let labels = name.get_labels();
         if labels.len() < 2 {
@@ -83,7 +84,10 @@ impl TopLevelDomain {
         };
 
         let second_label = labels.get(labels.len() - 2).unwrap().to_string();
-        return parse_pkarr_uri(&second_label).is_ok();
+        matches!(
+            parse_pkarr_uri(&second_label),
+            Ok(_) | Err(PubkeyParserError::Unsupported(_))
+        )
     }
 
     /// Checks if the name ends with a public key domain
@@ -101,7 +105,21 @@ impl TopLevelDomain {
     /// Append the top level domain to the reply. Zones are stored without a tld on Mainline
     /// so we need to add it again here.
     pub fn add(&self, reply: &mut Packet<'_>) {
-        // Append questions
+        self.add_to_questions(reply);
+        reply.answers = self.records_with_tld(&reply.answers, false);
+    }
+
+    /// Like `add` but also appends the top level domain to the owner names in the authority
+    /// and additional section and to the NS and CNAME targets, so every name in the reply is
+    /// in the form the client asked for. Glue records then still match their delegation.
+    pub fn add_to_all_sections(&self, reply: &mut Packet<'_>) {
+        self.add_to_questions(reply);
+        reply.answers = self.records_with_tld(&reply.answers, true);
+        reply.name_servers = self.records_with_tld(&reply.name_servers, true);
+        reply.additional_records = self.records_with_tld(&reply.additional_records, true);
+    }
+
+    fn add_to_questions(&self, reply: &mut Packet<'_>) {
         let mut new_questions = vec![];
         for mut question in reply.questions.iter() {
             if !self.name_ends_with_pubkey(&question.qname) {
@@ -109,29 +127,40 @@ impl TopLevelDomain {
                 new_questions.push(question.clone());
                 continue;
             };
-            let original_domain = question.qname.to_string();
-            let new_domain = format!("{original_domain}.{}", self.0);
-            let new_name = Name::new(&new_domain).unwrap();
+            let new_name = self.name_with_tld(&question.qname);
             let new_question =
                 Question::new(new_name, question.qtype, question.qclass, question.unicast_response).into_owned();
             new_questions.push(new_question);
         }
         reply.questions = new_questions;
-        // Append answers
-        let mut new_answers = vec![];
-        for mut answer in reply.answers.iter() {
-            if !self.name_ends_with_pubkey(&answer.name) {
-                // Other answer. Don't change.
-                new_answers.push(answer.clone());
-                continue;
+    }
+
+    /// Appends the tld to the owner names ending with a public key. With `targets` also to the NS and CNAME targets.
+    fn records_with_tld<'a>(&self, records: &[ResourceRecord<'a>], targets: bool) -> Vec<ResourceRecord<'a>> {
+        let mut new_records = vec![];
+        for record in records.iter() {
+            let rdata = match &record.rdata {
+                RData::NS(NS(target)) if targets && self.name_ends_with_pubkey(target) => {
+                    RData::NS(NS(self.name_with_tld(target)))
+                }
+                RData::CNAME(CNAME(target)) if targets && self.name_ends_with_pubkey(target) => {
+                    RData::CNAME(CNAME(self.name_with_tld(target)))
+                }
+                rdata => rdata.clone(),
             };
-            let original_domain = answer.name.to_string();
-            let new_domain = format!("{original_domain}.{}", self.0);
-            let new_name = Name::new(&new_domain).unwrap();
-            let new_answer = ResourceRecord::new(new_name, answer.class, answer.ttl, answer.rdata.clone()).into_owned();
-            new_answers.push(new_answer);
+            let mut new_record = record.clone();
+            new_record.rdata = rdata;
+            if self.name_ends_with_pubkey(&record.name) {
+                new_record.name = self.name_with_tld(&record.name);
+            }
+            new_records.push(new_record);
         }
-        reply.answers = new_answers;
+        new_records
+    }
+
+    fn name_with_tld<'a>(&self, name: &Name<'_>) -> Name<'a> {
+        let new_domain = format!("{name}.{}", self.0);
+        Name::new(&new_domain).unwrap().into_owned()
     }
 }
 
@@ -37,6 +37,25 @@ impl RateLimitingKey {
             | (segments[3] as u64);
         return Self::IpV6 { significant_bits: key };
     }
+
+    /**
+     * Generate a key for the subnet of the ip address.
+     * IPv6 prefixes are limited to the first 64 bits.
+     */
+    pub fn from_subnet(ip: IpAddr, ipv4_prefix_len: u8, ipv6_prefix_len: u8) -> Self {
+        match Self::from(ip) {
+            Self::Ipv4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - ipv4_prefix_len as u32).unwrap_or(0);
+                Self::Ipv4(Ipv4Addr::from(u32::from(ip) & mask))
+            }
+            Self::IpV6 { significant_bits } => {
+                let mask = u64::MAX.checked_shl(64 - ipv6_prefix_len as u32).unwrap_or(0);
+                Self::IpV6 {
+                    significant_bits: significant_bits & mask,
+                }
+            }
+        }
+    }
 }
 
 impl From<IpAddr> for RateLimitingKey {
@@ -67,6 +86,10 @@ pub struct RateLimiterBuilder {
     max_per_second: u32,
     max_per_minute: u32,
     burst_size: u32,
+    subnet_max_per_second: u32,
+    subnet_burst_size: u32,
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
 }
 
 impl RateLimiterBuilder {
@@ -75,6 +98,10 @@ impl RateLimiterBuilder {
             max_per_second: 0,
             max_per_minute: 0,
             burst_size: 0,
+            subnet_max_per_second: 0,
+            subnet_burst_size: 0,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 48,
         }
     }
 
@@ -103,27 +130,60 @@ impl RateLimiterBuilder {
         self
     }
 
-    /// Builds the RateLimiter. Panics if max_per_minute AND max_per_second is set at the same time.
-    pub fn build(self) -> RateLimiter {
-        if self.max_per_minute > 0 && self.max_per_second > 0 {
-            panic!("Can't set max_per_minute and max_per_second at the same time.")
-        };
+    /// Maximum number of requests per second of a whole subnet. Applied in addition to the per ip limit.
+    /// 0 is disabled.
+    pub fn subnet_max_per_second(mut self, limit: u32) -> Self {
+        self.subnet_max_per_second = limit;
+        self
+    }
 
+    /// Burst size of the subnet limit.
+    /// 0 is disabled.
+    pub fn subnet_burst_size(mut self, size: u32) -> Self {
+        self.subnet_burst_size = size;
+        self
+    }
+
+    /// Prefix lengths that define a subnet. Defaults to /24 for IPv4 and /48 for IPv6.
+    pub fn subnet_prefix_len(mut self, ipv4: u8, ipv6: u8) -> Self {
+        self.ipv4_prefix_len = ipv4;
+        self.ipv6_prefix_len = ipv6;
+        self
+    }
+
+    fn quota(per_second: u32, per_minute: u32, burst_size: u32) -> Option<Quota> {
         let mut quota: Quota;
-        if self.max_per_minute > 0 {
-            quota = Quota::per_minute(NonZeroU32::new(self.max_per_minute).unwrap());
-        } else if self.max_per_second > 0 {
-            quota = Quota::per_second(NonZeroU32::new(self.max_per_second).unwrap());
+        if per_minute > 0 {
+            quota = Quota::per_minute(NonZeroU32::new(per_minute).unwrap());
+        } else if per_second > 0 {
+            quota = Quota::per_second(NonZeroU32::new(per_second).unwrap());
         } else {
-            return RateLimiter { limiter: None };
+            return None;
         }
 
-        if self.burst_size > 0 {
-            quota = quota.allow_burst(NonZeroU32::new(self.burst_size).unwrap());
+        if burst_size > 0 {
+            quota = quota.allow_burst(NonZeroU32::new(burst_size).unwrap());
         }
+        Some(quota)
+    }
+
+    /// Builds the RateLimiter. Panics if max_per_minute AND max_per_second is set at the same time
+    /// or if the subnet limit is set and a subnet prefix length is longer than 32 bits for IPv4 or 64 bits for IPv6.
+    pub fn build(self) -> RateLimiter {
+        if self.max_per_minute > 0 && self.max_per_second > 0 {
+            panic!("Can't set max_per_minute and max_per_second at the same time.")
+        };
+        if self.subnet_max_per_second > 0 && (self.ipv4_prefix_len > 32 || self.ipv6_prefix_len > 64) {
+            panic!("Subnet prefix length can't be longer than /32 for IPv4 and /64 for IPv6.")
+        };
 
         RateLimiter {
-            limiter: Some(GovenerRateLimiter::keyed(quota)),
+            limiter: Self::quota(self.max_per_second, self.max_per_minute, self.burst_size)
+                .map(GovenerRateLimiter::keyed),
+            subnet_limiter: Self::quota(self.subnet_max_per_second, 0, self.subnet_burst_size)
+                .map(GovenerRateLimiter::keyed),
+            ipv4_prefix_len: self.ipv4_prefix_len,
+            ipv6_prefix_len: self.ipv6_prefix_len,
         }
     }
 }
@@ -131,18 +191,81 @@ impl RateLimiterBuilder {
 #[derive(Debug)]
 pub struct RateLimiter {
     limiter: Option<DefaultKeyedRateLimiter<RateLimitingKey>>,
+    /**
+     * Limits the aggregate of all ip addresses in a subnet.
+     */
+    subnet_limiter: Option<DefaultKeyedRateLimiter<RateLimitingKey>>,
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
 }
 
 impl RateLimiter {
     /**
-     * Checks if this IP address is limited. Increases the usage by one.
+     * Checks if this IP address or its subnet is limited. Increases the usage by one.
+     * The subnet usage is only increased if the IP address itself is not limited.
      */
     pub fn check_is_limited_and_increase(&self, ip: &IpAddr) -> bool {
         if let Some(limiter) = &self.limiter {
             let ip = ip.clone();
-            let is_rate_limited = limiter.check_key(&ip.into()).is_err();
-            return is_rate_limited;
+            if limiter.check_key(&ip.into()).is_err() {
+                return true;
+            }
+        };
+        if let Some(limiter) = &self.subnet_limiter {
+            let key = RateLimitingKey::from_subnet(*ip, self.ipv4_prefix_len, self.ipv6_prefix_len);
+            return limiter.check_key(&key).is_err();
         };
         return false;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_len_ignored_without_subnet_limit() {
+        let limiter = RateLimiterBuilder::new().subnet_prefix_len(33, 65).build();
+        assert!(!limiter.check_is_limited_and_increase(&"1.1.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ip_and_subnet_limits_both_apply() {
+        let limiter = RateLimiterBuilder::new()
+            .max_per_minute(1)
+            .burst_size(2)
+            .subnet_max_per_second(1)
+            .subnet_burst_size(5)
+            .build();
+
+        // A single ip is limited by its own burst.
+        let single: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(!limiter.check_is_limited_and_increase(&single));
+        assert!(!limiter.check_is_limited_and_increase(&single));
+        assert!(limiter.check_is_limited_and_increase(&single));
+
+        // Diverse ips in the same subnet share the remaining subnet budget.
+        let subnet_results: Vec<bool> = (2..=5)
+            .map(|i| limiter.check_is_limited_and_increase(&format!("10.0.0.{i}").parse().unwrap()))
+            .collect();
+        assert_eq!(subnet_results, vec![false, false, false, true]);
+
+        // Other subnets are unaffected.
+        let other: IpAddr = "10.0.1.1".parse().unwrap();
+        assert!(!limiter.check_is_limited_and_increase(&other));
+    }
+
+    #[test]
+    fn subnet_key() {
+        let ip: IpAddr = "192.168.17.42".parse().unwrap();
+        assert_eq!(
+            RateLimitingKey::from_subnet(ip, 24, 48),
+            RateLimitingKey::Ipv4("192.168.17.0".parse().unwrap())
+        );
+        let ip: IpAddr = "2001:db8:aaaa:bbbb::1".parse().unwrap();
+        assert_eq!(
+            RateLimitingKey::from_subnet(ip, 24, 48),
+            RateLimitingKey::from_ipv6("2001:db8:aaaa::".parse().unwrap())
+        );
+    }
+}
@@ -4,6 +4,7 @@ use anyhow::anyhow;
 use moka::{future::Cache, policy::EvictionPolicy};
 use pkarr::dns::Packet;
 
+use super::dns_packets::ParsedPacket;
 use crate::config::get_global_config;
 
 /// Caches dns responses.
@@ -16,7 +17,7 @@ pub struct CacheItem {
 
 impl CacheItem {
     pub fn new(query: Vec<u8>, response: Vec<u8>) -> Result<Self, anyhow::Error> {
-        let _ = Packet::parse(&response)?; // Validate that the response is parseable.
+        let _ = ParsedPacket::new(response.clone())?; // Validate that the response is parseable.
         Ok(Self {
             query_key: Self::derive_query_key(&query)?,
             response,
@@ -27,22 +28,24 @@ impl CacheItem {
     /// Derives a query key from the first question. May fail if the packet cant be parsed
     /// or the query doesn't have a question.
     pub fn derive_query_key(query: &Vec<u8>) -> Result<String, anyhow::Error> {
-        let packet = Packet::parse(query)?;
+        let packet = ParsedPacket::new(query.clone())?;
         let question = packet
+            .parsed()
             .questions
             .first()
             .ok_or(anyhow!("Query does not include a question."))?;
         Ok(format!("{}:{:?}:{:?}", question.qname, question.qclass, question.qtype))
     }
 
-    fn response_packet(&self) -> Packet {
-        Packet::parse(&self.response).unwrap()
+    fn response_packet(&self) -> ParsedPacket {
+        ParsedPacket::new(self.response.clone()).unwrap()
     }
 
     /// Lowest ttl of any anwser in seconds. Used to determine when to update the cache.
     /// NotFound or packet with now answeres => None.
     pub fn lowest_answer_ttl(&self) -> Option<u64> {
         self.response_packet()
+            .parsed()
             .answers
             .iter()
             .map(|answer| answer.ttl as u64)
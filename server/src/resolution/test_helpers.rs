@@ -0,0 +1,140 @@
+/**
+ * Fixtures shared by the test modules: signed packets, sockets that resolve with a mock DHT and fake relays.
+ */
+use super::{DnsSocket, MockDht, PkarrResolver, ResolverSettings};
+use pkarr::{
+    dns::{
+        rdata::{OPTCode, RData, OPT},
+        Name, Packet, PacketFlag, Question, ResourceRecord, CLASS, QCLASS, QTYPE, TYPE,
+    },
+    Keypair, SignedPacket,
+};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Signed packet with a single A record on the apex.
+pub fn a_packet(keypair: &Keypair, ip: Ipv4Addr) -> SignedPacket {
+    let mut packet = Packet::new_reply(0);
+    packet.answers.push(ResourceRecord::new(
+        Name::new(".").unwrap(),
+        CLASS::IN,
+        300,
+        RData::A(ip.into()),
+    ));
+    SignedPacket::from_packet(keypair, &packet).unwrap()
+}
+
+/// Signed packet with a single A record on the apex pointing to 127.0.0.1.
+pub fn apex_a_packet(keypair: &Keypair) -> SignedPacket {
+    a_packet(keypair, Ipv4Addr::new(127, 0, 0, 1))
+}
+
+/// Recursive A query for the domain.
+pub fn a_query(domain: &str) -> Packet<'_> {
+    let mut query = Packet::new_query(0);
+    let question = Question::new(
+        Name::new(domain).unwrap(),
+        QTYPE::TYPE(TYPE::A),
+        QCLASS::CLASS(CLASS::IN),
+        false,
+    );
+    query.questions = vec![question];
+    query.set_flags(PacketFlag::RECURSION_DESIRED);
+    query
+}
+
+/// Adds an OPT record with the options to the query, marking it as EDNS capable.
+pub fn set_opt<'a>(query: &mut Packet<'a>, opt_codes: Vec<OPTCode<'a>>) {
+    *query.opt_mut() = Some(OPT {
+        opt_codes,
+        udp_packet_size: 1232,
+        version: 0,
+    });
+}
+
+/// Socket that resolves pkarr with the mock DHT only.
+pub async fn offline_socket(dht: MockDht) -> DnsSocket {
+    offline_socket_with_settings(dht, ResolverSettings::default()).await
+}
+
+/// Socket that resolves pkarr with the mock DHT only and the given resolver settings.
+pub async fn offline_socket_with_settings(dht: MockDht, settings: ResolverSettings) -> DnsSocket {
+    let resolver = PkarrResolver::with_backend(settings, Box::new(dht));
+    DnsSocket::random_socket_with_resolver(resolver).await.unwrap()
+}
+
+/// Relay that answers every request with the raw response returned by `respond`.
+pub async fn fake_relay(respond: impl Fn(&str) -> Vec<u8> + Send + 'static) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+            let size = stream.read(&mut buffer).await.unwrap();
+            let response = respond(&String::from_utf8_lossy(&buffer[..size]));
+            let _ = stream.write_all(&response).await;
+        }
+    });
+    addr
+}
+
+/// Relay that answers every request with the raw response.
+pub async fn relay_answering(response: Vec<u8>) -> SocketAddr {
+    fake_relay(move |_| response.clone()).await
+}
+
+/// Relay that answers every request with the packet.
+pub async fn serving_relay(packet: SignedPacket) -> SocketAddr {
+    relay_answering(ok_response(&packet)).await
+}
+
+/// Relay that knows only the packet and answers 404 for every other key.
+pub async fn parent_relay(packet: SignedPacket) -> SocketAddr {
+    let path = format!("GET /{} ", packet.public_key().to_z32());
+    let found = ok_response(&packet);
+    fake_relay(move |request| {
+        if request.starts_with(&path) {
+            found.clone()
+        } else {
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec()
+        }
+    })
+    .await
+}
+
+/// Relay that closes every connection without answering.
+pub async fn closed_relay() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let _ = listener.accept().await.unwrap();
+        }
+    });
+    addr
+}
+
+/// Relay that accepts connections but never answers nor closes them.
+pub async fn silent_relay() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut streams = vec![];
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            streams.push(stream);
+        }
+    });
+    addr
+}
+
+fn ok_response(packet: &SignedPacket) -> Vec<u8> {
+    let payload = packet.to_relay_payload();
+    let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", payload.len()).into_bytes();
+    response.extend_from_slice(&payload);
+    response
+}